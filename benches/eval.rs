@@ -0,0 +1,89 @@
+// Time per pixel of each CPU evaluator over the same generated functions:
+//     cargo bench --bench eval [--features jit]
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use shaderand_wasm::bnf_parser::Parser;
+use shaderand_wasm::vm::Evaluator;
+
+const GRAMMAR: &str = "E | vec3(C, C, C) ;
+A | x | y | t | r | theta ;
+C || A ||| add(C, C) ||| sub(C, A) ||| mul(C, C) | div(C, C) | mod(C, C) | sqrt(C) | abs(C) | sin(C) | cos(C) ;";
+const FUNCTIONS: usize = 20;
+const PIXELS: u16 = 256;
+
+fn time(mut eval: impl FnMut(f32, f32)) -> Duration {
+    let start = Instant::now();
+    for y in 0..PIXELS {
+        for x in 0..PIXELS {
+            eval(
+                f32::from(x) / f32::from(PIXELS) * 2.0 - 1.0,
+                f32::from(y) / f32::from(PIXELS) * 2.0 - 1.0,
+            );
+        }
+    }
+    start.elapsed()
+}
+
+fn time_rows(mut eval: impl FnMut(&[f32], &[f32])) -> Duration {
+    let xs = (0..PIXELS)
+        .map(|x| f32::from(x) / f32::from(PIXELS) * 2.0 - 1.0)
+        .collect::<Vec<_>>();
+    let mut ys = vec![0.0; xs.len()];
+    let start = Instant::now();
+    for y in 0..PIXELS {
+        ys.fill(f32::from(y) / f32::from(PIXELS) * 2.0 - 1.0);
+        eval(&xs, &ys);
+    }
+    start.elapsed()
+}
+
+fn main() -> Result<(), String> {
+    let grammar = Parser::new(GRAMMAR).parse().map_err(|e| format!("{e:?}"))?;
+    let mut totals = [Duration::ZERO; 4];
+    let mut native = true;
+    for _ in 0..FUNCTIONS {
+        let func = grammar
+            .gen_from_rule(0, 10)
+            .ok_or("Failed to generate function")?;
+        let shared = func.share()?;
+        let mut interpreter = Evaluator::interpreted(func.compile_program()?);
+        let mut evaluator = Evaluator::new(func.compile_program()?);
+        native &= evaluator.is_native();
+        let mut colors = Vec::new();
+
+        let timings = [
+            time(|x, y| {
+                black_box(shared.eval_fn(x, y, 0.0).ok());
+            }),
+            time(|x, y| {
+                black_box(interpreter.eval(x, y, 0.0));
+            }),
+            time_rows(|xs, ys| {
+                black_box(interpreter.eval_batch(xs, ys, 0.0, &mut colors).ok());
+            }),
+            time(|x, y| {
+                black_box(evaluator.eval(x, y, 0.0));
+            }),
+        ];
+        for (total, timing) in totals.iter_mut().zip(timings) {
+            *total = total.saturating_add(timing);
+        }
+    }
+
+    let pixels = f64::from(PIXELS) * f64::from(PIXELS) * FUNCTIONS as f64;
+    let [tree, bytecode, batched, best] = totals;
+    for (name, total) in [
+        ("Shared::eval_fn", tree),
+        ("bytecode", bytecode),
+        ("batched", batched),
+        (if native { "jit" } else { "jit (off)" }, best),
+    ] {
+        println!(
+            "{name:>15}: {:8.1} ns/pixel, {:5.1}x Shared::eval_fn",
+            total.as_secs_f64() * 1e9 / pixels,
+            tree.as_secs_f64() / total.as_secs_f64()
+        );
+    }
+    Ok(())
+}
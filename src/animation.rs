@@ -0,0 +1,799 @@
+// Animation export: renders frames evenly spaced over a range of t with the CPU renderer and writes
+// them as a looping GIF, APNG or animated WebP, as a Y4M or raw RGB stream for piping into an
+// external encoder, or as numbered images. Frames are encoded as they are rendered, so only one is
+// held in memory at a time, bar WebP, which keeps every encoded frame until it knows the size of
+// the file it starts with. GIF frames each get their own 256 colour palette from NeuQuant and are
+// dithered against it, which keeps the smooth gradients most expressions produce from banding.
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+
+use color_quant::NeuQuant;
+use image::RgbImage;
+
+use crate::backend::TimeMap;
+use crate::interval::Interval;
+use crate::render::{ImageFormat, RenderOptions};
+use crate::vm::Program;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+    WebP,
+    /// YUV4MPEG2, uncompressed frames with a header giving their size and rate
+    Y4m,
+    /// Bare RGB bytes, frame after frame
+    Rgb,
+}
+
+impl AnimationFormat {
+    pub const ALL: [AnimationFormat; 5] = [
+        AnimationFormat::Gif,
+        AnimationFormat::Apng,
+        AnimationFormat::WebP,
+        AnimationFormat::Y4m,
+        AnimationFormat::Rgb,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Apng => "png",
+            AnimationFormat::WebP => "webp",
+            AnimationFormat::Y4m => "y4m",
+            AnimationFormat::Rgb => "rgb",
+        }
+    }
+
+    /// Picks the format from a file name's extension
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(format!(
+                "No file extension to pick an animation format from: {}",
+                path.display()
+            ))?;
+        extension.parse()
+    }
+}
+
+impl FromStr for AnimationFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gif" => Ok(AnimationFormat::Gif),
+            "png" | "apng" => Ok(AnimationFormat::Apng),
+            "webp" => Ok(AnimationFormat::WebP),
+            "y4m" => Ok(AnimationFormat::Y4m),
+            "rgb" | "raw" => Ok(AnimationFormat::Rgb),
+            _ => Err(format!("Unknown animation format: {s}")),
+        }
+    }
+}
+
+impl Display for AnimationFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AnimationFormat::Gif => "GIF",
+            AnimationFormat::Apng => "APNG",
+            AnimationFormat::WebP => "WebP",
+            AnimationFormat::Y4m => "Y4M",
+            AnimationFormat::Rgb => "raw RGB",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Animation {
+    pub frames: u32,
+    /// Frame `i` is at `time.lo + i / frames * (time.hi - time.lo)`, unless looping
+    pub time: Interval,
+    pub fps: f32,
+    /// Ease across `time` and back as the shaders' `TimeMap::Loop` does, over one period, so the
+    /// last frame leads smoothly into the first
+    pub looping: bool,
+    /// Renders averaged into each frame for motion blur, or 1 for none
+    pub motion_samples: u32,
+    /// How much of the time to the next frame those renders spread across, from 0 to 1
+    pub shutter: f32,
+}
+
+impl Default for Animation {
+    fn default() -> Self {
+        Animation {
+            frames: 60,
+            time: Interval::new(-1.0, 1.0),
+            fps: 30.0,
+            looping: false,
+            motion_samples: 1,
+            shutter: 0.5,
+        }
+    }
+}
+
+impl Animation {
+    /// The t of each frame
+    pub fn times(&self) -> impl Iterator<Item = f32> + '_ {
+        (0..self.frames).map(|frame| self.time_at(frame as f32))
+    }
+
+    /// The t `frame` frames in, which may be part way between two
+    pub fn time_at(&self, frame: f32) -> f32 {
+        if self.looping {
+            // A period of `frames` with the frame number as the time
+            let time_map = TimeMap::Loop {
+                period: self.frames as f32,
+                range: self.time,
+            };
+            time_map.t(frame)
+        } else {
+            self.time.lo + (frame / self.frames as f32) * (self.time.hi - self.time.lo)
+        }
+    }
+
+    /// The t of each render averaged into `frame`, from when the shutter opens
+    pub fn exposure(&self, frame: u32) -> Vec<f32> {
+        let samples = self.motion_samples.max(1);
+        (0..samples)
+            .map(|idx| self.time_at(frame as f32 + self.shutter * (idx as f32 / samples as f32)))
+            .collect()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.frames == 0 {
+            return Err("An animation needs at least 1 frame".to_string());
+        }
+        if !(self.fps.is_finite() && self.fps > 0.0) {
+            return Err(format!("Invalid frame rate: {}", self.fps));
+        }
+        if self.motion_samples == 0 {
+            return Err("Motion blur needs at least 1 sample".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.shutter) {
+            return Err(format!("Shutter must be from 0 to 1, not {}", self.shutter));
+        }
+        Ok(())
+    }
+
+    // How long each frame shows for
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn delay_ms(&self) -> Result<u32, String> {
+        self.check()?;
+        // Clamped, so the cast is exact
+        Ok((1000.0 / self.fps).round().clamp(1.0, 60_000.0) as u32)
+    }
+
+    // The frame rate as a fraction, to the nearest thousandth of a frame per second
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn frame_rate(&self) -> (u32, u32) {
+        // Clamped, so the cast is exact
+        let thousandths = (self.fps * 1000.0).round().clamp(1.0, u32::MAX as f32) as u32;
+        let common = gcd(thousandths, 1000);
+        (
+            thousandths.checked_div(common).unwrap_or(thousandths),
+            1000_u32.checked_div(common).unwrap_or(1000),
+        )
+    }
+
+    // Each frame, rendered as it is asked for
+    fn render_frames<'a>(
+        &'a self,
+        options: &'a RenderOptions,
+        program: &'a Program,
+        cancel: &'a AtomicBool,
+        mut progress: impl FnMut(u32, u32) + 'a,
+    ) -> impl Iterator<Item = Result<RgbImage, String>> + 'a {
+        (0..self.frames).map(move |frame| {
+            let image =
+                options.render_exposure(program, &self.exposure(frame), cancel, |_, _| {})?;
+            progress(frame.saturating_add(1), self.frames);
+            Ok(image)
+        })
+    }
+
+    /// Renders the frames of `program` with `options`, whose `t` is replaced by each frame's, and
+    /// writes them to `writer` as `format`. `progress` is told the frames done so far and in
+    /// total, and setting `cancel` stops with an error.
+    pub fn write(
+        &self,
+        options: &RenderOptions,
+        program: &Program,
+        format: AnimationFormat,
+        writer: impl Write,
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<(), String> {
+        let delay = self.delay_ms()?;
+        let frames = self.render_frames(options, program, cancel, progress);
+        let (width, height) = (options.width, options.height);
+        match format {
+            AnimationFormat::Gif => write_gif(writer, width, height, delay, frames),
+            AnimationFormat::Apng => write_apng(writer, width, height, self.frames, delay, frames),
+            AnimationFormat::WebP => write_webp(writer, width, height, delay, frames),
+            AnimationFormat::Y4m => write_y4m(writer, width, height, self.frame_rate(), frames),
+            AnimationFormat::Rgb => write_rgb(writer, frames),
+        }
+    }
+
+    /// `write` to a new file at `path`
+    pub fn save(
+        &self,
+        options: &RenderOptions,
+        program: &Program,
+        format: AnimationFormat,
+        path: &Path,
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<(), String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+        self.write(options, program, format, &mut writer, cancel, progress)?;
+        writer.flush().map_err(|e| e.to_string())
+    }
+
+    /// Renders the frames as `write` does, saving each to `pattern` with its last run of `#`s
+    /// replaced by the frame number, zero padded to that many digits. The image format goes by
+    /// the extension and is written as `RenderOptions::render_file` does.
+    pub fn save_frames(
+        &self,
+        options: &RenderOptions,
+        program: &Program,
+        pattern: &str,
+        cancel: &AtomicBool,
+        mut progress: impl FnMut(u32, u32),
+    ) -> Result<(), String> {
+        let format = ImageFormat::from_path(Path::new(pattern))?;
+        let end = pattern
+            .rfind('#')
+            .ok_or(format!("No #s for the frame number in {pattern}"))?
+            .saturating_add(1);
+        let head = pattern.get(..end).unwrap_or_default();
+        let prefix = head.trim_end_matches('#');
+        let digits = head.len().saturating_sub(prefix.len());
+        let suffix = pattern.get(end..).unwrap_or_default();
+        self.check()?;
+        for frame in 0..self.frames {
+            let path = format!("{prefix}{frame:0digits$}{suffix}");
+            let times = self.exposure(frame);
+            options.render_file(
+                program,
+                &times,
+                Path::new(&path),
+                format,
+                &[],
+                cancel,
+                |_, _| {},
+            )?;
+            progress(frame.saturating_add(1), self.frames);
+        }
+        Ok(())
+    }
+}
+
+// Floyd-Steinberg error diffusion onto the palette `quant` was trained on
+fn dither(image: &RgbImage, quant: &NeuQuant, palette: &[u8]) -> Vec<u8> {
+    let width = image.width() as usize;
+    // The error carried into this row and the next, with a pixel of padding either side
+    let mut this = vec![[0.0f32; 3]; width.saturating_add(2)];
+    let mut next = this.clone();
+    let mut indices = Vec::with_capacity(width.saturating_mul(image.height() as usize));
+    for row in image.rows() {
+        for (x, pixel) in row.enumerate() {
+            let carried = this.get(x.saturating_add(1)).copied().unwrap_or_default();
+            let mut wanted = [0.0f32; 3];
+            for ((wanted, channel), carried) in wanted.iter_mut().zip(pixel.0).zip(carried) {
+                *wanted = (f32::from(channel) + carried).clamp(0.0, 255.0);
+            }
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let [r, g, b] = wanted.map(|channel| channel.round() as u8);
+            let idx = quant.index_of(&[r, g, b, 255]);
+            let chosen = palette
+                .get(idx.saturating_mul(3)..idx.saturating_mul(3).saturating_add(3))
+                .unwrap_or(&[0, 0, 0]);
+            let mut error = [0.0f32; 3];
+            for ((error, wanted), chosen) in error.iter_mut().zip(wanted).zip(chosen) {
+                *error = wanted - f32::from(*chosen);
+            }
+            let spread = |row: &mut [[f32; 3]], dx: usize, weight: f32| {
+                if let Some(cell) = row.get_mut(x.saturating_add(dx)) {
+                    for (cell, error) in cell.iter_mut().zip(error) {
+                        *cell += error * weight / 16.0;
+                    }
+                }
+            };
+            spread(&mut this, 2, 7.0);
+            spread(&mut next, 0, 3.0);
+            spread(&mut next, 1, 5.0);
+            spread(&mut next, 2, 1.0);
+            indices.push(u8::try_from(idx).unwrap_or(u8::MAX));
+        }
+        std::mem::swap(&mut this, &mut next);
+        next.fill([0.0; 3]);
+    }
+    indices
+}
+
+// The palette and indices of an image with no more than 256 colours, which need no quantising
+fn exact_palette(image: &RgbImage) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut colors: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(image.len());
+    for pixel in image.pixels() {
+        let next = colors.len();
+        let idx = match colors.entry(pixel.0) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                palette.extend(pixel.0);
+                *entry.insert(u8::try_from(next).ok()?)
+            }
+        };
+        indices.push(idx);
+    }
+    Some((palette, indices))
+}
+
+fn write_gif(
+    writer: impl Write,
+    width: u32,
+    height: u32,
+    delay_ms: u32,
+    frames: impl Iterator<Item = Result<RgbImage, String>>,
+) -> Result<(), String> {
+    let too_big = |_| format!("GIFs can't be bigger than 65535x65535, not {width}x{height}");
+    let (gif_width, gif_height) = (
+        u16::try_from(width).map_err(too_big)?,
+        u16::try_from(height).map_err(too_big)?,
+    );
+    let mut encoder =
+        gif::Encoder::new(writer, gif_width, gif_height, &[]).map_err(|e| e.to_string())?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(|e| e.to_string())?;
+    // GIF delays are in hundredths of a second
+    let delay = u16::try_from(delay_ms.div_ceil(10)).unwrap_or(u16::MAX);
+    for image in frames {
+        let image = image?;
+        let (palette, indices) = exact_palette(&image).unwrap_or_else(|| {
+            let rgba = image
+                .pixels()
+                .flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2], 255])
+                .collect::<Vec<_>>();
+            // Sampling every pixel gives the best palette NeuQuant can find
+            let quant = NeuQuant::new(1, 256, &rgba);
+            let palette = quant.color_map_rgb();
+            let indices = dither(&image, &quant, &palette);
+            (palette, indices)
+        });
+        let mut frame =
+            gif::Frame::from_palette_pixels(gif_width, gif_height, indices, palette, None);
+        frame.delay = delay;
+        encoder.write_frame(&frame).map_err(|e| e.to_string())?;
+    }
+    encoder.into_inner().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn write_apng(
+    writer: impl Write,
+    width: u32,
+    height: u32,
+    count: u32,
+    delay_ms: u32,
+    frames: impl Iterator<Item = Result<RgbImage, String>>,
+) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(count, 0).map_err(|e| e.to_string())?;
+    encoder
+        .set_frame_delay(u16::try_from(delay_ms).unwrap_or(u16::MAX), 1000)
+        .map_err(|e| e.to_string())?;
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    for image in frames {
+        writer
+            .write_image_data(image?.as_raw())
+            .map_err(|e| e.to_string())?;
+    }
+    writer.finish().map_err(|e| e.to_string())
+}
+
+// The low three bytes of `val`, little endian, as WebP stores sizes and positions
+fn u24(val: u32) -> [u8; 3] {
+    let [a, b, c, _] = val.to_le_bytes();
+    [a, b, c]
+}
+
+fn webp_chunk(out: &mut Vec<u8>, fourcc: [u8; 4], payload: &[u8]) -> Result<(), String> {
+    out.extend_from_slice(&fourcc);
+    let size = u32::try_from(payload.len()).map_err(|_| "WebP chunk too large")?;
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+    Ok(())
+}
+
+// image-webp only writes still images, so each frame is encoded losslessly on its own and its
+// VP8L chunk wrapped in an ANMF chunk of an extended format file
+fn write_webp(
+    mut writer: impl Write,
+    width: u32,
+    height: u32,
+    delay_ms: u32,
+    frames: impl Iterator<Item = Result<RgbImage, String>>,
+) -> Result<(), String> {
+    if width > 16384 || height > 16384 {
+        return Err(format!(
+            "WebPs can't be bigger than 16384x16384, not {width}x{height}"
+        ));
+    }
+    let (last_x, last_y) = (u24(width.saturating_sub(1)), u24(height.saturating_sub(1)));
+    let mut chunks = Vec::new();
+    // Animated, on a canvas the size of the image
+    let mut header = vec![0b0000_0010, 0, 0, 0];
+    header.extend(last_x);
+    header.extend(last_y);
+    webp_chunk(&mut chunks, *b"VP8X", &header)?;
+    // Black background, looping forever
+    webp_chunk(&mut chunks, *b"ANIM", &[0, 0, 0, 255, 0, 0])?;
+    for image in frames {
+        let image = image?;
+        let mut still = Vec::new();
+        image_webp::WebPEncoder::new(&mut still)
+            .encode(image.as_raw(), width, height, image_webp::ColorType::Rgb8)
+            .map_err(|e| e.to_string())?;
+        // Past the RIFF header is the one VP8L chunk
+        let bitstream = still.get(12..).ok_or("WebP encoder wrote no image")?;
+        let mut frame = vec![0; 6];
+        frame.extend(last_x);
+        frame.extend(last_y);
+        frame.extend(u24(delay_ms));
+        // Frames are opaque and cover the canvas, so there's nothing to blend or dispose of
+        frame.push(0b0000_0010);
+        frame.extend_from_slice(bitstream);
+        webp_chunk(&mut chunks, *b"ANMF", &frame)?;
+    }
+    let size = u32::try_from(chunks.len().saturating_add(4)).map_err(|_| "WebP file too large")?;
+    writer.write_all(b"RIFF").map_err(|e| e.to_string())?;
+    writer
+        .write_all(&size.to_le_bytes())
+        .map_err(|e| e.to_string())?;
+    writer.write_all(b"WEBP").map_err(|e| e.to_string())?;
+    writer.write_all(&chunks).map_err(|e| e.to_string())
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while let Some(rem) = a.checked_rem(b) {
+        (a, b) = (b, rem);
+    }
+    a
+}
+
+// BT.601 studio range, which is what readers take Y4M to be without being told otherwise
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn ycbcr(rgb: [u8; 3]) -> [u8; 3] {
+    let [r, g, b] = rgb.map(|channel| f32::from(channel) / 255.0);
+    let y = 16.0 + 65.481 * r + 128.553 * g + 24.966 * b;
+    let cb = 128.0 - 37.797 * r - 74.203 * g + 112.0 * b;
+    let cr = 128.0 + 112.0 * r - 93.786 * g - 18.214 * b;
+    // All within 16 to 240, so the cast is exact
+    [y, cb, cr].map(|val| val.round().clamp(0.0, 255.0) as u8)
+}
+
+fn write_y4m(
+    mut writer: impl Write,
+    width: u32,
+    height: u32,
+    (numerator, denominator): (u32, u32),
+    frames: impl Iterator<Item = Result<RgbImage, String>>,
+) -> Result<(), String> {
+    // Full resolution chroma, as subsampling is the encoder's call
+    writeln!(
+        writer,
+        "YUV4MPEG2 W{width} H{height} F{numerator}:{denominator} Ip A1:1 C444 XCOLORRANGE=LIMITED"
+    )
+    .map_err(|e| e.to_string())?;
+    for image in frames {
+        let image = image?;
+        let mut planes = [const { Vec::new() }; 3];
+        for pixel in image.pixels() {
+            for (plane, val) in planes.iter_mut().zip(ycbcr(pixel.0)) {
+                plane.push(val);
+            }
+        }
+        writer.write_all(b"FRAME\n").map_err(|e| e.to_string())?;
+        for plane in planes {
+            writer.write_all(&plane).map_err(|e| e.to_string())?;
+        }
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+fn write_rgb(
+    mut writer: impl Write,
+    frames: impl Iterator<Item = Result<RgbImage, String>>,
+) -> Result<(), String> {
+    for image in frames {
+        writer
+            .write_all(image?.as_raw())
+            .map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::node::{ArithmeticOp, FnNode, UnaryOp};
+    use crate::render::ColorMap;
+
+    // A sweep of colour across the image that moves with t
+    fn sweep() -> Program {
+        let sweep = FnNode::unary(
+            UnaryOp::Sin,
+            FnNode::arithmetic(
+                FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::Number(3.0)),
+                ArithmeticOp::Add,
+                FnNode::T,
+            ),
+        );
+        FnNode::triple(sweep.clone(), FnNode::Y, sweep)
+            .compile_program()
+            .expect("Compilation should succeed")
+    }
+
+    fn options() -> RenderOptions {
+        RenderOptions {
+            width: 23,
+            height: 17,
+            ..RenderOptions::default()
+        }
+    }
+
+    fn animation() -> Animation {
+        Animation {
+            frames: 5,
+            time: Interval::new(0.0, 2.0),
+            fps: 12.5,
+            ..Animation::default()
+        }
+    }
+
+    // The sweep's first frame, and the five frames of it written as `format`
+    fn written(format: AnimationFormat) -> (RgbImage, Vec<u8>) {
+        let program = sweep();
+        let first = options()
+            .render_image(&program, &AtomicBool::new(false), |_, _| {})
+            .expect("Render should succeed");
+        let mut bytes = Vec::new();
+        let mut reported = Vec::new();
+        animation()
+            .write(
+                &options(),
+                &program,
+                format,
+                &mut bytes,
+                &AtomicBool::new(false),
+                |done, total| reported.push((done, total)),
+            )
+            .expect("Writing should succeed");
+        assert_eq!(reported.last(), Some(&(5, 5)), "{format}");
+        (first, bytes)
+    }
+
+    #[test]
+    fn test_animation_times() {
+        let animation = animation();
+        assert_eq!(
+            animation.times().collect::<Vec<_>>(),
+            [0.0, 0.4, 0.8, 1.2, 1.6]
+        );
+        let looping = Animation {
+            frames: 4,
+            looping: true,
+            ..animation
+        };
+        let times = looping.times().collect::<Vec<_>>();
+        for (time, expected) in times.iter().zip([0.0, 1.0, 2.0, 1.0]) {
+            assert!((time - expected).abs() < 1e-6, "{times:?}");
+        }
+
+        let still = Animation {
+            frames: 0,
+            ..animation
+        };
+        let empty = still.write(
+            &options(),
+            &sweep(),
+            AnimationFormat::Gif,
+            Vec::new(),
+            &AtomicBool::new(false),
+            |_, _| {},
+        );
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn test_gif() {
+        let (first, bytes) = written(AnimationFormat::Gif);
+        let mut decode = gif::DecodeOptions::new();
+        decode.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = decode
+            .clone()
+            .read_info(bytes.as_slice())
+            .expect("GIF should decode");
+        let mut frames = 0;
+        while let Some(frame) = decoder.read_next_frame().expect("Frame should decode") {
+            assert_eq!(frame.delay, 8);
+            if frames == 0 {
+                // Dithering keeps the colour right on average, if not everywhere
+                let mut drift = [0i64; 3];
+                for (gif, rgb) in frame.buffer.chunks(4).zip(first.pixels()) {
+                    for ((drift, a), b) in drift.iter_mut().zip(gif).zip(rgb.0) {
+                        *drift += i64::from(*a) - i64::from(b);
+                    }
+                }
+                let pixels = i64::from(first.width() * first.height());
+                assert!(drift.iter().all(|drift| drift.abs() < pixels), "{drift:?}");
+            }
+            frames += 1;
+        }
+        assert_eq!(frames, 5);
+
+        // Few enough colours to keep exactly
+        let quarters = FnNode::triple(FnNode::X, FnNode::Y, FnNode::Number(0.0));
+        let small = RenderOptions {
+            width: 4,
+            height: 4,
+            ..options()
+        };
+        let mut bytes = Vec::new();
+        animation()
+            .write(
+                &small,
+                &quarters
+                    .compile_program()
+                    .expect("Compilation should succeed"),
+                AnimationFormat::Gif,
+                &mut bytes,
+                &AtomicBool::new(false),
+                |_, _| {},
+            )
+            .expect("Writing should succeed");
+        let mut decoder = decode
+            .read_info(bytes.as_slice())
+            .expect("GIF should decode");
+        let frame = decoder
+            .read_next_frame()
+            .expect("Frame should decode")
+            .expect("There should be a frame");
+        let expected = small.render(&quarters).expect("Render should succeed");
+        assert!(frame
+            .buffer
+            .chunks(4)
+            .zip(expected.pixels())
+            .all(|(gif, rgb)| gif.get(..3) == Some(&rgb.0[..])));
+    }
+
+    #[test]
+    fn test_apng() {
+        let (first, bytes) = written(AnimationFormat::Apng);
+        let decoder = png::Decoder::new(std::io::Cursor::new(&bytes));
+        let mut reader = decoder.read_info().expect("APNG should decode");
+        let control = reader.info().animation_control.expect("Should be animated");
+        assert_eq!((control.num_frames, control.num_plays), (5, 0));
+        let mut buffer = vec![0; reader.output_buffer_size().unwrap_or(0)];
+        reader.next_frame(&mut buffer).expect("Frame should decode");
+        assert!(buffer == *first.as_raw());
+    }
+
+    #[test]
+    fn test_webp() {
+        let (first, bytes) = written(AnimationFormat::WebP);
+        let mut decoder =
+            image_webp::WebPDecoder::new(std::io::Cursor::new(&bytes)).expect("WebP should decode");
+        assert!(decoder.is_animated());
+        assert_eq!(decoder.num_frames(), 5);
+        assert_eq!(decoder.dimensions(), (23, 17));
+        let mut buffer = vec![0; decoder.output_buffer_size().unwrap_or(0)];
+        let delay = decoder
+            .read_frame(&mut buffer)
+            .expect("Frame should decode");
+        assert_eq!(delay, 80);
+        let stride = if decoder.has_alpha() { 4 } else { 3 };
+        let rgb = buffer
+            .chunks(stride)
+            .flat_map(|pixel| pixel.iter().take(3).copied())
+            .collect::<Vec<_>>();
+        assert!(rgb == *first.as_raw());
+    }
+
+    #[test]
+    fn test_streams() {
+        let (first, bytes) = written(AnimationFormat::Y4m);
+        let header = "YUV4MPEG2 W23 H17 F25:2 Ip A1:1 C444 XCOLORRANGE=LIMITED\n";
+        assert!(bytes.starts_with(header.as_bytes()));
+        assert_eq!(
+            bytes.len(),
+            header.len() + ("FRAME\n".len() + first.len()) * 5
+        );
+
+        let (first, bytes) = written(AnimationFormat::Rgb);
+        assert_eq!(bytes.len(), first.len() * 5);
+        assert!(bytes.get(..first.len()) == Some(first.as_raw()));
+    }
+
+    #[test]
+    fn test_frame_sequences() {
+        assert_eq!(ycbcr([0, 0, 0]), [16, 128, 128]);
+        assert_eq!(ycbcr([255, 255, 255]), [235, 128, 128]);
+        assert_eq!(ycbcr([255, 0, 0]), [81, 90, 240]);
+
+        // Brightens from black to white as t goes from 0 to 1
+        let fade = FnNode::arithmetic(FnNode::T, ArithmeticOp::Sub, FnNode::Number(0.5));
+        let program = FnNode::triple(fade.clone(), fade.clone(), fade)
+            .compile_program()
+            .expect("Compilation should succeed");
+        let options = RenderOptions {
+            width: 3,
+            height: 2,
+            color_map: ColorMap::Shader,
+            ..RenderOptions::default()
+        };
+        let blurred = Animation {
+            frames: 2,
+            time: Interval::new(0.0, 1.0),
+            motion_samples: 4,
+            shutter: 1.0,
+            ..Animation::default()
+        };
+        assert_eq!(blurred.exposure(1), [0.5, 0.625, 0.75, 0.875]);
+        let mut bytes = Vec::new();
+        blurred
+            .write(
+                &options,
+                &program,
+                AnimationFormat::Rgb,
+                &mut bytes,
+                &AtomicBool::new(false),
+                |_, _| {},
+            )
+            .expect("Writing should succeed");
+        // The mean of the four renders, not any one of them
+        assert_eq!(bytes.get(..3), Some(&[47, 47, 47][..]));
+        assert_eq!(bytes.get(18..21), Some(&[175, 175, 175][..]));
+
+        let pattern = std::env::temp_dir().join("shaderand-sequence-test-###.png");
+        let pattern = pattern.to_str().expect("Temporary paths should be UTF-8");
+        blurred
+            .save_frames(
+                &options,
+                &program,
+                pattern,
+                &AtomicBool::new(false),
+                |_, _| {},
+            )
+            .expect("Saving should succeed");
+        for (frame, row) in ["000", "001"].into_iter().zip(bytes.chunks(18)) {
+            let path = pattern.replace("###", frame);
+            let image = image::open(&path).expect("Frame should load").to_rgb8();
+            assert!(image.as_raw() == row);
+            let _ = std::fs::remove_file(&path);
+        }
+        let unnumbered = blurred.save_frames(
+            &options,
+            &program,
+            "frame.png",
+            &AtomicBool::new(false),
+            |_, _| {},
+        );
+        assert!(unnumbered.is_err());
+    }
+}
@@ -0,0 +1,485 @@
+// Shader code generation. Each backend only spells out what differs between shading languages;
+// `compile_expr` walks the tree once for all of them.
+use std::f32::consts::TAU;
+use std::fmt::Write;
+
+use crate::hoist::Limits;
+use crate::interval::Interval;
+use crate::node::{ArithmeticOp, CompareOp, FnNode, UnaryOp};
+use crate::pretty::{Layout, Printer};
+use crate::template::{Slot, Template};
+use crate::validate::Frontend;
+
+pub trait ShaderBackend {
+    /// Preamble a standalone shader in this language starts with
+    fn version(&self) -> &'static str;
+
+    /// naga frontend that `validate` checks this language's output with
+    fn frontend(&self) -> Frontend;
+
+    /// Declaration of the temporary `name`, up to and including the `=`
+    fn declare(&self, name: &str, is_bool: bool) -> String;
+
+    /// A float with the given bit pattern, for values that have no literal (inf, NaN)
+    fn float_bits(&self, bits: u32) -> String;
+
+    fn modulo(&self, a: &str, b: &str) -> String;
+
+    fn select(&self, cond: &str, then: &str, elze: &str) -> String;
+
+    fn vec3(&self, r: &str, g: &str, b: &str) -> String;
+
+    /// The stock `{{color_map}}`, from the channels `rgb` to the displayed colour
+    fn color_map(&self) -> &'static str;
+
+    /// A function of `params`, each flagged if it is a bool, running the statements `body`. It
+    /// returns a bool if `is_bool` is set and a float otherwise.
+    fn function(&self, name: &str, params: &[(&str, bool)], is_bool: bool, body: &str) -> String;
+
+    // `{:?}` always prints a decimal point or exponent, so the literal is never typed as an integer
+    fn number(&self, val: f32) -> String {
+        if val.is_finite() {
+            format!("({val:?})")
+        } else {
+            self.float_bits(val.to_bits())
+        }
+    }
+
+    fn compile_expr(&self, node: &FnNode) -> Result<String, String> {
+        Ok(match node {
+            FnNode::X => "x".to_string(),
+            FnNode::Y => "y".to_string(),
+            FnNode::T => "t".to_string(),
+            FnNode::R => "r".to_string(),
+            FnNode::Theta => "theta".to_string(),
+            FnNode::Param(name, _) => name.clone(),
+            FnNode::Var(idx) => format!("v{idx}"),
+            FnNode::Call(idx, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.compile_expr(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                format!("h{idx}({})", args.join(", "))
+            }
+            FnNode::Number(val) => self.number(*val),
+            FnNode::Boolean(val) => val.to_string(),
+
+            FnNode::Random | FnNode::Rule(_, _) => {
+                return Err("Rule node encountered during shader compilation".to_string());
+            }
+
+            FnNode::Unary(kind, expr) => {
+                let name = match kind {
+                    UnaryOp::Sqrt => "sqrt",
+                    UnaryOp::Abs => "abs",
+                    UnaryOp::Sin => "sin",
+                    UnaryOp::Cos => "cos",
+                    UnaryOp::Tan => "tan",
+                };
+                format!("{name}({})", self.compile_expr(expr)?)
+            }
+
+            FnNode::Arithmetic(a, kind, b) => {
+                let (a, b) = (self.compile_expr(a)?, self.compile_expr(b)?);
+                let op = match kind {
+                    ArithmeticOp::Add => "+",
+                    ArithmeticOp::Sub => "-",
+                    ArithmeticOp::Mul => "*",
+                    ArithmeticOp::Div => "/",
+                    ArithmeticOp::Mod => return Ok(self.modulo(&a, &b)),
+                };
+                format!("({a} {op} {b})")
+            }
+
+            FnNode::Compare(a, kind, b) => {
+                let op = match kind {
+                    CompareOp::GreaterThanEqual => ">=",
+                    CompareOp::GreaterThan => ">",
+                    CompareOp::LessThanEqual => "<=",
+                    CompareOp::LessThan => "<",
+                    CompareOp::Equal => "==",
+                    CompareOp::NotEqual => "!=",
+                };
+                format!("({} {op} {})", self.compile_expr(a)?, self.compile_expr(b)?)
+            }
+
+            FnNode::If(cond, then, elze) => self.select(
+                &self.compile_expr(cond)?,
+                &self.compile_expr(then)?,
+                &self.compile_expr(elze)?,
+            ),
+
+            FnNode::Triple(r, g, b) => self.vec3(
+                &self.compile_expr(r)?,
+                &self.compile_expr(g)?,
+                &self.compile_expr(b)?,
+            ),
+        })
+    }
+}
+
+/// Desktop OpenGL, used by the native viewer
+pub struct Glsl450;
+
+/// WebGL 2 and Shadertoy. Unlike desktop GLSL there are no implicit int to float conversions.
+pub struct GlslEs300;
+
+/// WebGPU, used by the browser renderer
+pub struct Wgsl;
+
+fn glsl_declare(name: &str, is_bool: bool) -> String {
+    format!("{} {name} =", glsl_type(is_bool))
+}
+
+fn glsl_type(is_bool: bool) -> &'static str {
+    if is_bool {
+        "bool"
+    } else {
+        "float"
+    }
+}
+
+fn glsl_function(name: &str, params: &[(&str, bool)], is_bool: bool, body: &str) -> String {
+    let params = params
+        .iter()
+        .map(|(param, is_bool)| format!("{} {param}", glsl_type(*is_bool)))
+        .collect::<Vec<_>>();
+    format!(
+        "{} {name}({}) {{\n{body}}}\n",
+        glsl_type(is_bool),
+        params.join(", ")
+    )
+}
+
+fn glsl_select(cond: &str, then: &str, elze: &str) -> String {
+    format!("(({cond}) ? ({then}) : ({elze}))")
+}
+
+impl ShaderBackend for Glsl450 {
+    fn version(&self) -> &'static str {
+        "#version 450\n"
+    }
+
+    fn frontend(&self) -> Frontend {
+        Frontend::Glsl
+    }
+
+    fn declare(&self, name: &str, is_bool: bool) -> String {
+        glsl_declare(name, is_bool)
+    }
+
+    fn float_bits(&self, bits: u32) -> String {
+        format!("uintBitsToFloat({bits:#x}u)")
+    }
+
+    fn modulo(&self, a: &str, b: &str) -> String {
+        format!("mod({a}, {b})")
+    }
+
+    fn select(&self, cond: &str, then: &str, elze: &str) -> String {
+        glsl_select(cond, then, elze)
+    }
+
+    fn vec3(&self, r: &str, g: &str, b: &str) -> String {
+        format!("vec3({r}, {g}, {b})")
+    }
+
+    fn color_map(&self) -> &'static str {
+        "vec4(rgb + 0.5, 1.0)"
+    }
+
+    fn function(&self, name: &str, params: &[(&str, bool)], is_bool: bool, body: &str) -> String {
+        glsl_function(name, params, is_bool, body)
+    }
+}
+
+impl ShaderBackend for GlslEs300 {
+    fn version(&self) -> &'static str {
+        "#version 300 es\nprecision highp float;\n"
+    }
+
+    // naga only reads desktop GLSL, which the generated expressions are a subset of
+    fn frontend(&self) -> Frontend {
+        Frontend::Glsl
+    }
+
+    fn declare(&self, name: &str, is_bool: bool) -> String {
+        glsl_declare(name, is_bool)
+    }
+
+    fn float_bits(&self, bits: u32) -> String {
+        format!("uintBitsToFloat({bits:#x}u)")
+    }
+
+    fn modulo(&self, a: &str, b: &str) -> String {
+        format!("mod({a}, {b})")
+    }
+
+    fn select(&self, cond: &str, then: &str, elze: &str) -> String {
+        glsl_select(cond, then, elze)
+    }
+
+    fn vec3(&self, r: &str, g: &str, b: &str) -> String {
+        format!("vec3({r}, {g}, {b})")
+    }
+
+    fn color_map(&self) -> &'static str {
+        "vec4(rgb + 0.5, 1.0)"
+    }
+
+    fn function(&self, name: &str, params: &[(&str, bool)], is_bool: bool, body: &str) -> String {
+        glsl_function(name, params, is_bool, body)
+    }
+}
+
+impl ShaderBackend for Wgsl {
+    fn version(&self) -> &'static str {
+        ""
+    }
+
+    fn frontend(&self) -> Frontend {
+        Frontend::Wgsl
+    }
+
+    fn declare(&self, name: &str, _is_bool: bool) -> String {
+        format!("let {name} =")
+    }
+
+    fn float_bits(&self, bits: u32) -> String {
+        format!("bitcast<f32>({bits:#x}u)")
+    }
+
+    // WGSL has no `mod`; `%` truncates like the CPU evaluator where GLSL's `mod` floors
+    fn modulo(&self, a: &str, b: &str) -> String {
+        format!("({a} % {b})")
+    }
+
+    fn select(&self, cond: &str, then: &str, elze: &str) -> String {
+        format!("select({elze}, {then}, {cond})")
+    }
+
+    fn vec3(&self, r: &str, g: &str, b: &str) -> String {
+        format!("vec3<f32>({r}, {g}, {b})")
+    }
+
+    fn color_map(&self) -> &'static str {
+        "vec4<f32>(rgb + 0.5, 1.0)"
+    }
+
+    fn function(&self, name: &str, params: &[(&str, bool)], is_bool: bool, body: &str) -> String {
+        let wgsl_type = |is_bool| if is_bool { "bool" } else { "f32" };
+        let params = params
+            .iter()
+            .map(|(param, is_bool)| format!("{param}: {}", wgsl_type(*is_bool)))
+            .collect::<Vec<_>>();
+        format!(
+            "fn {name}({}) -> {} {{\n{body}}}\n",
+            params.join(", "),
+            wgsl_type(is_bool)
+        )
+    }
+}
+
+/// How a shader's running time in seconds becomes `t`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeMap {
+    /// `tan(seconds)`, which reaches every t but jumps from +inf back to -inf every π seconds
+    #[default]
+    Tan,
+    /// Eases from `range.lo` to `range.hi` and back every `period` seconds, so animations loop
+    /// without a seam
+    Loop { period: f32, range: Interval },
+}
+
+impl TimeMap {
+    /// The t the shaders compute at `seconds`
+    pub fn t(self, seconds: f32) -> f32 {
+        match self {
+            TimeMap::Tan => seconds.tan(),
+            TimeMap::Loop { period, range } => {
+                let (mid, half) = Self::swing(range);
+                mid - half * (seconds * (TAU / period)).cos()
+            }
+        }
+    }
+
+    /// The stock `{{time_map}}`, from `seconds` to t
+    pub fn shader_expr(self, backend: &dyn ShaderBackend) -> String {
+        match self {
+            TimeMap::Tan => "tan(seconds)".to_string(),
+            TimeMap::Loop { period, range } => {
+                let (mid, half) = Self::swing(range);
+                format!(
+                    "{} - {} * cos(seconds * {})",
+                    backend.number(mid),
+                    backend.number(half),
+                    backend.number(TAU / period)
+                )
+            }
+        }
+    }
+
+    // Centre and half width of the range a loop swings across
+    fn swing(range: Interval) -> (f32, f32) {
+        (range.lo.midpoint(range.hi), (range.hi - range.lo) / 2.0)
+    }
+}
+
+impl FnNode {
+    /// Fills `template` with the expression, its shared temporaries, the backend's colour map and
+    /// `time_map`. `uniforms` declares the params in whatever form the caller's pipeline provides
+    /// them. Expressions exceeding `limits` are split into helper functions.
+    pub fn compile_fs(
+        &mut self,
+        template: &Template,
+        uniforms: &str,
+        backend: &dyn ShaderBackend,
+        layout: Layout,
+        limits: Limits,
+        time_map: TimeMap,
+    ) -> Result<String, String> {
+        self.optimize()?;
+        let mut shared = self.share()?;
+        let hoisted = shared.hoist(limits)?;
+
+        let (helpers, lets, compiled_node) = match layout {
+            Layout::Compact => {
+                let mut helpers = String::new();
+                for (idx, helper) in hoisted.iter().enumerate() {
+                    let mut params = Vec::with_capacity(helper.params.len());
+                    for (arg, is_bool) in &helper.params {
+                        params.push((backend.compile_expr(arg)?, *is_bool));
+                    }
+                    let params = params
+                        .iter()
+                        .map(|(param, is_bool)| (param.as_str(), *is_bool))
+                        .collect::<Vec<_>>();
+                    let body = format!("    return {};\n", backend.compile_expr(&helper.body)?);
+                    helpers.push_str(&backend.function(
+                        &format!("h{idx}"),
+                        &params,
+                        helper.is_bool,
+                        &body,
+                    ));
+                }
+                let mut lets = String::new();
+                for (idx, binding) in shared.lets.iter().enumerate() {
+                    writeln!(
+                        lets,
+                        "    {} {};",
+                        backend.declare(&format!("v{idx}"), binding.is_bool),
+                        backend.compile_expr(&binding.expr)?
+                    )
+                    .map_err(|e| format!("{e}"))?;
+                }
+                (helpers, lets, backend.compile_expr(&shared.body)?)
+            }
+            Layout::Pretty { width, origins } => Printer {
+                backend,
+                width,
+                origins,
+            }
+            .shader(&shared, &hoisted, template.has(Slot::Helpers))?,
+        };
+
+        let formatted_fs = template.render(&[
+            (Slot::Uniforms, uniforms),
+            (Slot::Helpers, &helpers),
+            (Slot::Lets, &lets),
+            (Slot::Expr, &compiled_node),
+            (Slot::ColorMap, backend.color_map()),
+            (Slot::TimeMap, &time_map.shader_expr(backend)),
+        ])?;
+        eprintln!("{formatted_fs}");
+        Ok(formatted_fs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // One of every node the code generators accept, bar the `Var`s that CSE introduces
+    fn every_variant() -> FnNode {
+        let arithmetic = [
+            ArithmeticOp::Add,
+            ArithmeticOp::Sub,
+            ArithmeticOp::Mul,
+            ArithmeticOp::Div,
+            ArithmeticOp::Mod,
+        ]
+        .into_iter()
+        .fold(FnNode::X, |acc, op| FnNode::arithmetic(acc, op, FnNode::Y));
+        let unary = [
+            UnaryOp::Sqrt,
+            UnaryOp::Abs,
+            UnaryOp::Sin,
+            UnaryOp::Cos,
+            UnaryOp::Tan,
+        ]
+        .into_iter()
+        .fold(FnNode::T, |acc, op| FnNode::unary(op, acc));
+        let compares = [
+            CompareOp::GreaterThan,
+            CompareOp::LessThan,
+            CompareOp::GreaterThanEqual,
+            CompareOp::LessThanEqual,
+            CompareOp::Equal,
+            CompareOp::NotEqual,
+        ]
+        .into_iter()
+        .fold(FnNode::Number(f32::INFINITY), |acc, op| {
+            FnNode::if_(
+                FnNode::compare(FnNode::R, op, FnNode::Theta),
+                acc,
+                FnNode::X,
+            )
+        });
+        FnNode::triple(
+            arithmetic,
+            FnNode::if_(
+                FnNode::Boolean(true),
+                unary,
+                FnNode::Param("speed".to_string(), 0.5),
+            ),
+            FnNode::arithmetic(compares, ArithmeticOp::Add, FnNode::Number(-1.0)),
+        )
+    }
+
+    #[test]
+    fn test_every_variant_compiles() {
+        let node = every_variant();
+        let glsl = Glsl450.compile_expr(&node).expect("GLSL should compile");
+        let glsl_es = GlslEs300
+            .compile_expr(&node)
+            .expect("GLSL ES should compile");
+        let wgsl = Wgsl.compile_expr(&node).expect("WGSL should compile");
+
+        assert_eq!(glsl, glsl_es);
+        assert!(glsl.starts_with("vec3(mod(((((x + y) - y) * y) / y), y), "));
+        assert!(glsl.contains("tan(cos(sin(abs(sqrt(t)))))"));
+        assert!(glsl.contains("((true) ? ("));
+        assert!(glsl.contains("uintBitsToFloat(0x7f800000u)"));
+        assert!(glsl.contains(" + (-1.0))"));
+
+        assert!(wgsl.starts_with("vec3<f32>((((((x + y) - y) * y) / y) % y), select(speed, "));
+        assert!(wgsl.contains("bitcast<f32>(0x7f800000u)"));
+        assert!(wgsl.contains("select(x, "));
+        for op in [">=", "<=", "==", "!="] {
+            assert!(wgsl.contains(&format!("(r {op} theta)")));
+        }
+        for wgsl_invalid in ["?", "mod(", "vec3("] {
+            assert!(!wgsl.contains(wgsl_invalid), "WGSL contains {wgsl_invalid}");
+        }
+
+        for backend in [&Glsl450 as &dyn ShaderBackend, &GlslEs300, &Wgsl] {
+            assert!(backend.compile_expr(&FnNode::Random).is_err());
+            assert!(backend.compile_expr(&FnNode::Rule(0, 'A')).is_err());
+            assert_eq!(backend.number(1.0), "(1.0)");
+            assert_eq!(backend.compile_expr(&FnNode::Var(3)).as_deref(), Ok("v3"));
+            if let Err(e) = node.validate(backend) {
+                panic!("{e}\n{}", e.source);
+            }
+        }
+    }
+}
@@ -32,7 +32,8 @@ pub enum TokenKind {
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*")]
     Identifier,
 
-    #[regex(r"[0-9]+")]
+    // `-inf` too, being how a negative infinity prints
+    #[regex(r"-?[0-9]+(\.[0-9]+)?|-inf")]
     Number,
 
     #[token("(")]
@@ -48,6 +49,15 @@ pub enum TokenKind {
     #[token(":")]
     Colon,
 
+    #[token("=")]
+    Equals,
+
+    #[token("[")]
+    LBracket,
+
+    #[token("]")]
+    RBracket,
+
     #[token(";")]
     End,
 
@@ -4,7 +4,7 @@ use logos::Logos;
 use crate::node::{ArithmeticOp, FnNode, UnaryOp};
 
 // We'll assume these types are already defined in your project
-use crate::grammar::{Branch, Grammar};
+use crate::grammar::{Branch, Grammar, Param};
 
 use crate::bnf_lexer::TokenKind;
 
@@ -14,8 +14,10 @@ pub enum ParseError {
     ExpectedIdentifier,
     ExpectedColonColonEqual,
     ExpectedEnd,
+    ExpectedNumber,
     InvalidBranchWeight,
     InvalidRule,
+    InvalidParam(String),
     UnknownSymbol(String),
     UnknownFunction(String),
 }
@@ -24,6 +26,53 @@ pub enum ParseError {
 pub struct Parser<'a> {
     lexer: logos::Lexer<'a, TokenKind>,
     symbols: Vec<String>,
+    params: Vec<Param>,
+}
+
+// Names that already mean something inside an expression and so cannot be reused for a param
+const RESERVED_NAMES: &[&str] = &[
+    "x", "X", "y", "Y", "z", "Z", "t", "T", "r", "R", "theta", "random", "vec3", "add", "sub",
+    "mul", "div", "mod", "sqrt", "abs", "sin", "cos", "tan", "param", "in",
+];
+
+// Params go into the shaders and exported source by name, so they can't take a name the stock
+// templates, the channel helpers of pretty printed shaders, source preludes or the built-in
+// functions the generated code calls already use
+const TEMPLATE_NAMES: &str = "\
+    time seconds rgb uniforms Uniforms params VertexOutput clip_position tex_coords map_rgb \
+    time_map fs_main vs_main fragTexCoord finalColor applyColorTransform timeMap main mainImage \
+    fragColor fragCoord uv iTime iResolution color_r color_g color_b art Math FLT_EPSILON length \
+    atan atan2 select bitcast uintBitsToFloat vec2 vec4 sqrtf fabsf sinf cosf tanf atan2f fmodf";
+
+// Keywords and type names of GLSL, WGSL, C, JavaScript and Rust, the languages params end up in
+const KEYWORDS: &str = "\
+    attribute bool break bvec2 bvec3 bvec4 case const continue default discard do else false flat \
+    float for highp if inout int invariant ivec2 ivec3 ivec4 layout lowp mat2 mat3 mat4 mediump \
+    out precision return sampler2D smooth struct switch true uint uniform uvec2 uvec3 uvec4 \
+    varying void while alias array diagnostic enable f16 f32 fn i32 let loop override ptr \
+    requires u32 var vec3f vec4f auto char double enum extern goto inline long register restrict \
+    short signed sizeof static typedef union unsigned volatile arguments await catch class \
+    debugger delete eval export extends finally function implements import instanceof interface \
+    new null package private protected public super this throw try typeof undefined with yield \
+    Infinity NaN as async crate dyn impl match move mut pub ref self Self trait type unsafe use \
+    where inf";
+
+// Whether a param can't be called `name`
+fn is_reserved(name: &str) -> bool {
+    // `v<N>` is left free for the temporaries introduced by CSE and `h<N>` for the helper
+    // functions hoisting splits out
+    let is_temporary = name
+        .strip_prefix(['v', 'h'])
+        .is_some_and(|n| n.parse::<usize>().is_ok());
+    // GLSL reserves `gl_` and both GLSL and WGSL reserve `__`
+    is_temporary
+        || name.starts_with("gl_")
+        || name.contains("__")
+        || RESERVED_NAMES.contains(&name)
+        || TEMPLATE_NAMES
+            .split_whitespace()
+            .chain(KEYWORDS.split_whitespace())
+            .any(|reserved| reserved == name)
 }
 
 impl<'a> Parser<'a> {
@@ -33,22 +82,28 @@ impl<'a> Parser<'a> {
         Parser {
             lexer,
             symbols: Vec::new(),
+            params: Vec::new(),
         }
     }
 
     pub fn collect_symbols(&mut self) -> Result<(), ParseError> {
         let mut lexer = self.lexer.clone();
         // web_sys::console::log_1(&format!("Tokens: {:?}", self.lexer).into());
-        println!("Tokens: {:?}", self.lexer);
+        eprintln!("Tokens: {:?}", self.lexer);
         let mut ended = true;
 
         while let Some(Ok(token)) = lexer.next() {
             match token {
                 TokenKind::Identifier => {
                     let symbol = lexer.slice();
-                    if lexer.next() == Some(Ok(TokenKind::Pipes)) && ended {
-                        self.symbols.push(symbol.to_string());
-                        ended = false;
+                    // The lookahead may be the `;` closing a branch that ends in a bare terminal
+                    match lexer.next() {
+                        Some(Ok(TokenKind::Pipes)) if ended => {
+                            self.symbols.push(symbol.to_string());
+                            ended = false;
+                        }
+                        Some(Ok(TokenKind::End)) => ended = true,
+                        _ => {}
                     }
                 }
 
@@ -69,7 +124,7 @@ impl<'a> Parser<'a> {
     pub fn parse(&mut self) -> Result<Grammar, ParseError> {
         let mut grammar = Grammar::new();
         self.collect_symbols()?;
-        println!("Symbols: {:?}", self.symbols);
+        eprintln!("Symbols: {:?}", self.symbols);
 
         while let Some(Ok(token)) = self.lexer.next() {
             match token {
@@ -77,6 +132,8 @@ impl<'a> Parser<'a> {
                     let symbol = self.lexer.slice();
                     if self.symbols.contains(&symbol.to_string()) {
                         self.parse_rule(symbol, &mut grammar)?;
+                    } else if symbol == "param" {
+                        self.parse_param(&mut grammar)?;
                     } else {
                         return Err(ParseError::UnknownSymbol(symbol.to_string()));
                     }
@@ -113,6 +170,57 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    // param <name> = <default> in [<min>, <max>];
+    pub fn parse_param(&mut self, grammar: &mut Grammar) -> Result<(), ParseError> {
+        if self.lexer.next() != Some(Ok(TokenKind::Identifier)) {
+            return Err(ParseError::ExpectedIdentifier);
+        }
+        let name = self.lexer.slice().to_string();
+        if is_reserved(&name) || self.symbols.contains(&name) {
+            return Err(ParseError::InvalidParam(name));
+        }
+
+        self.expect(&TokenKind::Equals)?;
+        let default = self.parse_number()?;
+        self.expect(&TokenKind::Identifier)?;
+        if self.lexer.slice() != "in" {
+            return Err(ParseError::UnexpectedToken(TokenKind::Identifier));
+        }
+        self.expect(&TokenKind::LBracket)?;
+        let min = self.parse_number()?;
+        self.expect(&TokenKind::Comma)?;
+        let max = self.parse_number()?;
+        self.expect(&TokenKind::RBracket)?;
+        if self.lexer.next() != Some(Ok(TokenKind::End)) {
+            return Err(ParseError::ExpectedEnd);
+        }
+
+        let param = Param::new(name, default, min, max);
+        grammar
+            .add_param(param.clone())
+            .map_err(|_| ParseError::InvalidParam(param.name.clone()))?;
+        self.params.push(param);
+        Ok(())
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> Result<(), ParseError> {
+        match self.lexer.next() {
+            Some(Ok(token)) if token == *kind => Ok(()),
+            Some(Ok(token)) => Err(ParseError::UnexpectedToken(token)),
+            _ => Err(ParseError::UnexpectedToken(TokenKind::EOF)),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<f32, ParseError> {
+        if self.lexer.next() != Some(Ok(TokenKind::Number)) {
+            return Err(ParseError::ExpectedNumber);
+        }
+        self.lexer
+            .slice()
+            .parse()
+            .map_err(|_| ParseError::ExpectedNumber)
+    }
+
     pub fn parse_branch(&mut self, weight: usize) -> Result<Branch, ParseError> {
         if let Some(Ok(token)) = self.lexer.next() {
             match token {
@@ -138,10 +246,15 @@ impl<'a> Parser<'a> {
                 ident.chars().last().unwrap_or(' '),
             ));
         }
+        if let Some(param) = self.params.iter().find(|p| p.name == ident) {
+            return Ok(FnNode::Param(param.name.clone(), param.default));
+        }
         match ident {
             "X" | "x" => Ok(FnNode::X),
             "Y" | "y" => Ok(FnNode::Y),
             "Z" | "z" | "T" | "t" => Ok(FnNode::T),
+            "R" | "r" => Ok(FnNode::R),
+            "theta" => Ok(FnNode::Theta),
             "random" => Ok(FnNode::Random),
             "vec3" => {
                 let mut nodes = Vec::new();
@@ -210,7 +323,7 @@ impl<'a> Parser<'a> {
                     }
                 }
                 if nodes.len() != 1 {
-                    println!("Invalid number of arguments: {nodes:?}");
+                    eprintln!("Invalid number of arguments: {nodes:?}");
                     return Err(ParseError::InvalidRule);
                 }
 
@@ -267,9 +380,87 @@ mod test {
         let result = parser.parse();
         assert!(result.is_ok(), "Parse should be successful");
         let _ = result.map(|grammar| {
-            println!("{grammar}");
+            eprintln!("{grammar}");
             let node = grammar.gen_from_rule(0, 10);
             assert!(node.is_some(), "Node should be generated");
         });
     }
+
+    #[test]
+    fn test_parse_polar_terminals() {
+        let mut parser = Parser::new("E | vec3(r, theta, x) ;");
+        let grammar = parser.parse().expect("Parse should be successful");
+        let node = grammar
+            .gen_from_rule(0, 2)
+            .expect("Node should be generated");
+        assert_eq!(node.to_string(), "(r, theta, x)");
+    }
+
+    #[test]
+    fn test_parse_rule_ending_in_terminal() {
+        let input = "E | vec3(C, C, C) ;\nB | x | r ;\nC | B || add(C, B) ;";
+        let grammar = Parser::new(input)
+            .parse()
+            .expect("Parse should be successful");
+        assert_eq!(grammar.symbols, ["E", "B", "C"]);
+    }
+
+    #[test]
+    fn test_parse_shipped_grammar() {
+        let grammar = Parser::new(include_str!("../grammar.bnf"))
+            .parse()
+            .expect("Parse should be successful");
+        assert_eq!(grammar.symbols, ["E", "B", "C"]);
+        assert!(grammar.gen_seeded(0, 8, 0).is_some());
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let input = "param speed = 0.5 in [0, 2];\nE | vec3(speed, x, y) ;";
+        let grammar = Parser::new(input)
+            .parse()
+            .expect("Parse should be successful");
+        let param = grammar.param("speed").expect("Param should be declared");
+        assert!((param.default - 0.5).abs() < f32::EPSILON);
+        assert!((param.max - 2.0).abs() < f32::EPSILON);
+
+        // The grammar is stored as text by the web app, so params have to round-trip
+        let reparsed = Parser::new(&grammar.to_string()).parse();
+        assert!(reparsed.is_ok_and(|g| g.params.len() == 1));
+
+        assert!(Parser::new("param x = 0 in [0, 1];").parse().is_err());
+        assert!(Parser::new("param a = 3 in [0, 1];").parse().is_err());
+    }
+
+    #[test]
+    fn test_reject_target_names() {
+        let names = [
+            "time",
+            "seconds",
+            "uniforms",
+            "fragColor",
+            "uv",
+            "float",
+            "let",
+            "fn",
+            "main",
+            "Math",
+            "art",
+            "static",
+            "gl_speed",
+            "my__speed",
+            "v3",
+            "h0",
+            "color_g",
+        ];
+        for name in names {
+            let input = format!("param {name} = 0.5 in [0, 1];\nE | vec3(x, y, t) ;");
+            assert!(
+                Parser::new(&input).parse().is_err(),
+                "{name} should be rejected"
+            );
+        }
+        let input = "param time_scale = 0.5 in [0, 1];\nE | vec3(time_scale, y, t) ;";
+        assert!(Parser::new(input).parse().is_ok());
+    }
 }
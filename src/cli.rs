@@ -0,0 +1,646 @@
+use std::fmt::Display;
+use std::io::BufWriter;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+
+use crate::animation::{Animation, AnimationFormat};
+use crate::backend::{Glsl450, ShaderBackend, TimeMap, Wgsl};
+use crate::bnf_parser::Parser;
+use crate::export::{self, ExportFormat};
+use crate::grammar::{Grammar, Origins};
+use crate::hoist::Limits;
+use crate::interval::Interval;
+use crate::metadata::Metadata;
+use crate::native;
+use crate::node::FnNode;
+use crate::pretty::Layout;
+use crate::recipe::{Recipe, TemplateChoice};
+use crate::render::{Depth, ImageFormat, RenderOptions, Sampling};
+use crate::template::Template;
+use crate::vm::{Evaluator, Program};
+
+/// How deep generated functions may nest rules
+const GEN_DEPTH: usize = 10;
+
+const USAGE: &str = r"
+Usage: shaderand [OPTIONS]
+
+Options:
+    --grammar <PATH>       Grammar file to generate from (default: ./grammar.bnf)
+    --param <NAME=VALUE>   Override a grammar param, may be repeated
+    --seed <N>             Generate the function --render and --animate draw from N, the same
+                           grammar and seed always giving the same function (default: random)
+    --render               Render a single frame on the CPU instead of opening the viewer
+    --output <PATH>        Where --render writes the image, as PNG, JPEG, WebP, BMP, TIFF,
+                           OpenEXR or Radiance HDR going by the extension (default: output.png).
+                           OpenEXR and HDR keep channel values unclamped, and PNGs record what
+                           they were made from for --from
+    --from <PATH>          Render again what a PNG saved by --render was made from, with the
+                           recipe and settings it records. Options after it override them, a
+                           different --seed generating anew from its grammar.
+    --recipe <PATH>        Draw a recipe saved by --save-recipe or the web app, in the viewer
+                           unless --render, --animate or --export is given. Options after it
+                           override it as they do --from.
+    --save-recipe <PATH>   Also save what is drawn as a recipe: the grammar, seed, function,
+                           params and settings, for --recipe, --from or the web app
+    --tone-map <MAP>       How 8 and 16-bit images bring channel values into range: clamp,
+                           reinhard, aces, or auto to stretch each channel to fill it
+                           (default: clamp)
+    --depth <BITS>         Bits per channel of PNG output, 8 or 16 (default: 8)
+    --time <T>             The t --render draws (default: 0)
+    --animate <PATH>       Render an animation over --t-range on the CPU instead of opening the
+                           viewer, as a GIF, APNG, animated WebP, Y4M or raw RGB going by the
+                           extension (.gif, .png, .webp, .y4m, .rgb)
+    --sequence <PATTERN>   Render the --animate frames as numbered images instead, the last run
+                           of #s in PATTERN becoming the frame number (e.g. frames/####.png)
+    --pipe <FORMAT>        Write the --animate frames to stdout as y4m or rgb instead, e.g.
+                           --pipe y4m | ffmpeg -i - out.mp4
+    --frames <N>           Frames in the animation (default: 60)
+    --duration <SECONDS>   Length of the animation, setting --frames from --fps
+    --fps <FPS>            Frame rate of the animation (default: 30)
+    --motion-blur <N>      Average N renders spread across the shutter into each frame
+    --shutter <FRACTION>   How much of the time between frames --motion-blur spreads across,
+                           from 0 to 1 (default: 0.5)
+    --size <WxH>           Size of the --render image or animation frames (default: 1920x944)
+    --threads <N>          Threads --render uses (default: one per core)
+    --samples <N>          Antialias --render by averaging an N by N grid of samples per pixel
+    --jitter <SEED>        Jitter each --samples sample within its cell, reproducibly for SEED
+    --adaptive <DIFF>      Only take --samples where a pixel differs from a neighbour by more than
+                           DIFF, from 0 to 1, in some channel
+    --export <PATH>        Write the shader to PATH instead of opening the viewer, as WGSL, HLSL,
+                           Metal, SPIR-V, a Shadertoy program, or a Rust, C or JavaScript
+                           function going by the extension
+                           (.wgsl, .hlsl, .metal, .spv, .shadertoy, .rs, .c, .js)
+    --normalize            Rescale each colour channel so its range fills the visible range
+    --t-range <LO,HI>      Range of t assumed by --normalize and shown by --animate and --loop
+                           (default: -1,1)
+    --loop <SECONDS>       Ease t across --t-range and back every SECONDS in the viewer and
+                           exported shaders instead of sweeping it with tan(time), so they loop
+                           seamlessly. Animations render one period at --fps, ignoring --frames
+                           and --duration.
+    --template <PATH>      GLSL 450 fragment shader template for the viewer, with {{expr}} where
+                           the colour goes and optionally {{uniforms}}, {{helpers}}, {{lets}},
+                           {{color_map}} and {{time_map}}
+    --max-expr-depth <N>   Deepest nesting allowed in a shader expression before subtrees are
+                           split into helper functions (default: 32)
+    --max-expr-nodes <N>   Most operations allowed in a shader expression before subtrees are
+                           split into helper functions (default: 256)
+    --help                 Print this message
+";
+
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub grammar_path: String,
+    pub params: Vec<(String, f32)>,
+    pub seed: Option<u64>,
+    pub from: Option<Recipe>,
+    pub save_recipe: Option<String>,
+    pub render: bool,
+    pub output: String,
+    pub render_options: RenderOptions,
+    pub animate: Option<String>,
+    pub sequence: Option<String>,
+    pub pipe: Option<AnimationFormat>,
+    pub animation: Animation,
+    pub duration: Option<f32>,
+    pub export: Option<String>,
+    pub normalize: bool,
+    pub t_range: Interval,
+    pub loop_period: Option<f32>,
+    pub template: Option<String>,
+    pub limits: Limits,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            grammar_path: "./grammar.bnf".to_string(),
+            params: Vec::new(),
+            seed: None,
+            from: None,
+            save_recipe: None,
+            render: false,
+            output: "output.png".to_string(),
+            render_options: RenderOptions::default(),
+            animate: None,
+            sequence: None,
+            pipe: None,
+            animation: Animation::default(),
+            duration: None,
+            export: None,
+            normalize: false,
+            t_range: Interval::new(-1.0, 1.0),
+            loop_period: None,
+            template: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--grammar" => {
+                    parsed.grammar_path = args.next().ok_or("--grammar expects a path")?;
+                }
+                "--param" => {
+                    let param = args.next().ok_or("--param expects NAME=VALUE")?;
+                    let (name, value) = param
+                        .split_once('=')
+                        .ok_or(format!("Invalid param override: {param}"))?;
+                    let value = value
+                        .parse::<f32>()
+                        .map_err(|e| format!("Invalid value for param {name}: {e}"))?;
+                    parsed.params.push((name.to_string(), value));
+                }
+                "--seed" => parsed.seed = Some(number(&mut args, &arg)?),
+                "--from" => {
+                    let path = args.next().ok_or("--from expects a path")?;
+                    let metadata = Metadata::read(Path::new(&path))?;
+                    parsed.render_options = RenderOptions {
+                        threads: parsed.render_options.threads,
+                        tile_size: parsed.render_options.tile_size,
+                        ..metadata.options
+                    };
+                    parsed.load(metadata.recipe);
+                    parsed.render = true;
+                }
+                "--recipe" => {
+                    let path = args.next().ok_or("--recipe expects a path")?;
+                    let recipe = std::fs::read_to_string(&path)
+                        .map_err(|e| format!("{path}: {e}"))?
+                        .parse()
+                        .map_err(|e| format!("{path}: {e}"))?;
+                    parsed.load(recipe);
+                }
+                "--save-recipe" => {
+                    parsed.save_recipe = Some(args.next().ok_or("--save-recipe expects a path")?);
+                }
+                "--render" => parsed.render = true,
+                "--export" => {
+                    parsed.export = Some(args.next().ok_or("--export expects a path")?);
+                }
+                "--normalize" => parsed.normalize = true,
+                "--t-range" => {
+                    let range = args.next().ok_or("--t-range expects LO,HI")?;
+                    let (lo, hi) = range
+                        .split_once(',')
+                        .ok_or(format!("Invalid t range: {range}"))?;
+                    let parse = |val: &str| {
+                        val.trim()
+                            .parse::<f32>()
+                            .map_err(|e| format!("Invalid t range {range}: {e}"))
+                    };
+                    let (lo, hi) = (parse(lo)?, parse(hi)?);
+                    if lo > hi {
+                        return Err(format!("Invalid t range {range}: {lo} > {hi}"));
+                    }
+                    parsed.t_range = Interval::new(lo, hi);
+                }
+                "--loop" => {
+                    let period: f32 = number(&mut args, &arg)?;
+                    if !(period.is_finite() && period > 0.0) {
+                        return Err(format!("--loop must be above 0, not {period}"));
+                    }
+                    parsed.loop_period = Some(period);
+                }
+                "--template" => {
+                    parsed.template = Some(args.next().ok_or("--template expects a path")?);
+                }
+                "--max-expr-depth" | "--max-expr-nodes" => {
+                    let limit = count(&mut args, &arg)?;
+                    if arg == "--max-expr-depth" {
+                        parsed.limits.max_depth = limit;
+                    } else {
+                        parsed.limits.max_nodes = limit;
+                    }
+                }
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    return Ok(None);
+                }
+                _ => {
+                    if !parsed.parse_render(&arg, &mut args)? {
+                        return Err(format!("Unknown argument: {arg}\n{USAGE}"));
+                    }
+                }
+            }
+        }
+        Ok(Some(parsed))
+    }
+
+    // Takes on the settings of a recipe to draw it again
+    fn load(&mut self, recipe: Recipe) {
+        self.seed = Some(recipe.seed);
+        self.params.clone_from(&recipe.params);
+        self.normalize = recipe.normalize;
+        self.t_range = recipe.t_range;
+        self.loop_period = recipe.loop_period;
+        self.render_options.width = recipe.width;
+        self.render_options.height = recipe.height;
+        self.render_options.t = recipe.time;
+        self.render_options.color_map = recipe.color_map;
+        self.from = Some(recipe);
+    }
+
+    /// The animation the flags describe, over --t-range
+    pub fn animation(&self) -> Animation {
+        let mut animation = Animation {
+            time: self.t_range,
+            ..self.animation
+        };
+        // Either sets the length in seconds, a loop taking precedence
+        if let Some(seconds) = self.loop_period.or(self.duration) {
+            // Rounded and at least 1, so the cast is exact
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let frames = (seconds * animation.fps)
+                .round()
+                .clamp(1.0, u32::MAX as f32) as u32;
+            animation.frames = frames;
+        }
+        animation.looping = self.loop_period.is_some();
+        animation
+    }
+
+    fn animates(&self) -> bool {
+        self.animate.is_some() || self.sequence.is_some() || self.pipe.is_some()
+    }
+
+    // The options for CPU rendering, returning whether `arg` was one
+    fn parse_render(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, String> {
+        match arg {
+            "--output" => {
+                self.output = args.next().ok_or("--output expects a path")?;
+            }
+            "--time" => self.render_options.t = number(args, arg)?,
+            "--animate" => {
+                self.animate = Some(args.next().ok_or("--animate expects a path")?);
+            }
+            "--sequence" => {
+                self.sequence = Some(args.next().ok_or("--sequence expects a pattern")?);
+            }
+            "--pipe" => {
+                let format = args.next().ok_or("--pipe expects y4m or rgb")?.parse()?;
+                if !matches!(format, AnimationFormat::Y4m | AnimationFormat::Rgb) {
+                    return Err(format!("--pipe writes y4m or rgb, not {format}"));
+                }
+                self.pipe = Some(format);
+            }
+            "--frames" => self.animation.frames = count(args, arg)?,
+            "--duration" | "--fps" => {
+                let val: f32 = number(args, arg)?;
+                if !(val.is_finite() && val > 0.0) {
+                    return Err(format!("{arg} must be above 0, not {val}"));
+                }
+                if arg == "--fps" {
+                    self.animation.fps = val;
+                } else {
+                    self.duration = Some(val);
+                }
+            }
+            "--motion-blur" => self.animation.motion_samples = count(args, arg)?,
+            "--shutter" => {
+                let shutter: f32 = number(args, arg)?;
+                if !(0.0..=1.0).contains(&shutter) {
+                    return Err(format!("--shutter must be from 0 to 1, not {shutter}"));
+                }
+                self.animation.shutter = shutter;
+            }
+            "--size" => {
+                let size = args.next().ok_or("--size expects WxH")?;
+                let (width, height) = size
+                    .split_once('x')
+                    .ok_or(format!("Invalid size: {size}"))?;
+                let parse = |val: &str| match val.trim().parse::<u32>() {
+                    Ok(0) => Err(format!("Invalid size {size}: sides must be at least 1")),
+                    Ok(val) => Ok(val),
+                    Err(e) => Err(format!("Invalid size {size}: {e}")),
+                };
+                self.render_options.width = parse(width)?;
+                self.render_options.height = parse(height)?;
+            }
+            "--tone-map" => {
+                let map = args.next().ok_or("--tone-map expects a tone map")?;
+                self.render_options.tone_map = map.parse()?;
+            }
+            "--depth" => {
+                self.render_options.depth = match number::<u8>(args, arg)? {
+                    8 => Depth::Eight,
+                    16 => Depth::Sixteen,
+                    bits => return Err(format!("--depth must be 8 or 16, not {bits}")),
+                };
+            }
+            "--threads" => self.render_options.threads = count(args, arg)?,
+            "--samples" => {
+                let n = count(args, arg)?;
+                self.render_options.sampling = match self.render_options.sampling {
+                    Sampling::Jittered { seed, .. } => Sampling::Jittered { n, seed },
+                    _ => Sampling::Grid { n },
+                };
+            }
+            "--jitter" => {
+                let seed = number(args, arg)?;
+                let n = match self.render_options.sampling {
+                    Sampling::Single => 1,
+                    Sampling::Grid { n } | Sampling::Jittered { n, .. } => n,
+                };
+                self.render_options.sampling = Sampling::Jittered { n, seed };
+            }
+            "--adaptive" => {
+                let threshold: f32 = number(args, arg)?;
+                if !(0.0..=1.0).contains(&threshold) {
+                    return Err(format!("--adaptive must be from 0 to 1, not {threshold}"));
+                }
+                self.render_options.adaptive = Some(threshold);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+// The number following `arg`
+fn number<T: FromStr>(args: &mut impl Iterator<Item = String>, arg: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    args.next()
+        .ok_or(format!("{arg} expects a number"))?
+        .parse::<T>()
+        .map_err(|e| format!("Invalid value for {arg}: {e}"))
+}
+
+// The number following `arg`, which has to be at least 1
+fn count<T: FromStr + Default + PartialEq>(
+    args: &mut impl Iterator<Item = String>,
+    arg: &str,
+) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    let count = number(args, arg)?;
+    if count == T::default() {
+        return Err(format!("{arg} must be at least 1"));
+    }
+    Ok(count)
+}
+
+pub fn load_grammar(path: &str) -> Result<Grammar, String> {
+    let inp = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse_grammar(&inp)
+}
+
+fn parse_grammar(inp: &str) -> Result<Grammar, String> {
+    Parser::new(inp)
+        .parse()
+        .map_err(|e| format!("Error parsing BNF: {e:?}"))
+}
+
+/// Pairs every grammar param with its value, taking overrides over the declared defaults
+pub fn resolve_params(
+    grammar: &Grammar,
+    overrides: &[(String, f32)],
+) -> Result<Vec<(String, f32)>, String> {
+    if let Some((name, _)) = overrides.iter().find(|(n, _)| grammar.param(n).is_none()) {
+        return Err(format!("Unknown param: {name}"));
+    }
+    Ok(grammar
+        .params
+        .iter()
+        .map(|param| {
+            let value = overrides
+                .iter()
+                .rev()
+                .find(|(name, _)| *name == param.name)
+                .map_or(param.default, |(_, value)| *value);
+            (param.name.clone(), value)
+        })
+        .collect())
+}
+
+// Prints "\rRendered done/total unit" as a render goes
+fn report(unit: &'static str) -> impl FnMut(u32, u32) {
+    move |done, total| {
+        eprint!("\rRendered {done}/{total} {unit}");
+        if done == total {
+            eprintln!();
+        }
+    }
+}
+
+// What a command makes of the function it draws, deciding what the function is validated as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// The CPU renderer, which takes any function
+    Render,
+    /// A shader for --export, made from the WGSL one
+    Export,
+    /// The GLSL viewer
+    Viewer,
+}
+
+// The GLSL template the viewer fills for a recipe with `template`
+fn viewer_template(template: &TemplateChoice) -> &str {
+    match template {
+        TemplateChoice::Glsl(source) => source.as_str(),
+        TemplateChoice::Stock | TemplateChoice::Wgsl(_) => native::FRAGMENT_SHADER_TEMPLATE,
+    }
+}
+
+// What the flags draw: the recipe --from or --recipe loaded, or a function generated anew from
+// the grammar, validated as the shader `target` makes of it
+fn recipe(args: &Args, target: Target) -> Result<(Recipe, Origins), String> {
+    let source = match &args.from {
+        Some(from) => from.grammar.clone(),
+        None => std::fs::read_to_string(&args.grammar_path).map_err(|e| e.to_string())?,
+    };
+    let grammar = parse_grammar(&source)?;
+    let entry = match &args.from {
+        Some(from) => from.entry.clone(),
+        None => grammar
+            .symbols
+            .first()
+            .cloned()
+            .ok_or("The grammar has no rules")?,
+    };
+    let rule = grammar
+        .symbols
+        .iter()
+        .position(|symbol| *symbol == entry)
+        .ok_or(format!("No rule {entry} in the grammar"))?;
+    let template = match &args.template {
+        Some(path) => {
+            TemplateChoice::Glsl(std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?)
+        }
+        None => args
+            .from
+            .as_ref()
+            .map(|from| from.template.clone())
+            .unwrap_or_default(),
+    };
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let depth = args.from.as_ref().map_or(GEN_DEPTH, |from| from.depth);
+    let traced = |seed| {
+        let (func, origins) = grammar
+            .gen_traced(rule, depth, seed)
+            .ok_or("Failed to generate function")?;
+        Ok::<_, String>((func, origins, seed))
+    };
+    let validated = |backend: &dyn ShaderBackend,
+                     shader: &dyn Fn(&mut FnNode) -> Result<String, String>| {
+        let validated = grammar.gen_validated(rule, depth, seed, backend, shader)?;
+        for e in &validated.rejected {
+            eprintln!("Regenerating: {e}");
+        }
+        Ok::<_, String>((validated.func, validated.origins, validated.seed))
+    };
+    let (func, origins, seed) = match (&args.from, target) {
+        (Some(from), _) if from.seed == seed => {
+            let (_, origins, _) = traced(seed)?;
+            (from.func.clone(), origins, seed)
+        }
+        (_, Target::Render) => traced(seed)?,
+        (_, Target::Export) => {
+            let template = Template::parse(export::FRAGMENT_SHADER_TEMPLATE)?;
+            validated(&Wgsl, &|func| {
+                export::wgsl_fragment_shader(
+                    func,
+                    &grammar.params,
+                    &template,
+                    Layout::Compact,
+                    args.limits,
+                    TimeMap::default(),
+                )
+            })?
+        }
+        (_, Target::Viewer) => {
+            let template = Template::parse(viewer_template(&template))?;
+            validated(&Glsl450, &|func| {
+                native::fragment_shader(
+                    func,
+                    &grammar.params,
+                    &template,
+                    Layout::Compact,
+                    args.limits,
+                    TimeMap::default(),
+                )
+            })?
+        }
+    };
+    let recipe = Recipe {
+        params: resolve_params(&grammar, &args.params)?,
+        grammar: source,
+        entry,
+        seed,
+        depth,
+        func,
+        normalize: args.normalize,
+        t_range: args.t_range,
+        loop_period: args.loop_period,
+        color_map: args.render_options.color_map,
+        template,
+        width: args.render_options.width,
+        height: args.render_options.height,
+        time: args.render_options.t,
+    };
+    if let Some(path) = &args.save_recipe {
+        std::fs::write(path, recipe.to_string()).map_err(|e| format!("{path}: {e}"))?;
+        eprintln!("Saved recipe to {path}");
+    }
+    Ok((recipe, origins))
+}
+
+fn render_still(metadata: &Metadata, path: &str, program: &Program) -> Result<(), String> {
+    let path = Path::new(path);
+    let format = ImageFormat::from_path(path)?;
+    let cancel = AtomicBool::new(false);
+    let options = &metadata.options;
+    let text = metadata.text();
+    options.render_file(
+        program,
+        &[options.t],
+        path,
+        format,
+        &text,
+        &cancel,
+        report("rows"),
+    )
+}
+
+fn animate(args: &Args, program: &Program) -> Result<(), String> {
+    let animation = args.animation();
+    let options = &args.render_options;
+    let cancel = AtomicBool::new(false);
+    if let Some(pattern) = &args.sequence {
+        animation.save_frames(options, program, pattern, &cancel, report("frames"))
+    } else if let Some(format) = args.pipe {
+        let stdout = BufWriter::new(std::io::stdout().lock());
+        animation.write(options, program, format, stdout, &cancel, report("frames"))
+    } else {
+        let path = Path::new(args.animate.as_deref().unwrap_or_default());
+        let format = AnimationFormat::from_path(path)?;
+        animation.save(options, program, format, path, &cancel, report("frames"))
+    }
+}
+
+// The function `recipe` draws, warning of the channels normalizing left flat
+fn normalized(recipe: &Recipe) -> Result<FnNode, String> {
+    let (func, constant) = recipe.normalized()?;
+    for (channel, val) in constant {
+        eprintln!("Warning: channel {channel} is constant: {val}");
+    }
+    Ok(func)
+}
+
+pub fn run() -> Result<(), String> {
+    let Some(args) = Args::parse(std::env::args().skip(1))? else {
+        return Ok(());
+    };
+
+    if args.render || args.animates() {
+        let (recipe, _) = recipe(&args, Target::Render)?;
+        let func = normalized(&recipe)?;
+        eprintln!("Seed: {}", recipe.seed);
+        eprintln!("Function: {func}");
+        let program = func.compile_program()?;
+        if cfg!(feature = "jit") {
+            if let Err(e) = Evaluator::native(&program) {
+                eprintln!("JIT compilation failed, interpreting instead: {e}");
+            }
+        }
+        return if args.animates() {
+            animate(&args, &program)
+        } else {
+            let metadata = Metadata {
+                recipe,
+                options: args.render_options,
+            };
+            render_still(&metadata, &args.output, &program)
+        };
+    }
+
+    if let Some(path) = &args.export {
+        let format = ExportFormat::from_path(path)?;
+        let (recipe, _) = recipe(&args, Target::Export)?;
+        let params = recipe.load_grammar()?.params;
+        let func = normalized(&recipe)?;
+        let shader = export::export(&func, &params, format, args.limits, recipe.time_map())?;
+        std::fs::write(path, shader).map_err(|e| e.to_string())?;
+        println!("Exported {format} to {path}");
+        return Ok(());
+    }
+
+    let (recipe, origins) = recipe(&args, Target::Viewer)?;
+    if let TemplateChoice::Wgsl(_) = recipe.template {
+        eprintln!("Warning: the recipe's WGSL template is for the web app, using the stock one");
+    }
+    let template = Template::parse(viewer_template(&recipe.template))?;
+    native::glfw_main(&recipe, &origins, &template, args.limits)
+}
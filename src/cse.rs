@@ -0,0 +1,200 @@
+// Common-subexpression elimination: the tree is hash-consed into a DAG and every non-trivial
+// subtree with more than one use is hoisted into a let-bound temporary, referenced by `FnNode::Var`.
+use std::collections::HashMap;
+
+use crate::node::{ArithmeticOp, Color, CompareOp, FnNode, UnaryOp};
+
+#[derive(Debug, Clone)]
+pub struct Let {
+    pub expr: FnNode,
+    pub is_bool: bool,
+}
+
+/// An expression split into temporaries and the body that uses them.
+/// `lets[i]` is bound to `Var(i)` and only references earlier temporaries.
+#[derive(Debug, Clone)]
+pub struct Shared {
+    pub lets: Vec<Let>,
+    pub body: FnNode,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key<'a> {
+    Leaf(&'static str, u32),
+    Param(&'a str, u32),
+    Arithmetic(usize, ArithmeticOp, usize),
+    Compare(usize, CompareOp, usize),
+    Unary(UnaryOp, usize),
+    If(usize, usize, usize),
+    Triple(usize, usize, usize),
+}
+
+impl Key<'_> {
+    fn children(&self) -> Vec<usize> {
+        match self {
+            Key::Leaf(_, _) | Key::Param(_, _) => Vec::new(),
+            Key::Arithmetic(a, _, b) | Key::Compare(a, _, b) => vec![*a, *b],
+            Key::Unary(_, a) => vec![*a],
+            Key::If(a, b, c) | Key::Triple(a, b, c) => vec![*a, *b, *c],
+        }
+    }
+
+    // Leaves are cheaper to repeat than to bind, and a Triple is only ever the root
+    fn is_shareable(&self) -> bool {
+        !matches!(
+            self,
+            Key::Leaf(_, _) | Key::Param(_, _) | Key::Triple(_, _, _)
+        )
+    }
+}
+
+#[derive(Default)]
+struct Dag<'a> {
+    ids: HashMap<Key<'a>, usize>,
+    // In insertion order, so children always come before their parents
+    nodes: Vec<(Key<'a>, &'a FnNode)>,
+}
+
+impl<'a> Dag<'a> {
+    fn insert(&mut self, node: &'a FnNode) -> Result<usize, String> {
+        let key = match node {
+            FnNode::X => Key::Leaf("x", 0),
+            FnNode::Y => Key::Leaf("y", 0),
+            FnNode::T => Key::Leaf("t", 0),
+            FnNode::R => Key::Leaf("r", 0),
+            FnNode::Theta => Key::Leaf("theta", 0),
+            FnNode::Boolean(val) => Key::Leaf("bool", u32::from(*val)),
+            FnNode::Number(val) => Key::Leaf("number", val.to_bits()),
+            FnNode::Param(name, val) => Key::Param(name, val.to_bits()),
+            FnNode::Var(idx) => Key::Leaf("var", u32::try_from(*idx).map_err(|e| e.to_string())?),
+            FnNode::Random | FnNode::Rule(_, _) => {
+                return Err("Rule node encountered during CSE".to_string());
+            }
+            FnNode::Call(_, _) => {
+                return Err("Helper call encountered during CSE".to_string());
+            }
+            FnNode::Arithmetic(a, op, b) => Key::Arithmetic(self.insert(a)?, *op, self.insert(b)?),
+            FnNode::Compare(a, op, b) => Key::Compare(self.insert(a)?, *op, self.insert(b)?),
+            FnNode::Unary(op, a) => Key::Unary(op.clone(), self.insert(a)?),
+            FnNode::If(a, b, c) => Key::If(self.insert(a)?, self.insert(b)?, self.insert(c)?),
+            FnNode::Triple(a, b, c) => {
+                Key::Triple(self.insert(a)?, self.insert(b)?, self.insert(c)?)
+            }
+        };
+        if let Some(id) = self.ids.get(&key) {
+            return Ok(*id);
+        }
+        let id = self.nodes.len();
+        self.ids.insert(key.clone(), id);
+        self.nodes.push((key, node));
+        Ok(id)
+    }
+}
+
+impl FnNode {
+    pub fn share(&self) -> Result<Shared, String> {
+        let mut dag = Dag::default();
+        let root = dag.insert(self)?;
+
+        let mut uses = vec![0_usize; dag.nodes.len()];
+        for (key, _) in &dag.nodes {
+            for child in key.children() {
+                if let Some(count) = uses.get_mut(child) {
+                    *count = count.saturating_add(1);
+                }
+            }
+        }
+
+        let mut lets: Vec<Let> = Vec::new();
+        // Per DAG node: the expression to use at each reference, and whether it is boolean
+        let mut exprs: Vec<(FnNode, bool)> = Vec::with_capacity(dag.nodes.len());
+        for (id, (key, node)) in dag.nodes.iter().enumerate() {
+            let child = |idx: usize| -> Result<(FnNode, bool), String> {
+                exprs
+                    .get(idx)
+                    .cloned()
+                    .ok_or_else(|| "DAG child out of order".to_string())
+            };
+            let (expr, is_bool) = match key {
+                Key::Leaf(_, _) | Key::Param(_, _) => {
+                    ((*node).clone(), matches!(node, FnNode::Boolean(_)))
+                }
+                Key::Arithmetic(a, op, b) => {
+                    (FnNode::arithmetic(child(*a)?.0, *op, child(*b)?.0), false)
+                }
+                Key::Compare(a, op, b) => (FnNode::compare(child(*a)?.0, *op, child(*b)?.0), true),
+                Key::Unary(op, a) => (FnNode::unary(op.clone(), child(*a)?.0), false),
+                Key::If(a, b, c) => {
+                    let (then_branch, is_bool) = child(*b)?;
+                    (
+                        FnNode::if_(child(*a)?.0, then_branch, child(*c)?.0),
+                        is_bool,
+                    )
+                }
+                Key::Triple(a, b, c) => (
+                    FnNode::triple(child(*a)?.0, child(*b)?.0, child(*c)?.0),
+                    false,
+                ),
+            };
+
+            if key.is_shareable() && uses.get(id).is_some_and(|count| *count > 1) {
+                exprs.push((FnNode::Var(lets.len()), is_bool));
+                lets.push(Let { expr, is_bool });
+            } else {
+                exprs.push((expr, is_bool));
+            }
+        }
+
+        let body = exprs
+            .into_iter()
+            .nth(root)
+            .map(|(expr, _)| expr)
+            .ok_or("DAG root missing")?;
+        Ok(Shared { lets, body })
+    }
+}
+
+impl Shared {
+    pub fn eval_fn(&self, x: f32, y: f32, t: f32) -> Result<Color, String> {
+        let mut vars = Vec::with_capacity(self.lets.len());
+        for binding in &self.lets {
+            let value = binding.expr.eval(x, y, t, &vars)?;
+            vars.push(value);
+        }
+        Color::try_from(self.body.eval(x, y, t, &vars)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_share_repeated_subtrees() {
+        let dist = FnNode::unary(
+            UnaryOp::Sqrt,
+            FnNode::arithmetic(
+                FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::X),
+                ArithmeticOp::Add,
+                FnNode::arithmetic(FnNode::Y, ArithmeticOp::Mul, FnNode::Y),
+            ),
+        );
+        let node = FnNode::triple(
+            dist.clone(),
+            FnNode::arithmetic(dist.clone(), ArithmeticOp::Mul, FnNode::T),
+            FnNode::unary(UnaryOp::Sin, dist),
+        );
+
+        let shared = node.share().expect("CSE should succeed");
+        assert_eq!(shared.lets.len(), 1, "Only the distance should be bound");
+        assert_eq!(shared.body.to_string(), "(v0, mul(v0, t), sin(v0))");
+
+        for (x, y, t) in [(0.0, 0.0, 0.0), (0.3, -0.7, 1.5), (-1.0, 1.0, -2.0)] {
+            assert_eq!(
+                shared.eval_fn(x, y, t).ok(),
+                node.eval_fn(x, y, t).ok(),
+                "Shared evaluation should match the tree at ({x}, {y}, {t})"
+            );
+        }
+    }
+}
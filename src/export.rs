@@ -0,0 +1,392 @@
+// Shader export. The WGSL module the browser renders is the source of truth; naga translates the
+// validated module into the other targets so every export matches what was on screen. Shadertoy is
+// the exception, its `mainImage` entry point is generated straight from the expression, as are the
+// Rust, C and JavaScript functions from `source` for rendering without a GPU.
+use std::fmt::Display;
+use std::str::FromStr;
+
+use naga::valid::{Capabilities, ModuleInfo, ValidationFlags, Validator};
+
+use crate::backend::{GlslEs300, ShaderBackend, TimeMap, Wgsl};
+use crate::grammar::{Origins, Param};
+use crate::hoist::Limits;
+use crate::node::FnNode;
+use crate::pretty::{self, Layout};
+use crate::source::SourceLanguage;
+use crate::template::Template;
+
+/// WGSL Fragment shader template (converted from GLSL), the browser renderer's default
+pub const FRAGMENT_SHADER_TEMPLATE: &str = r"
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+struct Uniforms {
+    time: f32,
+    // Scalar padding: a vec3 here would be 16-byte aligned and no longer match the Rust layout
+    _padding0: f32,
+    _padding1: f32,
+    _padding2: f32,
+    params: array<vec4<f32>, 4>,
+};
+
+@group(0) @binding(0)
+var<uniform> uniforms: Uniforms;
+
+fn map_rgb(rgb: vec3<f32>) -> vec4<f32> {
+    return {{color_map}};
+}
+
+fn time_map(seconds: f32) -> f32 {
+    return {{time_map}};
+}
+{{helpers}}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let x = in.tex_coords.x;
+    let y = in.tex_coords.y;
+    let t = time_map(uniforms.time);
+    let r = length(vec2<f32>(x, y));
+    let theta = atan2(y, x);
+{{uniforms}}
+{{lets}}
+    return map_rgb({{expr}});
+}
+";
+
+// Shadertoy provides `iTime` and `iResolution`; params have no uniforms there and become constants
+const SHADERTOY_TEMPLATE: &str = r"// Paste into the Image tab of a new Shadertoy
+{{uniforms}}
+vec4 applyColorTransform(vec3 rgb) {
+    return {{color_map}};
+}
+
+float timeMap(float seconds) {
+    return {{time_map}};
+}
+{{helpers}}
+
+void mainImage(out vec4 fragColor, in vec2 fragCoord)
+{
+    // x and y each span [-1, 1] across the canvas, as in the viewers
+    vec2 uv = 2.0 * fragCoord / iResolution.xy - 1.0;
+    float x = uv.x;
+    float y = uv.y;
+    float t = timeMap(iTime);
+    float r = length(uv);
+    float theta = atan(y, x);
+{{lets}}
+    fragColor = applyColorTransform({{expr}});
+}
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Wgsl,
+    Hlsl,
+    Msl,
+    SpirV,
+    Shadertoy,
+    Rust,
+    C,
+    JavaScript,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 8] = [
+        ExportFormat::Wgsl,
+        ExportFormat::Hlsl,
+        ExportFormat::Msl,
+        ExportFormat::SpirV,
+        ExportFormat::Shadertoy,
+        ExportFormat::Rust,
+        ExportFormat::C,
+        ExportFormat::JavaScript,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Wgsl => "wgsl",
+            ExportFormat::Hlsl => "hlsl",
+            ExportFormat::Msl => "metal",
+            ExportFormat::SpirV => "spv",
+            ExportFormat::Shadertoy => "shadertoy",
+            ExportFormat::Rust => "rs",
+            ExportFormat::C => "c",
+            ExportFormat::JavaScript => "js",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ExportFormat::SpirV => "application/octet-stream",
+            ExportFormat::JavaScript => "text/javascript",
+            _ => "text/plain",
+        }
+    }
+
+    /// Picks the format from a file name's extension
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(format!(
+                "No file extension to pick an export format from: {path}"
+            ))?;
+        extension.parse()
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "wgsl" => Ok(ExportFormat::Wgsl),
+            "hlsl" => Ok(ExportFormat::Hlsl),
+            "metal" | "msl" => Ok(ExportFormat::Msl),
+            "spv" | "spirv" => Ok(ExportFormat::SpirV),
+            "shadertoy" => Ok(ExportFormat::Shadertoy),
+            "rs" | "rust" => Ok(ExportFormat::Rust),
+            "c" => Ok(ExportFormat::C),
+            "js" | "javascript" => Ok(ExportFormat::JavaScript),
+            _ => Err(format!("Unknown export format: {s}")),
+        }
+    }
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExportFormat::Wgsl => "WGSL",
+            ExportFormat::Hlsl => "HLSL",
+            ExportFormat::Msl => "Metal",
+            ExportFormat::SpirV => "SPIR-V",
+            ExportFormat::Shadertoy => "Shadertoy",
+            ExportFormat::Rust => "Rust",
+            ExportFormat::C => "C",
+            ExportFormat::JavaScript => "JavaScript",
+        })
+    }
+}
+
+// One `let <name> = uniforms.params[i].<lane>;` per grammar param, for `{{uniforms}}`
+fn param_lets(params: &[Param]) -> String {
+    let mut lets = Vec::with_capacity(params.len());
+    for (slot, chunk) in params.chunks(4).enumerate() {
+        for (param, lane) in chunk.iter().zip(["x", "y", "z", "w"]) {
+            lets.push(format!(
+                "    let {} = uniforms.params[{slot}].{lane};",
+                param.name
+            ));
+        }
+    }
+    lets.join("\n")
+}
+
+/// The WGSL fragment shader the browser renderer draws `func` with. `template` has to declare the
+/// `Uniforms` buffer the renderer binds, as the default does.
+pub fn wgsl_fragment_shader(
+    func: &mut FnNode,
+    params: &[Param],
+    template: &Template,
+    layout: Layout,
+    limits: Limits,
+    time_map: TimeMap,
+) -> Result<String, String> {
+    func.compile_fs(
+        template,
+        &param_lets(params),
+        &Wgsl,
+        layout,
+        limits,
+        time_map,
+    )
+}
+
+/// A complete Shadertoy program for `func`, with every param fixed at its bound value
+pub fn shadertoy_program(
+    func: &mut FnNode,
+    limits: Limits,
+    time_map: TimeMap,
+) -> Result<String, String> {
+    let consts = func
+        .bound_params()
+        .iter()
+        .map(|(name, value)| format!("const float {name} = {};", GlslEs300.number(*value)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    // Pasted and read by people, so laid out for reading
+    let layout = Layout::Pretty {
+        width: pretty::DEFAULT_WIDTH,
+        origins: &Origins::default(),
+    };
+    func.compile_fs(
+        &Template::parse(SHADERTOY_TEMPLATE)?,
+        &consts,
+        &GlslEs300,
+        layout,
+        limits,
+        time_map,
+    )
+}
+
+/// `func` as a standalone shader in `format`, wrapped in the default templates. `limits` and
+/// `time_map` only apply to the shaders, the Rust, C and JavaScript functions are never split and
+/// take t itself.
+pub fn export(
+    func: &FnNode,
+    params: &[Param],
+    format: ExportFormat,
+    limits: Limits,
+    time_map: TimeMap,
+) -> Result<Vec<u8>, String> {
+    let mut func = func.clone();
+    match format {
+        ExportFormat::Shadertoy => Ok(shadertoy_program(&mut func, limits, time_map)?.into_bytes()),
+        ExportFormat::Rust => Ok(func.compile_source(SourceLanguage::Rust)?.into_bytes()),
+        ExportFormat::C => Ok(func.compile_source(SourceLanguage::C)?.into_bytes()),
+        ExportFormat::JavaScript => Ok(func
+            .compile_source(SourceLanguage::JavaScript)?
+            .into_bytes()),
+        ExportFormat::Wgsl => {
+            let (wgsl, _, _) = stock_module(&mut func, params, limits, time_map)?;
+            Ok(wgsl.into_bytes())
+        }
+        ExportFormat::Hlsl => {
+            let (_, module, info) = stock_module(&mut func, params, limits, time_map)?;
+            let options = naga::back::hlsl::Options::default();
+            let pipeline_options = naga::back::hlsl::PipelineOptions::default();
+            let mut out = String::new();
+            naga::back::hlsl::Writer::new(&mut out, &options, &pipeline_options)
+                .write(&module, &info, None)
+                .map_err(|e| e.to_string())?;
+            Ok(out.into_bytes())
+        }
+        ExportFormat::Msl => {
+            let (_, module, info) = stock_module(&mut func, params, limits, time_map)?;
+            let (out, _) = naga::back::msl::write_string(
+                &module,
+                &info,
+                &naga::back::msl::Options::default(),
+                &naga::back::msl::PipelineOptions::default(),
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(out.into_bytes())
+        }
+        // SPIR-V comes back as little-endian words
+        ExportFormat::SpirV => {
+            let (_, module, info) = stock_module(&mut func, params, limits, time_map)?;
+            let words = naga::back::spv::write_vec(
+                &module,
+                &info,
+                &naga::back::spv::Options::default(),
+                None,
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+        }
+    }
+}
+
+// The WGSL of the default template for `func` and the module naga parses it into, parsed even
+// for WGSL itself so nothing invalid is ever written out
+fn stock_module(
+    func: &mut FnNode,
+    params: &[Param],
+    limits: Limits,
+    time_map: TimeMap,
+) -> Result<(String, naga::Module, ModuleInfo), String> {
+    let template = Template::parse(FRAGMENT_SHADER_TEMPLATE)?;
+    let wgsl = wgsl_fragment_shader(func, params, &template, Layout::Compact, limits, time_map)?;
+    let module = naga::front::wgsl::parse_str(&wgsl).map_err(|e| e.emit_to_string(&wgsl))?;
+    let info = Validator::new(ValidationFlags::all(), Capabilities::empty())
+        .validate(&module)
+        .map_err(|e| e.emit_to_string(&wgsl))?;
+    Ok((wgsl, module, info))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use crate::interval::Interval;
+
+    fn generate() -> (FnNode, Vec<Param>) {
+        let grammar = Parser::new(
+            "param speed = 0.5 in [0, 2];\nE | vec3(C, C, speed) ;\nC | x | y | t | r | sin(C) | mul(C, C) ;",
+        )
+        .parse()
+        .expect("Parse should be successful");
+        let func = grammar
+            .gen_seeded(0, 6, 3)
+            .expect("Generation should succeed");
+        (func, grammar.params)
+    }
+
+    #[test]
+    fn test_export_all_formats() {
+        let (func, params) = generate();
+        let looping = TimeMap::Loop {
+            period: 4.0,
+            range: Interval::new(-1.0, 3.0),
+        };
+        for format in ExportFormat::ALL {
+            for time_map in [TimeMap::Tan, looping] {
+                let out = export(&func, &params, format, Limits::default(), time_map)
+                    .unwrap_or_else(|e| panic!("{format}: {e}"));
+                assert!(!out.is_empty());
+            }
+            assert_eq!(format.extension().parse(), Ok(format));
+        }
+        let wgsl = export(
+            &func,
+            &params,
+            ExportFormat::Wgsl,
+            Limits::default(),
+            looping,
+        )
+        .expect("WGSL should compile");
+        let wgsl = String::from_utf8(wgsl).expect("WGSL is text");
+        assert!(wgsl.contains("return (1.0) - (2.0) * cos(seconds * (1.5707964));"));
+        let spirv = export(
+            &func,
+            &params,
+            ExportFormat::SpirV,
+            Limits::default(),
+            TimeMap::default(),
+        )
+        .expect("SPIR-V should translate");
+        assert_eq!(spirv.get(..4), Some(&0x0723_0203_u32.to_le_bytes()[..]));
+    }
+
+    #[test]
+    fn test_shadertoy_program() {
+        let (mut func, _) = generate();
+        func.bind_param("speed", 1.5);
+        let looping = TimeMap::Loop {
+            period: 10.0,
+            range: Interval::new(-1.0, 1.0),
+        };
+        let program = shadertoy_program(&mut func, Limits::default(), looping)
+            .expect("Shadertoy export should compile");
+        assert!(program.contains("const float speed = (1.5);"));
+        assert!(program.contains("vec2 uv = 2.0 * fragCoord / iResolution.xy - 1.0;"));
+
+        // Stand in for the uniforms and entry point Shadertoy wraps the program in
+        let source = format!(
+            "#version 450\nlayout(location = 0) out vec4 color;\nconst vec3 iResolution = vec3(800.0, 450.0, 1.0);\nconst float iTime = 1.0;\n{program}\nvoid main() {{\n    mainImage(color, gl_FragCoord.xy);\n}}\n"
+        );
+        let module = naga::front::glsl::Frontend::default()
+            .parse(
+                &naga::front::glsl::Options::from(naga::ShaderStage::Fragment),
+                &source,
+            )
+            .unwrap_or_else(|e| panic!("{e}\n{source}"));
+        Validator::new(ValidationFlags::all(), Capabilities::empty())
+            .validate(&module)
+            .unwrap_or_else(|e| panic!("{e}\n{source}"));
+    }
+}
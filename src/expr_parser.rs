@@ -0,0 +1,193 @@
+// Reads functions back from the form `FnNode` prints them in, e.g.
+// `(add(x, 0.5), sin(mul(t, speed)), (x gt y))`, so one stored as text can be rendered again.
+// Printed numbers are the shortest that parse back to the same f32, so the trip is exact.
+use std::vec::IntoIter;
+
+use logos::Logos;
+
+use crate::bnf_lexer::TokenKind;
+use crate::grammar::Param;
+use crate::node::{ArithmeticOp, CompareOp, FnNode, UnaryOp};
+
+/// Parses `src` as `FnNode` prints it, reading names that aren't built in as one of `params` at
+/// its default
+pub fn parse(src: &str, params: &[Param]) -> Result<FnNode, String> {
+    let mut lexer = TokenKind::lexer(src);
+    let mut tokens = Vec::new();
+    while let Some(token) = lexer.next() {
+        let token = token.map_err(|()| format!("Unexpected {:?} in expression", lexer.slice()))?;
+        tokens.push((token, lexer.slice()));
+    }
+    let mut parser = ExprParser {
+        tokens: tokens.into_iter(),
+        params,
+    };
+    let node = parser.expr()?;
+    match parser.tokens.next() {
+        None => Ok(node),
+        Some((_, slice)) => Err(format!("Unexpected {slice:?} after expression")),
+    }
+}
+
+struct ExprParser<'a, 'p> {
+    tokens: IntoIter<(TokenKind, &'a str)>,
+    params: &'p [Param],
+}
+
+impl<'a> ExprParser<'a, '_> {
+    fn next(&mut self) -> Result<(TokenKind, &'a str), String> {
+        self.tokens
+            .next()
+            .ok_or_else(|| "Unexpected end of expression".to_string())
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> Result<(), String> {
+        match self.next()? {
+            (token, _) if token == *kind => Ok(()),
+            (_, slice) => Err(format!("Expected {kind:?} in expression, found {slice:?}")),
+        }
+    }
+
+    fn expr(&mut self) -> Result<FnNode, String> {
+        match self.next()? {
+            (TokenKind::Number, slice) => slice
+                .parse()
+                .map(FnNode::Number)
+                .map_err(|e| format!("Invalid number {slice}: {e}")),
+            (TokenKind::LParen, _) => self.group(),
+            (TokenKind::Identifier, name) => self.named(name),
+            (_, slice) => Err(format!("Unexpected {slice:?} in expression")),
+        }
+    }
+
+    // `(r, g, b)` or `(a gt b)` and the other comparisons, after the `(`
+    fn group(&mut self) -> Result<FnNode, String> {
+        let first = self.expr()?;
+        let node = match self.next()? {
+            (TokenKind::Comma, _) => {
+                let second = self.expr()?;
+                self.expect(&TokenKind::Comma)?;
+                FnNode::triple(first, second, self.expr()?)
+            }
+            (TokenKind::Identifier, op) => {
+                let op = match op {
+                    "gt" => CompareOp::GreaterThan,
+                    "lt" => CompareOp::LessThan,
+                    "gte" => CompareOp::GreaterThanEqual,
+                    "lte" => CompareOp::LessThanEqual,
+                    "eq" => CompareOp::Equal,
+                    "neq" => CompareOp::NotEqual,
+                    _ => return Err(format!("Unknown comparison: {op}")),
+                };
+                FnNode::compare(first, op, self.expr()?)
+            }
+            (_, slice) => return Err(format!("Unexpected {slice:?} in expression")),
+        };
+        self.expect(&TokenKind::RParen)?;
+        Ok(node)
+    }
+
+    fn named(&mut self, name: &str) -> Result<FnNode, String> {
+        // Params come first, being printed by name alone
+        if let Some(param) = self.params.iter().find(|p| p.name == name) {
+            return Ok(FnNode::Param(param.name.clone(), param.default));
+        }
+        let node = match name {
+            "x" => FnNode::X,
+            "y" => FnNode::Y,
+            "t" => FnNode::T,
+            "r" => FnNode::R,
+            "theta" => FnNode::Theta,
+            "random" => FnNode::Random,
+            "true" | "false" => FnNode::Boolean(name == "true"),
+            "inf" => FnNode::Number(f32::INFINITY),
+            "NaN" => FnNode::Number(f32::NAN),
+            "add" | "sub" | "mul" | "div" | "mod" => {
+                let op = match name {
+                    "add" => ArithmeticOp::Add,
+                    "sub" => ArithmeticOp::Sub,
+                    "mul" => ArithmeticOp::Mul,
+                    "div" => ArithmeticOp::Div,
+                    _ => ArithmeticOp::Mod,
+                };
+                let [a, b] = self.args()?;
+                FnNode::arithmetic(a, op, b)
+            }
+            "sqrt" | "abs" | "sin" | "cos" | "tan" => {
+                let op = match name {
+                    "sqrt" => UnaryOp::Sqrt,
+                    "abs" => UnaryOp::Abs,
+                    "sin" => UnaryOp::Sin,
+                    "cos" => UnaryOp::Cos,
+                    _ => UnaryOp::Tan,
+                };
+                let [a] = self.args()?;
+                FnNode::unary(op, a)
+            }
+            "if" => {
+                let [cond, then_branch, else_branch] = self.args()?;
+                FnNode::if_(cond, then_branch, else_branch)
+            }
+            _ => return Err(format!("Unknown name in expression: {name}")),
+        };
+        Ok(node)
+    }
+
+    // The `N` comma separated arguments of a call, in parentheses
+    fn args<const N: usize>(&mut self) -> Result<[FnNode; N], String> {
+        self.expect(&TokenKind::LParen)?;
+        let mut args = Vec::with_capacity(N);
+        for idx in 0..N {
+            if idx > 0 {
+                self.expect(&TokenKind::Comma)?;
+            }
+            args.push(self.expr()?);
+        }
+        self.expect(&TokenKind::RParen)?;
+        args.try_into()
+            .map_err(|_| format!("Expected {N} arguments in expression"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+
+    #[test]
+    fn test_parse_printed_functions() {
+        let grammar = Parser::new(&format!(
+            "param speed = 0.5 in [0, 2];\n{}",
+            include_str!("../grammar.bnf")
+        ))
+        .parse()
+        .expect("Parse should be successful");
+        for seed in 0..20 {
+            let node = grammar
+                .gen_seeded(0, 8, seed)
+                .expect("Generation should succeed");
+            let parsed = parse(&node.to_string(), &grammar.params).expect("Should parse back");
+            assert_eq!(parsed, node);
+            assert_eq!(grammar.gen_seeded(0, 8, seed), Some(node));
+        }
+
+        let speed = FnNode::Param("speed".to_string(), 0.5);
+        let node = FnNode::triple(
+            FnNode::if_(
+                FnNode::compare(FnNode::X, CompareOp::LessThanEqual, speed.clone()),
+                FnNode::Number(-0.000_123_4),
+                FnNode::arithmetic(
+                    FnNode::unary(UnaryOp::Tan, FnNode::Theta),
+                    ArithmeticOp::Add,
+                    FnNode::Number(f32::NEG_INFINITY),
+                ),
+            ),
+            FnNode::arithmetic(FnNode::T, ArithmeticOp::Mod, FnNode::Number(3.0)),
+            FnNode::compare(speed, CompareOp::NotEqual, FnNode::Boolean(true)),
+        );
+        assert_eq!(parse(&node.to_string(), &grammar.params), Ok(node));
+        assert!(parse("add(x, y", &grammar.params).is_err());
+        assert!(parse("(x, y)", &grammar.params).is_err());
+        assert!(parse("mul(x, depth)", &grammar.params).is_err());
+    }
+}
@@ -1,6 +1,12 @@
 use crate::node::FnNode;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
 use std::fmt::Display;
 
+/// Upper bound on `param` declarations, fixed by the uniform buffer layout of the shader templates
+pub const MAX_PARAMS: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct Branch {
     pub node: FnNode,
@@ -14,10 +20,27 @@ pub struct Rule {
     pub weight_sum: usize,
 }
 
+/// A named, user-tweakable value declared as `param speed = 0.5 in [0, 2];`
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub default: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Grammar {
     pub symbols: Vec<String>,
     pub map: Vec<(String, Rule)>,
+    pub params: Vec<Param>,
+}
+
+/// Which rule generated each subtree, so shader output can point back into the grammar. Subtrees
+/// are matched by their printed form, so the ones that survive optimization keep their rule.
+#[derive(Debug, Clone, Default)]
+pub struct Origins {
+    rules: HashMap<String, String>,
 }
 
 pub struct GrammarError {
@@ -31,6 +54,20 @@ pub struct RuleError {
 }
 
 // =============================================================================
+impl Origins {
+    /// The innermost rule that produced `node`
+    pub fn rule(&self, node: &FnNode) -> Option<&str> {
+        self.rules.get(&node.to_string()).map(String::as_str)
+    }
+
+    // Rules are recorded innermost first, so the more specific one is kept
+    fn record(&mut self, node: &FnNode, symbol: &str) {
+        self.rules
+            .entry(node.to_string())
+            .or_insert_with(|| symbol.to_string());
+    }
+}
+
 impl Branch {
     pub fn new(node: FnNode, weight: usize) -> Self {
         Branch { node, weight }
@@ -47,13 +84,43 @@ impl Rule {
     }
 }
 
+impl Param {
+    pub fn new(name: String, default: f32, min: f32, max: f32) -> Self {
+        Param {
+            name,
+            default,
+            min,
+            max,
+        }
+    }
+}
+
 #[allow(dead_code)]
 impl Grammar {
     pub fn new() -> Self {
         Grammar {
             symbols: vec![],
             map: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn add_param(&mut self, param: Param) -> Result<(), &'static str> {
+        if self.params.len() >= MAX_PARAMS {
+            return Err("Too many params");
+        }
+        if self.params.iter().any(|p| p.name == param.name) {
+            return Err("Duplicate param");
         }
+        if param.min > param.max || !(param.min..=param.max).contains(&param.default) {
+            return Err("Param default outside of its range");
+        }
+        self.params.push(param);
+        Ok(())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|p| p.name == name)
     }
 
     pub fn add_rule(&mut self, branches: Vec<Branch>, symbol: String) -> Result<(), &'static str> {
@@ -67,30 +134,63 @@ impl Grammar {
             weight_sum,
         };
         self.symbols.push(symbol.clone());
-        println!("Added rule: {:?}", &rule);
+        eprintln!("Added rule: {:?}", &rule);
         self.map.push((symbol, rule));
         Ok(())
     }
 
     pub fn gen_from_rule(&self, rule_idx: usize, depth: usize) -> Option<FnNode> {
+        self.gen_rule(rule_idx, depth, &mut rand::rng(), None)
+    }
+
+    /// Like `gen_from_rule`, always generating the same function for the same `seed`
+    pub fn gen_seeded(&self, rule_idx: usize, depth: usize, seed: u64) -> Option<FnNode> {
+        self.gen_rule(rule_idx, depth, &mut StdRng::seed_from_u64(seed), None)
+    }
+
+    /// Like `gen_seeded`, also recording the rule behind every subtree
+    pub fn gen_traced(
+        &self,
+        rule_idx: usize,
+        depth: usize,
+        seed: u64,
+    ) -> Option<(FnNode, Origins)> {
+        let mut origins = Origins::default();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let node = self.gen_rule(rule_idx, depth, &mut rng, Some(&mut origins))?;
+        Some((node, origins))
+    }
+
+    fn gen_rule(
+        &self,
+        rule_idx: usize,
+        depth: usize,
+        rng: &mut impl Rng,
+        mut origins: Option<&mut Origins>,
+    ) -> Option<FnNode> {
         if depth == 0 || rule_idx >= self.map.len() {
             return None;
         }
 
-        let rule = &self.map[rule_idx].1;
+        let (symbol, rule) = &self.map[rule_idx];
         let mut attempts: i32 = 100; // GEN_RULE_MAX_ATTEMPTS
 
         while attempts > 0 {
-            let p = rand::random::<f64>();
+            let p = rng.random::<f64>();
             let mut t = 0.0;
 
             for branch in &rule.branches {
                 t += branch.weight as f64 / rule.weight_sum as f64;
 
                 if t >= p {
-                    let node = self.gen_node(&branch.node, depth);
+                    let node = self.expand(&branch.node, depth, rng, origins.as_deref_mut());
                     match node {
-                        Some(node) => return Some(node),
+                        Some(node) => {
+                            if let Some(origins) = origins.as_deref_mut() {
+                                origins.record(&node, symbol);
+                            }
+                            return Some(node);
+                        }
                         None => break,
                     }
                 }
@@ -101,25 +201,42 @@ impl Grammar {
     }
 
     pub fn gen_node(&self, node: &FnNode, depth: usize) -> Option<FnNode> {
+        self.expand(node, depth, &mut rand::rng(), None)
+    }
+
+    fn expand(
+        &self,
+        node: &FnNode,
+        depth: usize,
+        rng: &mut impl Rng,
+        mut origins: Option<&mut Origins>,
+    ) -> Option<FnNode> {
         match node {
             // Terminal nodes
-            FnNode::X | FnNode::Y | FnNode::T | FnNode::Number(_) | FnNode::Boolean(_) => {
-                Some(node.clone())
-            }
+            FnNode::X
+            | FnNode::Y
+            | FnNode::T
+            | FnNode::R
+            | FnNode::Theta
+            | FnNode::Param(_, _)
+            | FnNode::Var(_)
+            | FnNode::Call(_, _)
+            | FnNode::Number(_)
+            | FnNode::Boolean(_) => Some(node.clone()),
 
             // Random number generation
-            FnNode::Random => Some(FnNode::Number(rand::random::<f32>() * 2.0 - 1.0)),
+            FnNode::Random => Some(FnNode::Number(rng.random::<f32>() * 2.0 - 1.0)),
 
             // Unary operations
             FnNode::Unary(op, expr) => {
-                let e = self.gen_node(expr, depth)?;
+                let e = self.expand(expr, depth, rng, origins)?;
                 Some(FnNode::Unary(op.clone(), Box::new(e)))
             }
 
             // Binary operations
             FnNode::Arithmetic(lhs, _, rhs) | FnNode::Compare(lhs, _, rhs) => {
-                let l = self.gen_node(lhs, depth)?;
-                let r = self.gen_node(rhs, depth)?;
+                let l = self.expand(lhs, depth, rng, origins.as_deref_mut())?;
+                let r = self.expand(rhs, depth, rng, origins)?;
                 Some(match node {
                     FnNode::Arithmetic(_, kind, _) => {
                         FnNode::Arithmetic(Box::new(l), *kind, Box::new(r))
@@ -131,9 +248,9 @@ impl Grammar {
 
             // Triple operation
             FnNode::Triple(first, second, third) | FnNode::If(first, second, third) => {
-                let f = self.gen_node(first, depth)?;
-                let s = self.gen_node(second, depth)?;
-                let t = self.gen_node(third, depth)?;
+                let f = self.expand(first, depth, rng, origins.as_deref_mut())?;
+                let s = self.expand(second, depth, rng, origins.as_deref_mut())?;
+                let t = self.expand(third, depth, rng, origins)?;
                 match node {
                     FnNode::Triple(_, _, _) => {
                         Some(FnNode::Triple(Box::new(f), Box::new(s), Box::new(t)))
@@ -144,7 +261,7 @@ impl Grammar {
             }
 
             // Rule reference
-            FnNode::Rule(rule_idx, _) => self.gen_from_rule(*rule_idx, depth - 1),
+            FnNode::Rule(rule_idx, _) => self.gen_rule(*rule_idx, depth - 1, rng, origins),
         }
     }
 }
@@ -169,7 +286,7 @@ impl Default for Grammar {
         *   | t
         *   | abs(x)
         *   | abs(y)
-        *   | sqrt(add(mult(x, x), mult(y, y))) # Distance from (0, 0) to (x, y)
+        *   | r # Distance from (0, 0) to (x, y)
         *   ;
 
         * # Expressions
@@ -211,17 +328,7 @@ impl Default for Grammar {
                 Branch::new(FnNode::X, 1),
                 Branch::new(FnNode::Y, 1),
                 Branch::new(FnNode::T, 1),
-                Branch::new(
-                    FnNode::unary(
-                        UnaryOp::Sqrt,
-                        FnNode::arithmetic(
-                            FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::X),
-                            ArithmeticOp::Add,
-                            FnNode::arithmetic(FnNode::Y, ArithmeticOp::Mul, FnNode::Y),
-                        ),
-                    ),
-                    2,
-                ),
+                Branch::new(FnNode::R, 2),
             ],
             "A".to_string(),
         );
@@ -282,6 +389,13 @@ impl Default for Grammar {
 //   ;
 impl Display for Grammar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for param in &self.params {
+            writeln!(
+                f,
+                "param {} = {} in [{}, {}];",
+                param.name, param.default, param.min, param.max
+            )?;
+        }
         for (symbol, rule) in &self.map {
             writeln!(f, "{symbol}")?;
             for branch in &rule.branches {
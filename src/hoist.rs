@@ -0,0 +1,344 @@
+// Keeps generated expressions small enough for shader compilers, several of which reject deeply
+// nested or very long expressions outright. Working bottom up after CSE, whenever a node would
+// exceed the limits its largest operands are moved into helper functions `h{idx}` and replaced by
+// `FnNode::Call`s passing in whatever the subtree reads.
+use crate::cse::Shared;
+use crate::node::FnNode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Most operations nested inside one another in any emitted expression
+    pub max_depth: usize,
+    /// Most operations in any emitted expression
+    pub max_nodes: usize,
+}
+
+impl Default for Limits {
+    // Well inside what ANGLE, FXC and naga accept
+    fn default() -> Self {
+        Limits {
+            max_depth: 32,
+            max_nodes: 256,
+        }
+    }
+}
+
+/// A hoisted subtree, emitted as the function `h{idx}` for its index
+#[derive(Debug, Clone)]
+pub struct Helper {
+    /// The leaves the body reads, in parameter order, each flagged if it is a bool
+    pub params: Vec<(FnNode, bool)>,
+    pub is_bool: bool,
+    pub body: FnNode,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Size {
+    depth: usize,
+    nodes: usize,
+}
+
+impl Size {
+    const LEAF: Size = Size { depth: 1, nodes: 1 };
+
+    fn exceeds(self, limits: Limits) -> bool {
+        self.depth > limits.max_depth || self.nodes > limits.max_nodes
+    }
+}
+
+/// What a function computing `node` has to be passed: the coordinates it reads in their usual
+/// order, then params and shared temporaries in order of use, each flagged if it is a bool
+pub(crate) fn arguments(node: &FnNode, shared: &Shared) -> Vec<(FnNode, bool)> {
+    let mut args: Vec<(FnNode, bool)> = Vec::new();
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        let is_bool = match node {
+            FnNode::X | FnNode::Y | FnNode::T | FnNode::R | FnNode::Theta | FnNode::Param(_, _) => {
+                Some(false)
+            }
+            FnNode::Var(idx) => Some(shared.lets.get(*idx).is_some_and(|binding| binding.is_bool)),
+            _ => None,
+        };
+        if let Some(is_bool) = is_bool {
+            if !args.iter().any(|(arg, _)| arg == node) {
+                args.push((node.clone(), is_bool));
+            }
+        }
+        stack.extend(node.children().into_iter().rev());
+    }
+    let coordinates = [FnNode::X, FnNode::Y, FnNode::T, FnNode::R, FnNode::Theta];
+    args.sort_by_key(|(arg, _)| {
+        coordinates
+            .iter()
+            .position(|coordinate| coordinate == arg)
+            .unwrap_or(coordinates.len())
+    });
+    args
+}
+
+struct Hoister<'a> {
+    shared: &'a Shared,
+    limits: Limits,
+    helpers: Vec<Helper>,
+}
+
+impl Hoister<'_> {
+    // Whether `node` is a bool, or `None` for a colour, which is never hoisted
+    fn is_bool(&self, node: &FnNode) -> Option<bool> {
+        match node {
+            FnNode::Triple(_, _, _) => None,
+            FnNode::Compare(_, _, _) | FnNode::Boolean(_) => Some(true),
+            FnNode::If(_, then, _) => self.is_bool(then),
+            FnNode::Var(idx) => Some(
+                self.shared
+                    .lets
+                    .get(*idx)
+                    .is_some_and(|binding| binding.is_bool),
+            ),
+            FnNode::Call(idx, _) => Some(self.helpers.get(*idx).is_some_and(|h| h.is_bool)),
+            _ => Some(false),
+        }
+    }
+
+    fn hoist(&mut self, node: FnNode) -> Result<(FnNode, Size), String> {
+        let is_bool = self
+            .is_bool(&node)
+            .ok_or("A colour can't be hoisted into a helper")?;
+        let params = arguments(&node, self.shared);
+        let call = FnNode::Call(
+            self.helpers.len(),
+            params.iter().map(|(arg, _)| arg.clone()).collect(),
+        );
+        let size = Size {
+            depth: if params.is_empty() { 1 } else { 2 },
+            nodes: params.len().saturating_add(1),
+        };
+        self.helpers.push(Helper {
+            params,
+            is_bool,
+            body: node,
+        });
+        Ok((call, size))
+    }
+
+    // `node` with its operands fitted, hoisting the largest of them while it is too big itself
+    fn fit(&mut self, node: &FnNode) -> Result<(FnNode, Size), String> {
+        let mut children = Vec::new();
+        for child in node.children() {
+            children.push(self.fit(child)?);
+        }
+        let size = |children: &[(FnNode, Size)]| Size {
+            depth: children
+                .iter()
+                .map(|(_, size)| size.depth)
+                .max()
+                .unwrap_or(0)
+                .saturating_add(1),
+            nodes: children
+                .iter()
+                .map(|(_, size)| size.nodes)
+                .fold(1, usize::saturating_add),
+        };
+
+        while size(&children).exceeds(self.limits) {
+            // Leaves and calls gain nothing from hoisting, nor can colours be hoisted
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, (child, size))| {
+                    size.nodes > 1
+                        && !matches!(child, FnNode::Call(_, _))
+                        && self.is_bool(child).is_some()
+                })
+                .max_by_key(|(_, (_, size))| (size.nodes, size.depth))
+                .map(|(idx, _)| idx);
+            let Some(slot) = largest.and_then(|idx| children.get_mut(idx)) else {
+                break;
+            };
+            let (child, _) = std::mem::replace(slot, (FnNode::Number(0.0), Size::LEAF));
+            *slot = self.hoist(child)?;
+        }
+
+        let size = size(&children);
+        let mut children = children.into_iter().map(|(child, _)| Box::new(child));
+        let mut next = || children.next().ok_or("Node lost an operand while hoisting");
+        let node = match node {
+            FnNode::Arithmetic(_, op, _) => FnNode::Arithmetic(next()?, *op, next()?),
+            FnNode::Compare(_, op, _) => FnNode::Compare(next()?, *op, next()?),
+            FnNode::Unary(op, _) => FnNode::Unary(op.clone(), next()?),
+            FnNode::If(_, _, _) => FnNode::If(next()?, next()?, next()?),
+            FnNode::Triple(_, _, _) => FnNode::Triple(next()?, next()?, next()?),
+            FnNode::Call(idx, _) => FnNode::Call(
+                *idx,
+                std::iter::from_fn(|| next().ok().map(|arg| *arg)).collect(),
+            ),
+            leaf => return Ok((leaf.clone(), Size::LEAF)),
+        };
+        Ok((node, size))
+    }
+}
+
+impl Shared {
+    /// Hoists subtrees out of the temporaries and body until no expression exceeds `limits`,
+    /// returning the helpers `FnNode::Call` refers to in the order they have to be declared
+    pub fn hoist(&mut self, limits: Limits) -> Result<Vec<Helper>, String> {
+        let mut hoister = Hoister {
+            shared: &*self,
+            limits,
+            helpers: Vec::new(),
+        };
+        let mut lets = Vec::with_capacity(self.lets.len());
+        for binding in &self.lets {
+            lets.push(hoister.fit(&binding.expr)?.0);
+        }
+        let body = hoister.fit(&self.body)?.0;
+        let helpers = hoister.helpers;
+
+        for (binding, expr) in self.lets.iter_mut().zip(lets) {
+            binding.expr = expr;
+        }
+        self.body = body;
+        Ok(helpers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{Glsl450, ShaderBackend, TimeMap, Wgsl};
+    use crate::grammar::Origins;
+    use crate::node::{ArithmeticOp, CompareOp, UnaryOp};
+    use crate::pretty::Layout;
+    use crate::template::Template;
+
+    // sin(sin(...(a + b)...)), `depth` operations deep over the sum
+    fn deep(a: FnNode, b: FnNode, depth: usize) -> FnNode {
+        (0..depth).fold(FnNode::arithmetic(a, ArithmeticOp::Add, b), |acc, _| {
+            FnNode::unary(UnaryOp::Sin, acc)
+        })
+    }
+
+    fn max_size(node: &FnNode) -> Size {
+        let children = node.children();
+        Size {
+            depth: children
+                .iter()
+                .map(|child| max_size(child).depth)
+                .max()
+                .unwrap_or(0)
+                .saturating_add(1),
+            nodes: children
+                .iter()
+                .map(|child| max_size(child).nodes)
+                .fold(1, usize::saturating_add),
+        }
+    }
+
+    #[test]
+    fn test_hoist_within_limits() {
+        let limits = Limits {
+            max_depth: 8,
+            max_nodes: 20,
+        };
+        let node = FnNode::triple(
+            deep(FnNode::X, FnNode::Y, 40),
+            FnNode::T,
+            deep(FnNode::T, FnNode::R, 9),
+        );
+        let mut shared = node.share().expect("Sharing should succeed");
+        let helpers = shared.hoist(limits).expect("Hoisting should succeed");
+
+        assert!(!helpers.is_empty());
+        for expr in std::iter::once(&shared.body).chain(helpers.iter().map(|h| &h.body)) {
+            assert!(!max_size(expr).exceeds(limits), "{expr}");
+        }
+        // Each helper only calls ones declared before it
+        for (idx, helper) in helpers.iter().enumerate() {
+            assert!(!format!("{}", helper.body).contains(&format!("h{idx}(")));
+            assert!(!helper.is_bool);
+            assert!(
+                [
+                    vec![(FnNode::X, false), (FnNode::Y, false)],
+                    vec![(FnNode::T, false), (FnNode::R, false)]
+                ]
+                .contains(&helper.params),
+                "{:?}",
+                helper.params
+            );
+        }
+
+        let untouched = FnNode::triple(deep(FnNode::X, FnNode::Y, 3), FnNode::T, FnNode::X);
+        let mut shared = untouched.share().expect("Sharing should succeed");
+        assert!(shared.hoist(limits).expect("Nothing to hoist").is_empty());
+        assert_eq!(shared.body, untouched);
+    }
+
+    #[test]
+    fn test_hoisted_shaders_validate() {
+        let limits = Limits {
+            max_depth: 6,
+            max_nodes: 12,
+        };
+        let template = Template::parse(
+            "{{helpers}}\nfn main(x: f32, y: f32, t: f32, r: f32, theta: f32) -> vec3<f32> {\n{{lets}}    return {{expr}};\n}\n",
+        )
+        .expect("Template should parse");
+        let node = FnNode::triple(
+            deep(FnNode::X, FnNode::Y, 30),
+            // The condition fits on its own but not alongside the branches, so it becomes a helper
+            FnNode::if_(
+                FnNode::compare(
+                    deep(FnNode::R, FnNode::T, 2),
+                    CompareOp::LessThan,
+                    deep(FnNode::Theta, FnNode::X, 2),
+                ),
+                deep(FnNode::Y, FnNode::R, 12),
+                FnNode::Theta,
+            ),
+            FnNode::arithmetic(
+                deep(FnNode::T, FnNode::Theta, 20),
+                ArithmeticOp::Mul,
+                deep(FnNode::X, FnNode::T, 20),
+            ),
+        );
+        for layout in [
+            Layout::Compact,
+            Layout::Pretty {
+                width: 60,
+                origins: &Origins::default(),
+            },
+        ] {
+            let source = node
+                .clone()
+                .compile_fs(&template, "", &Wgsl, layout, limits, TimeMap::default())
+                .expect("WGSL should compile");
+            assert!(source.contains("fn h0("), "{source}");
+            if let Err(e) = naga::front::wgsl::parse_str(&source) {
+                panic!("{}\n{source}", e.emit_to_string(&source));
+            }
+        }
+
+        let mut shared = node.share().expect("Sharing should succeed");
+        let helpers = shared.hoist(limits).expect("Hoisting should succeed");
+        assert!(helpers.iter().any(|helper| helper.is_bool));
+        let glsl = helpers
+            .iter()
+            .enumerate()
+            .map(|(idx, helper)| {
+                let body = format!("    return {};\n", Glsl450.compile_expr(&helper.body)?);
+                let params = helper
+                    .params
+                    .iter()
+                    .map(|(arg, is_bool)| Ok((Glsl450.compile_expr(arg)?, *is_bool)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                let params = params
+                    .iter()
+                    .map(|(arg, is_bool)| (arg.as_str(), *is_bool))
+                    .collect::<Vec<_>>();
+                Ok(Glsl450.function(&format!("h{idx}"), &params, helper.is_bool, &body))
+            })
+            .collect::<Result<String, String>>()
+            .expect("GLSL should compile");
+        assert!(glsl.contains("bool h"), "{glsl}");
+    }
+}
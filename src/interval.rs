@@ -0,0 +1,389 @@
+// Interval arithmetic over `FnNode`: bounds each colour channel over x, y in [-1, 1] and a given t
+// range, with params fixed at their bound value. Bounds are conservative, so a channel can be
+// reported wider than it really is but never narrower. NaN results (sqrt of a negative, 0 / 0) are
+// not tracked.
+use std::f32::consts::{FRAC_PI_2, PI, SQRT_2, TAU};
+use std::fmt::Display;
+
+use crate::node::{ArithmeticOp, ColorMap, CompareOp, FnNode, UnaryOp};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: f32,
+    pub hi: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Range {
+    Number(Interval),
+    Boolean { maybe_true: bool, maybe_false: bool },
+}
+
+impl Interval {
+    pub const UNBOUNDED: Interval = Interval {
+        lo: f32::NEG_INFINITY,
+        hi: f32::INFINITY,
+    };
+
+    pub fn new(lo: f32, hi: f32) -> Self {
+        Interval { lo, hi }
+    }
+
+    pub fn point(val: f32) -> Self {
+        Interval { lo: val, hi: val }
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.lo.is_finite() && self.hi.is_finite()
+    }
+
+    /// Whether the channel provably takes a single value everywhere
+    pub fn is_constant(&self) -> bool {
+        self.is_bounded() && self.hi <= self.lo
+    }
+
+    fn union(self, other: Interval) -> Interval {
+        Interval::new(self.lo.min(other.lo), self.hi.max(other.hi))
+    }
+
+    // Smallest interval holding all `values`, unbounded if any of them is NaN (e.g. 0 * inf)
+    fn hull(values: [f32; 4]) -> Interval {
+        if values.iter().any(|val| val.is_nan()) {
+            return Interval::UNBOUNDED;
+        }
+        let lo = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let hi = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        Interval::new(lo, hi)
+    }
+
+    fn contains_zero(self) -> bool {
+        self.lo <= 0.0 && self.hi >= 0.0
+    }
+
+    // Whether some `phase + k * period` lies inside the interval
+    fn contains_phase(self, phase: f32, period: f32) -> bool {
+        let k = ((self.lo - phase) / period).ceil();
+        phase + k * period <= self.hi
+    }
+
+    fn arithmetic(self, op: ArithmeticOp, b: Interval) -> Interval {
+        let a = self;
+        match op {
+            ArithmeticOp::Add => Interval::new(a.lo + b.lo, a.hi + b.hi),
+            ArithmeticOp::Sub => Interval::new(a.lo - b.hi, a.hi - b.lo),
+            ArithmeticOp::Mul => {
+                Interval::hull([a.lo * b.lo, a.lo * b.hi, a.hi * b.lo, a.hi * b.hi])
+            }
+            ArithmeticOp::Div | ArithmeticOp::Mod if b.contains_zero() => Interval::UNBOUNDED,
+            ArithmeticOp::Div => {
+                Interval::hull([a.lo / b.lo, a.lo / b.hi, a.hi / b.lo, a.hi / b.hi])
+            }
+            // Covers both the truncated (CPU) and floored (GPU) remainder
+            ArithmeticOp::Mod => {
+                let m = b.lo.abs().max(b.hi.abs());
+                Interval::new(-m, m)
+            }
+        }
+    }
+
+    fn unary(self, op: &UnaryOp) -> Interval {
+        match op {
+            UnaryOp::Sqrt => Interval::new(self.lo.max(0.0).sqrt(), self.hi.max(0.0).sqrt()),
+            UnaryOp::Abs if self.lo >= 0.0 => self,
+            UnaryOp::Abs if self.hi <= 0.0 => Interval::new(-self.hi, -self.lo),
+            UnaryOp::Abs => Interval::new(0.0, self.hi.max(-self.lo)),
+            UnaryOp::Sin => self.sin(),
+            UnaryOp::Cos => Interval::new(self.lo + FRAC_PI_2, self.hi + FRAC_PI_2).sin(),
+            UnaryOp::Tan => {
+                if !self.is_bounded()
+                    || self.hi - self.lo >= PI
+                    || self.contains_phase(FRAC_PI_2, PI)
+                {
+                    Interval::UNBOUNDED
+                } else {
+                    Interval::new(self.lo.tan(), self.hi.tan())
+                }
+            }
+        }
+    }
+
+    fn sin(self) -> Interval {
+        if !self.is_bounded() || self.hi - self.lo >= TAU {
+            return Interval::new(-1.0, 1.0);
+        }
+        let (a, b) = (self.lo.sin(), self.hi.sin());
+        Interval::new(
+            if self.contains_phase(-FRAC_PI_2, TAU) {
+                -1.0
+            } else {
+                a.min(b)
+            },
+            if self.contains_phase(FRAC_PI_2, TAU) {
+                1.0
+            } else {
+                a.max(b)
+            },
+        )
+    }
+}
+
+impl ColorMap {
+    /// The channel values that map to 0 to 1
+    pub fn range(self) -> Interval {
+        match self {
+            ColorMap::Signed => Interval::new(-1.0, 1.0),
+            ColorMap::Shader => Interval::new(-0.5, 0.5),
+            ColorMap::Unit => Interval::new(0.0, 1.0),
+        }
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+impl Range {
+    fn union(self, other: Range) -> Result<Range, String> {
+        match (self, other) {
+            (Range::Number(a), Range::Number(b)) => Ok(Range::Number(a.union(b))),
+            (
+                Range::Boolean {
+                    maybe_true: a_true,
+                    maybe_false: a_false,
+                },
+                Range::Boolean {
+                    maybe_true: b_true,
+                    maybe_false: b_false,
+                },
+            ) => Ok(Range::Boolean {
+                maybe_true: a_true || b_true,
+                maybe_false: a_false || b_false,
+            }),
+            _ => Err("Mismatched branch types in if statement".to_string()),
+        }
+    }
+}
+
+fn compare(a: Interval, op: CompareOp, b: Interval) -> Range {
+    let diff = a.arithmetic(ArithmeticOp::Sub, b);
+    let (maybe_true, maybe_false) = match op {
+        CompareOp::GreaterThan => (a.hi > b.lo, a.lo <= b.hi),
+        CompareOp::LessThan => (a.lo < b.hi, a.hi >= b.lo),
+        CompareOp::GreaterThanEqual => (a.hi >= b.lo, a.lo < b.hi),
+        CompareOp::LessThanEqual => (a.lo <= b.hi, a.hi > b.lo),
+        CompareOp::Equal => (
+            diff.lo < f32::EPSILON && diff.hi > -f32::EPSILON,
+            diff.lo <= -f32::EPSILON || diff.hi >= f32::EPSILON,
+        ),
+        CompareOp::NotEqual => (
+            diff.lo < -f32::EPSILON || diff.hi > f32::EPSILON,
+            diff.lo <= f32::EPSILON && diff.hi >= -f32::EPSILON,
+        ),
+    };
+    Range::Boolean {
+        maybe_true,
+        maybe_false,
+    }
+}
+
+impl FnNode {
+    fn range(&self, t: Interval) -> Result<Range, String> {
+        let number = |node: &FnNode| match node.range(t)? {
+            Range::Number(interval) => Ok(interval),
+            Range::Boolean { .. } => Err("Expected a number, found a boolean".to_string()),
+        };
+        match self {
+            FnNode::X | FnNode::Y => Ok(Range::Number(Interval::new(-1.0, 1.0))),
+            FnNode::T => Ok(Range::Number(t)),
+            FnNode::R => Ok(Range::Number(Interval::new(0.0, SQRT_2))),
+            FnNode::Theta => Ok(Range::Number(Interval::new(-PI, PI))),
+            FnNode::Boolean(val) => Ok(Range::Boolean {
+                maybe_true: *val,
+                maybe_false: !*val,
+            }),
+            FnNode::Param(_, val) | FnNode::Number(val) => Ok(Range::Number(Interval::point(*val))),
+            FnNode::Random | FnNode::Rule(_, _) | FnNode::Var(_) | FnNode::Call(_, _) => {
+                Err("Unexpanded node encountered during range analysis".to_string())
+            }
+            FnNode::Arithmetic(a, op, b) => {
+                Ok(Range::Number(number(a)?.arithmetic(*op, number(b)?)))
+            }
+            FnNode::Compare(a, op, b) => Ok(compare(number(a)?, *op, number(b)?)),
+            FnNode::Unary(op, expr) => Ok(Range::Number(number(expr)?.unary(op))),
+            FnNode::If(cond, then_branch, else_branch) => match cond.range(t)? {
+                Range::Boolean {
+                    maybe_true: true,
+                    maybe_false: false,
+                } => then_branch.range(t),
+                Range::Boolean {
+                    maybe_true: false,
+                    maybe_false: true,
+                } => else_branch.range(t),
+                Range::Boolean { .. } => then_branch.range(t)?.union(else_branch.range(t)?),
+                Range::Number(_) => Err("Invalid condition for if statement".to_string()),
+            },
+            FnNode::Triple(_, _, _) => Err("Nested triple in expression".to_string()),
+        }
+    }
+
+    /// Bounds of the red, green and blue channels of a colour expression, with t in `t`
+    pub fn channel_ranges(&self, t: Interval) -> Result<[Interval; 3], String> {
+        let FnNode::Triple(r, g, b) = self else {
+            return Err("Expected a colour triple".to_string());
+        };
+        let mut ranges = [Interval::UNBOUNDED; 3];
+        for (range, channel) in ranges.iter_mut().zip([r, g, b]) {
+            match channel.range(t)? {
+                Range::Number(interval) => *range = interval,
+                Range::Boolean { .. } => return Err("Boolean colour channel".to_string()),
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Rescales every bounded, non-constant channel onto the range `color_map` shows in full.
+    /// Returns the ranges found before rescaling.
+    pub fn normalize(&mut self, t: Interval, color_map: ColorMap) -> Result<[Interval; 3], String> {
+        let ranges = self.channel_ranges(t)?;
+        let FnNode::Triple(r, g, b) = self else {
+            return Err("Expected a colour triple".to_string());
+        };
+        let target = color_map.range();
+        for (channel, range) in [r, g, b].into_iter().zip(ranges) {
+            let scale = (target.hi - target.lo) / (range.hi - range.lo);
+            if !range.is_bounded() || range.is_constant() || !scale.is_finite() {
+                continue;
+            }
+            let expr = std::mem::replace(&mut **channel, FnNode::Number(0.0));
+            **channel = FnNode::arithmetic(
+                FnNode::arithmetic(expr, ArithmeticOp::Mul, FnNode::Number(scale)),
+                ArithmeticOp::Add,
+                FnNode::Number(target.lo - range.lo * scale),
+            );
+        }
+        Ok(ranges)
+    }
+}
+
+/// The channels that come out constant over the analysed domain, which render flat, with their
+/// value
+pub fn constant_channels(ranges: &[Interval; 3]) -> Vec<(char, f32)> {
+    ['r', 'g', 'b']
+        .into_iter()
+        .zip(ranges)
+        .filter(|(_, range)| range.is_constant())
+        .map(|(channel, range)| (channel, range.lo))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_channel_ranges() {
+        let node = FnNode::triple(
+            FnNode::Number(0.25),
+            FnNode::unary(
+                UnaryOp::Sin,
+                FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::Number(0.5)),
+            ),
+            FnNode::arithmetic(FnNode::Number(1.0), ArithmeticOp::Div, FnNode::X),
+        );
+        let [r, g, b] = node
+            .channel_ranges(Interval::new(-1.0, 1.0))
+            .expect("Analysis should succeed");
+        assert!(r.is_constant());
+        assert_eq!(constant_channels(&[r, g, b]), vec![('r', 0.25)]);
+        assert!(!g.is_constant());
+        assert!((g.hi - 0.5_f32.sin()).abs() < 1e-6 && (g.lo + 0.5_f32.sin()).abs() < 1e-6);
+        assert!(!b.is_bounded());
+
+        // x never exceeds 2, so only the else branch is reachable
+        let node = FnNode::triple(
+            FnNode::if_(
+                FnNode::compare(FnNode::X, CompareOp::GreaterThan, FnNode::Number(2.0)),
+                FnNode::Y,
+                FnNode::Number(0.0),
+            ),
+            FnNode::R,
+            FnNode::Theta,
+        );
+        let [r, _, _] = node
+            .channel_ranges(Interval::new(-1.0, 1.0))
+            .expect("Analysis should succeed");
+        assert!(r.is_constant());
+    }
+
+    #[test]
+    fn test_ranges_contain_eval() {
+        let input = r"
+        E | vec3(C, C, C) ;
+        A | random | x | y | t | r | theta ;
+        C ||  A
+          ||| add(C, C)
+          ||| sub(C, C)
+          ||| mul(C, C)
+          |   div(C, C)
+          |   sqrt(C)
+          |   abs(C)
+          |   sin(C)
+          |   cos(C)
+          |   tan(C)
+          |   mod(C, C)
+          ;";
+        let grammar = Parser::new(input)
+            .parse()
+            .expect("Parse should be successful");
+        let t = Interval::new(-2.0, 2.0);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for seed in 0..200 {
+            let Some(mut node) = grammar.gen_seeded(0, 6, seed) else {
+                continue;
+            };
+            let ranges = node.channel_ranges(t).expect("Analysis should succeed");
+            let original = node.clone();
+            node.normalize(t, ColorMap::Shader)
+                .expect("Normalisation should succeed");
+
+            for _ in 0..32 {
+                let (x, y, t) = (
+                    rng.random::<f32>() * 2.0 - 1.0,
+                    rng.random::<f32>() * 2.0 - 1.0,
+                    rng.random::<f32>() * 4.0 - 2.0,
+                );
+                let color = original
+                    .eval_fn(x, y, t)
+                    .expect("Evaluation should succeed");
+                let normalized = node.eval_fn(x, y, t).expect("Evaluation should succeed");
+                let channels = [
+                    (color.r, normalized.r),
+                    (color.g, normalized.g),
+                    (color.b, normalized.b),
+                ];
+                for ((val, rescaled), range) in channels.into_iter().zip(ranges) {
+                    if !val.is_finite() {
+                        continue;
+                    }
+                    let slack = 1e-4 * range.lo.abs().max(range.hi.abs()).max(1.0);
+                    assert!(
+                        val >= range.lo - slack && val <= range.hi + slack,
+                        "{original}\n{val} at ({x}, {y}, {t}) outside {range}"
+                    );
+                    if range.is_bounded() && !range.is_constant() {
+                        assert!(
+                            rescaled.abs() <= 0.5 + 1e-3,
+                            "{original}\nrescaled to {rescaled} at ({x}, {y}, {t})"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
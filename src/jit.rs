@@ -0,0 +1,436 @@
+#![allow(clippy::many_single_char_names)]
+// Native code for a `vm::Program`, via Cranelift and behind the `jit` feature. Every bytecode
+// instruction maps onto a single Cranelift instruction, or a call back into Rust for the functions
+// that have none (sin, cos, tan, atan2 and `%`), so results are bit-identical to the interpreter
+// and to `FnNode::eval`.
+use std::collections::HashMap;
+
+use cranelift_codegen::ir::condcodes::FloatCC;
+use cranelift_codegen::ir::{types, AbiParam, FuncRef, InstBuilder, MemFlags, Signature, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use crate::node::{ArithmeticOp, CompareOp, UnaryOp};
+use crate::vm::{Instr, Program, R, T, THETA, X, Y};
+
+// Writes the colour at `(x, y, t)` to the three floats behind the pointer
+type RawFunction = extern "C" fn(f32, f32, f32, *mut f32);
+
+extern "C" fn sin(a: f32) -> f32 {
+    a.sin()
+}
+
+extern "C" fn cos(a: f32) -> f32 {
+    a.cos()
+}
+
+extern "C" fn tan(a: f32) -> f32 {
+    a.tan()
+}
+
+extern "C" fn fmod(a: f32, b: f32) -> f32 {
+    a % b
+}
+
+extern "C" fn atan2(y: f32, x: f32) -> f32 {
+    y.atan2(x)
+}
+
+const UNARY_CALLBACKS: [(&str, extern "C" fn(f32) -> f32); 3] = [
+    ("shaderand_sin", sin),
+    ("shaderand_cos", cos),
+    ("shaderand_tan", tan),
+];
+
+const BINARY_CALLBACKS: [(&str, extern "C" fn(f32, f32) -> f32); 2] =
+    [("shaderand_fmod", fmod), ("shaderand_atan2", atan2)];
+
+/// A program compiled to machine code for the host
+pub struct NativeFunction {
+    // Owns the code `func` points into, freed on drop
+    module: Option<JITModule>,
+    func: RawFunction,
+}
+
+fn signature(module: &JITModule, params: usize, returns: usize) -> Signature {
+    let mut sig = module.make_signature();
+    sig.params
+        .extend(std::iter::repeat_n(AbiParam::new(types::F32), params));
+    sig.returns
+        .extend(std::iter::repeat_n(AbiParam::new(types::F32), returns));
+    sig
+}
+
+fn variable(reg: usize) -> Result<Variable, String> {
+    u32::try_from(reg)
+        .map(Variable::from_u32)
+        .map_err(|_| format!("Register {reg} out of range"))
+}
+
+// Calls back into Rust, by name
+struct Callbacks {
+    refs: HashMap<&'static str, FuncRef>,
+}
+
+impl Callbacks {
+    fn call(
+        &self,
+        builder: &mut FunctionBuilder,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Value, String> {
+        let func = *self
+            .refs
+            .get(name)
+            .ok_or_else(|| format!("No callback {name}"))?;
+        let call = builder.ins().call(func, args);
+        builder
+            .inst_results(call)
+            .first()
+            .copied()
+            .ok_or_else(|| format!("Callback {name} returned nothing"))
+    }
+}
+
+fn load(builder: &mut FunctionBuilder, reg: usize) -> Result<Value, String> {
+    Ok(builder.use_var(variable(reg)?))
+}
+
+fn store(builder: &mut FunctionBuilder, reg: usize, value: Value) -> Result<(), String> {
+    builder.def_var(variable(reg)?, value);
+    Ok(())
+}
+
+// Any instruction but the jumps, which need the surrounding blocks
+fn compute(
+    builder: &mut FunctionBuilder,
+    callbacks: &Callbacks,
+    instr: &Instr,
+    (x, y): (Value, Value),
+) -> Result<(), String> {
+    match instr {
+        Instr::Polar => {
+            let xx = builder.ins().fmul(x, x);
+            let yy = builder.ins().fmul(y, y);
+            let sum = builder.ins().fadd(xx, yy);
+            let r = builder.ins().sqrt(sum);
+            store(builder, R, r)?;
+            let theta = callbacks.call(builder, "shaderand_atan2", &[y, x])?;
+            store(builder, THETA, theta)?;
+        }
+        Instr::Arithmetic { op, dst, a, b } => {
+            let (a, b) = (load(builder, *a)?, load(builder, *b)?);
+            let value = match op {
+                ArithmeticOp::Add => builder.ins().fadd(a, b),
+                ArithmeticOp::Sub => builder.ins().fsub(a, b),
+                ArithmeticOp::Mul => builder.ins().fmul(a, b),
+                ArithmeticOp::Div => builder.ins().fdiv(a, b),
+                ArithmeticOp::Mod => callbacks.call(builder, "shaderand_fmod", &[a, b])?,
+            };
+            store(builder, *dst, value)?;
+        }
+        Instr::Compare { op, dst, a, b } => {
+            let (a, b) = (load(builder, *a)?, load(builder, *b)?);
+            let holds = match op {
+                CompareOp::GreaterThan => builder.ins().fcmp(FloatCC::GreaterThan, a, b),
+                CompareOp::LessThan => builder.ins().fcmp(FloatCC::LessThan, a, b),
+                CompareOp::GreaterThanEqual => {
+                    builder.ins().fcmp(FloatCC::GreaterThanOrEqual, a, b)
+                }
+                CompareOp::LessThanEqual => builder.ins().fcmp(FloatCC::LessThanOrEqual, a, b),
+                CompareOp::Equal | CompareOp::NotEqual => {
+                    let diff = builder.ins().fsub(a, b);
+                    let diff = builder.ins().fabs(diff);
+                    let epsilon = builder.ins().f32const(f32::EPSILON);
+                    let cc = if *op == CompareOp::Equal {
+                        FloatCC::LessThan
+                    } else {
+                        FloatCC::GreaterThan
+                    };
+                    builder.ins().fcmp(cc, diff, epsilon)
+                }
+            };
+            let one = builder.ins().f32const(1.0);
+            let zero = builder.ins().f32const(0.0);
+            let value = builder.ins().select(holds, one, zero);
+            store(builder, *dst, value)?;
+        }
+        Instr::Unary { op, dst, a } => {
+            let a = load(builder, *a)?;
+            let value = match op {
+                UnaryOp::Sqrt => builder.ins().sqrt(a),
+                UnaryOp::Abs => builder.ins().fabs(a),
+                UnaryOp::Sin => callbacks.call(builder, "shaderand_sin", &[a])?,
+                UnaryOp::Cos => callbacks.call(builder, "shaderand_cos", &[a])?,
+                UnaryOp::Tan => callbacks.call(builder, "shaderand_tan", &[a])?,
+            };
+            store(builder, *dst, value)?;
+        }
+        Instr::Copy { dst, src } => {
+            let value = load(builder, *src)?;
+            store(builder, *dst, value)?;
+        }
+        Instr::JumpUnless { .. } | Instr::Jump { .. } => {
+            return Err("Jumps have no value to compute".to_string())
+        }
+    }
+    Ok(())
+}
+
+fn translate(
+    program: &Program,
+    builder: &mut FunctionBuilder,
+    callbacks: &Callbacks,
+) -> Result<(), String> {
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let params = builder.block_params(entry).to_vec();
+    let &[x, y, t, out] = params.as_slice() else {
+        return Err("Unexpected parameters".to_string());
+    };
+
+    for (reg, init) in program.registers.iter().enumerate() {
+        let var = variable(reg)?;
+        builder.declare_var(var, types::F32);
+        let value = match reg {
+            X => x,
+            Y => y,
+            T => t,
+            _ => builder.ins().f32const(*init),
+        };
+        builder.def_var(var, value);
+    }
+
+    let mut blocks = HashMap::new();
+    for instr in &program.instrs {
+        if let Instr::JumpUnless { target, .. } | Instr::Jump { target } = instr {
+            blocks
+                .entry(*target)
+                .or_insert_with(|| builder.create_block());
+        }
+    }
+    let block = |target: &usize| {
+        blocks
+            .get(target)
+            .copied()
+            .ok_or_else(|| format!("No block for jump target {target}"))
+    };
+
+    // Whether the current block has ended with a jump
+    let mut filled = false;
+    for (pc, instr) in program.instrs.iter().enumerate() {
+        if let Some(next) = blocks.get(&pc) {
+            if !filled {
+                builder.ins().jump(*next, &[]);
+            }
+            builder.switch_to_block(*next);
+            filled = false;
+        } else if filled {
+            // Unreachable, but still needs a block to live in
+            let next = builder.create_block();
+            builder.switch_to_block(next);
+            filled = false;
+        }
+
+        match instr {
+            Instr::JumpUnless { cond, target } => {
+                let cond = load(builder, *cond)?;
+                let zero = builder.ins().f32const(0.0);
+                let is_zero = builder.ins().fcmp(FloatCC::Equal, cond, zero);
+                let next = builder.create_block();
+                builder.ins().brif(is_zero, block(target)?, &[], next, &[]);
+                builder.switch_to_block(next);
+            }
+            Instr::Jump { target } => {
+                builder.ins().jump(block(target)?, &[]);
+                filled = true;
+            }
+            _ => compute(builder, callbacks, instr, (x, y))?,
+        }
+    }
+    if let Some(end) = blocks.get(&program.instrs.len()) {
+        if !filled {
+            builder.ins().jump(*end, &[]);
+        }
+        builder.switch_to_block(*end);
+    } else if filled {
+        let end = builder.create_block();
+        builder.switch_to_block(end);
+    }
+
+    for (reg, offset) in program.output.into_iter().zip([0, 4, 8]) {
+        let value = load(builder, reg)?;
+        builder.ins().store(MemFlags::trusted(), value, out, offset);
+    }
+    builder.ins().return_(&[]);
+    builder.seal_all_blocks();
+    Ok(())
+}
+
+impl NativeFunction {
+    pub fn compile(program: &Program) -> Result<NativeFunction, String> {
+        let mut flags = settings::builder();
+        for (name, value) in [
+            ("use_colocated_libcalls", "false"),
+            ("is_pic", "false"),
+            ("opt_level", "speed"),
+        ] {
+            flags.set(name, value).map_err(|e| e.to_string())?;
+        }
+        let isa = cranelift_native::builder()
+            .map_err(|e| format!("Host not supported by the JIT: {e}"))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| e.to_string())?;
+        let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
+        for (name, callback) in UNARY_CALLBACKS {
+            jit_builder.symbol(name, callback as *const u8);
+        }
+        for (name, callback) in BINARY_CALLBACKS {
+            jit_builder.symbol(name, callback as *const u8);
+        }
+        let mut module = JITModule::new(jit_builder);
+
+        let mut ids: Vec<(&str, FuncId)> = Vec::new();
+        let callbacks = UNARY_CALLBACKS
+            .iter()
+            .map(|(name, _)| (*name, 1))
+            .chain(BINARY_CALLBACKS.iter().map(|(name, _)| (*name, 2)));
+        for (name, arity) in callbacks {
+            let sig = signature(&module, arity, 1);
+            let id = module
+                .declare_function(name, Linkage::Import, &sig)
+                .map_err(|e| e.to_string())?;
+            ids.push((name, id));
+        }
+
+        let mut sig = signature(&module, 3, 0);
+        sig.params
+            .push(AbiParam::new(module.target_config().pointer_type()));
+        let func_id = module
+            .declare_function("art", Linkage::Local, &sig)
+            .map_err(|e| e.to_string())?;
+        let mut ctx: Context = module.make_context();
+        ctx.func.signature = sig;
+        let mut func_ctx = FunctionBuilderContext::new();
+        {
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+            let mut callbacks = Callbacks {
+                refs: HashMap::new(),
+            };
+            for (name, id) in ids {
+                let func_ref = module.declare_func_in_func(id, builder.func);
+                callbacks.refs.insert(name, func_ref);
+            }
+            translate(program, &mut builder, &callbacks)?;
+            builder.finalize();
+        }
+        module
+            .define_function(func_id, &mut ctx)
+            .map_err(|e| e.to_string())?;
+        module.finalize_definitions().map_err(|e| e.to_string())?;
+        let code = module.get_finalized_function(func_id);
+        // SAFETY: `code` was just defined with this signature, and lives as long as `module`
+        let func = unsafe { std::mem::transmute::<*const u8, RawFunction>(code) };
+        Ok(NativeFunction {
+            module: Some(module),
+            func,
+        })
+    }
+
+    pub fn call(&self, x: f32, y: f32, t: f32) -> [f32; 3] {
+        let mut out = [0.0; 3];
+        (self.func)(x, y, t, out.as_mut_ptr());
+        out
+    }
+}
+
+impl Drop for NativeFunction {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `func` is private and never handed out, so nothing calls it after this
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use crate::node::FnNode;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_matches_eval(node: &FnNode, native: &NativeFunction, x: f32, y: f32, t: f32) {
+        let expected = node.eval_fn(x, y, t).expect("Evaluation should succeed");
+        let [r, g, b] = native.call(x, y, t);
+        let same = |a: f32, b: f32| a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan());
+        assert!(
+            same(expected.r, r) && same(expected.g, g) && same(expected.b, b),
+            "{node}\ndiffers at ({x}, {y}, {t}): {expected:?} vs {:?}",
+            [r, g, b]
+        );
+    }
+
+    #[test]
+    fn test_native_matches_eval() {
+        let grammar = Parser::new(
+            "param speed = 0.5 in [0, 2];\nE | vec3(C, C, C) ;\nA | x | y | t | r | theta | speed ;\nC || A ||| add(C, C) ||| sub(C, A) ||| mul(C, C) | div(C, C) | mod(C, C) | sqrt(C) | abs(C) | sin(C) | cos(C) | tan(C) ;",
+        )
+        .parse()
+        .expect("Parse should be successful");
+        let mut rng = StdRng::seed_from_u64(0);
+        for seed in 0..50 {
+            let Some(node) = grammar.gen_seeded(0, 8, seed) else {
+                continue;
+            };
+            let program = node.compile_program().expect("Compilation should succeed");
+            let native = NativeFunction::compile(&program).expect("JIT should succeed");
+            for _ in 0..32 {
+                let (x, y, t) = (
+                    rng.random::<f32>() * 2.0 - 1.0,
+                    rng.random::<f32>() * 2.0 - 1.0,
+                    rng.random::<f32>() * 4.0 - 2.0,
+                );
+                assert_matches_eval(&node, &native, x, y, t);
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_branches() {
+        let wave = FnNode::unary(
+            UnaryOp::Sin,
+            FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::R),
+        );
+        let cond = FnNode::compare(wave.clone(), CompareOp::LessThan, FnNode::Y);
+        let node = FnNode::if_(
+            FnNode::compare(FnNode::T, CompareOp::Equal, FnNode::Number(0.0)),
+            FnNode::triple(wave.clone(), FnNode::Theta, FnNode::Number(f32::NAN)),
+            FnNode::triple(
+                FnNode::if_(
+                    cond,
+                    FnNode::Y,
+                    FnNode::arithmetic(wave, ArithmeticOp::Mod, FnNode::T),
+                ),
+                FnNode::Number(-0.0),
+                FnNode::T,
+            ),
+        );
+        let native =
+            NativeFunction::compile(&node.compile_program().expect("Compilation should succeed"))
+                .expect("JIT should succeed");
+        for (x, y, t) in [
+            (0.0, 0.0, 0.0),
+            (0.5, -0.25, 1.0),
+            (-1.0, 0.9, -0.5),
+            (0.3, 0.3, 0.0),
+        ] {
+            assert_matches_eval(&node, &native, x, y, t);
+        }
+    }
+}
@@ -1,7 +1,20 @@
+pub mod backend;
 pub mod bnf_lexer;
 pub mod bnf_parser;
+pub mod cse;
+pub mod export;
+pub mod expr_parser;
 pub mod grammar;
+pub mod hoist;
+pub mod interval;
 pub mod node;
+pub mod pretty;
+pub mod recipe;
+pub mod simplify;
+pub mod source;
+pub mod template;
+pub mod validate;
+pub mod vm;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod simple;
@@ -9,6 +22,21 @@ pub mod simple;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod native;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cli;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod render;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod animation;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod metadata;
+
+#[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+pub mod jit;
+
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
 
@@ -1,6 +1,6 @@
 fn main() -> Result<(), String> {
     #[cfg(not(target_arch = "wasm32"))]
-    shaderand_wasm::native::glfw_main()?;
+    shaderand_wasm::cli::run()?;
 
     Ok(())
 }
@@ -0,0 +1,194 @@
+// What a render was made from, saved in the text chunks of the PNGs `--render` writes and read
+// back by `--from`: the recipe, with the CPU render settings it leaves out in chunks of their own.
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::recipe::Recipe;
+use crate::render::{Depth, RenderOptions};
+
+/// The `Software` chunk of every image this writes metadata for
+const SOFTWARE: &str = "shaderand";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub recipe: Recipe,
+    /// Threads and tile size aren't saved, making no difference to the image
+    pub options: RenderOptions,
+}
+
+impl Metadata {
+    /// Keyword and text pairs for `RenderOptions::render_file` to save in a PNG
+    pub fn text(&self) -> Vec<(String, String)> {
+        let mut text = vec![
+            ("Software", SOFTWARE.to_string()),
+            ("Recipe", self.recipe.to_string()),
+            ("Sampling", self.options.sampling.to_string()),
+            ("Tone map", self.options.tone_map.to_string()),
+        ];
+        if let Some(threshold) = self.options.adaptive {
+            text.push(("Adaptive", threshold.to_string()));
+        }
+        text.into_iter()
+            .map(|(keyword, text)| (keyword.to_string(), text))
+            .collect()
+    }
+
+    /// Reads back what `text` wrote, taking the settings it leaves out from `options`
+    pub fn from_text(text: &[(String, String)], options: RenderOptions) -> Result<Self, String> {
+        if field::<String>(text, "Software")?.as_deref() != Some(SOFTWARE) {
+            return Err("The image has no metadata to render it from".to_string());
+        }
+        let recipe: Recipe = required(text, "Recipe")?;
+        let options = RenderOptions {
+            width: recipe.width,
+            height: recipe.height,
+            t: recipe.time,
+            color_map: recipe.color_map,
+            sampling: required(text, "Sampling")?,
+            tone_map: required(text, "Tone map")?,
+            adaptive: field(text, "Adaptive")?,
+            ..options
+        };
+        Ok(Metadata { recipe, options })
+    }
+
+    /// Reads the metadata of a PNG saved with `text`
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let reader = png::Decoder::new(BufReader::new(file))
+            .read_info()
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        let info = reader.info();
+        let mut text = info
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+            .collect::<Vec<_>>();
+        for chunk in &info.compressed_latin1_text {
+            text.push((
+                chunk.keyword.clone(),
+                chunk.get_text().map_err(|e| e.to_string())?,
+            ));
+        }
+        for chunk in &info.utf8_text {
+            text.push((
+                chunk.keyword.clone(),
+                chunk.get_text().map_err(|e| e.to_string())?,
+            ));
+        }
+        let options = RenderOptions {
+            width: info.width,
+            height: info.height,
+            depth: match info.bit_depth {
+                png::BitDepth::Sixteen => Depth::Sixteen,
+                _ => Depth::Eight,
+            },
+            ..RenderOptions::default()
+        };
+        Self::from_text(&text, options)
+    }
+}
+
+// The text of the chunk called `keyword`, if there is one
+fn field<T: FromStr>(text: &[(String, String)], keyword: &str) -> Result<Option<T>, String>
+where
+    T::Err: Display,
+{
+    text.iter()
+        .find(|(name, _)| name == keyword)
+        .map(|(_, val)| {
+            val.parse()
+                .map_err(|e| format!("Invalid {keyword} in image metadata: {e}"))
+        })
+        .transpose()
+}
+
+fn required<T: FromStr>(text: &[(String, String)], keyword: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    field(text, keyword)?.ok_or(format!("No {keyword} in image metadata"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use crate::interval::Interval;
+    use crate::node::ColorMap;
+    use crate::recipe::TemplateChoice;
+    use crate::render::{ImageFormat, Sampling, ToneMap};
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_render_from_metadata() {
+        // Not all Latin-1, so it takes an iTXt chunk
+        let grammar = format!(
+            "# Größe\nparam speed = 0.5 in [0, 2];\n{}",
+            include_str!("../grammar.bnf").replace("| theta", "| mul(theta, speed)")
+        );
+        let func = Parser::new(&grammar)
+            .parse()
+            .expect("Parse should be successful")
+            .gen_seeded(0, 8, 42)
+            .expect("Generation should succeed");
+        let recipe = Recipe {
+            grammar,
+            entry: "E".to_string(),
+            seed: 42,
+            depth: 8,
+            func,
+            params: vec![("speed".to_string(), 1.25)],
+            normalize: true,
+            t_range: Interval::new(-1.0, 1.0),
+            loop_period: None,
+            color_map: ColorMap::Unit,
+            template: TemplateChoice::Stock,
+            width: 10,
+            height: 6,
+            time: 0.3,
+        };
+        let metadata = Metadata {
+            options: RenderOptions {
+                width: recipe.width,
+                height: recipe.height,
+                t: recipe.time,
+                color_map: recipe.color_map,
+                depth: Depth::Sixteen,
+                sampling: Sampling::Jittered { n: 2, seed: 9 },
+                adaptive: Some(0.05),
+                tone_map: ToneMap::Reinhard,
+                ..RenderOptions::default()
+            },
+            recipe,
+        };
+        let program = metadata
+            .recipe
+            .function()
+            .and_then(|func| func.compile_program())
+            .expect("Compilation should succeed");
+        let path = std::env::temp_dir().join("shaderand-metadata-test.png");
+        let cancel = AtomicBool::new(false);
+        let options = metadata.options;
+        let text = metadata.text();
+        options
+            .render_file(
+                &program,
+                &[options.t],
+                &path,
+                ImageFormat::Png,
+                &text,
+                &cancel,
+                |_, _| {},
+            )
+            .expect("Rendering should succeed");
+
+        let loaded = Metadata::read(&path).expect("Metadata should load");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, metadata);
+        assert!(Metadata::from_text(&[], options).is_err());
+    }
+}
@@ -1,4 +1,10 @@
-use crate::bnf_parser;
+use crate::backend::{Glsl450, TimeMap};
+use crate::grammar::{Origins, Param};
+use crate::hoist::Limits;
+use crate::node::FnNode;
+use crate::pretty::{self, Layout};
+use crate::recipe::Recipe;
+use crate::template::Template;
 
 use gl::types::{GLchar, GLenum, GLfloat, GLint, GLsizei, GLsizeiptr, GLuint};
 use glfw::{Action, Context, Key, Modifiers};
@@ -34,22 +40,32 @@ void main()
 }
 ";
 
-const FRAGMENT_SHADER_TEMPLATE: &str = r"
+/// GLSL 450 fragment shader template, the viewer's default
+pub const FRAGMENT_SHADER_TEMPLATE: &str = r"
 #version 450
 precision mediump float; // Medium precision for portability across devices
 in vec2 fragTexCoord;    // Interpolated texture coordinates
 out vec4 finalColor;     // Output color of the fragment
 uniform float time;      // Time uniform for animation effects
+{{uniforms}}
 // Function to apply color transformation
 vec4 applyColorTransform(vec3 rgb) {
-    return vec4(rgb + 0.5, 1); // Example transformation, adjust as needed
+    return {{color_map}};
 }
+// Function from the running time to t
+float timeMap(float seconds) {
+    return {{time_map}};
+}
+{{helpers}}
 void main()
 {
     float x = fragTexCoord.x;
     float y = fragTexCoord.y;
-    float t = tan(time);
-    finalColor = applyColorTransform(%s);
+    float t = timeMap(time);
+    float r = length(vec2(x, y));
+    float theta = atan(y, x);
+{{lets}}
+    finalColor = applyColorTransform({{expr}});
 }
 ";
 
@@ -82,38 +98,77 @@ fn compile_shader(source: &str, shader_type: GLenum) -> GLuint {
     }
 }
 
-fn get_random_fs() -> Result<String, String> {
-    // use crate::grammar::Grammar;
-    let inp = "./grammar.bnf";
-    let inp = std::fs::read_to_string(inp).map_err(|e| e.to_string())?;
-    let mut parser = bnf_parser::Parser::new(&inp);
+// One `uniform float <name>;` per grammar param, for `{{uniforms}}`
+fn param_uniforms(params: &[Param]) -> String {
+    params
+        .iter()
+        .map(|param| format!("uniform float {};", param.name))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    let grammar = match parser.parse() {
-        Ok(grammar) => grammar,
-        Err(e) => {
-            return Err(format!("Error parsing BNF: {e:?}"));
-        }
-    };
-    // let grammar = Grammar::default();
+/// The GLSL fragment shader the viewer draws `func` with, its params as uniforms
+pub fn fragment_shader(
+    func: &mut FnNode,
+    params: &[Param],
+    template: &Template,
+    layout: Layout,
+    limits: Limits,
+    time_map: TimeMap,
+) -> Result<String, String> {
+    func.compile_fs(
+        template,
+        &param_uniforms(params),
+        &Glsl450,
+        layout,
+        limits,
+        time_map,
+    )
+}
+
+// The shader for `recipe`, its params left as uniforms unless normalizing bound them
+fn recipe_fs(
+    recipe: &Recipe,
+    origins: &Origins,
+    template: &Template,
+    limits: Limits,
+) -> Result<String, String> {
     println!("Grammar:");
-    println!("{grammar}");
+    println!("{}", recipe.grammar);
 
-    let Some(mut func) = grammar.gen_from_rule(0, 10) else {
-        return Err("Failed to generate function".to_string());
+    let grammar = recipe.load_grammar()?;
+    let mut func = if recipe.normalize {
+        let (func, constant) = recipe.normalized()?;
+        for (channel, val) in constant {
+            eprintln!("Warning: channel {channel} is constant: {val}");
+        }
+        func
+    } else {
+        recipe.func.clone()
     };
-    // println!("Function:");
-    // println!("{func}");
-    // func.optimize()?;
-    // println!("Optimized Function:");
-    // println!("{func}");
-    let template_fs = String::from(FRAGMENT_SHADER_TEMPLATE);
-    func.compile_to_glsl_fs(&template_fs)
+    let layout = Layout::Pretty {
+        width: pretty::DEFAULT_WIDTH,
+        origins,
+    };
+    fragment_shader(
+        &mut func,
+        &grammar.params,
+        template,
+        layout,
+        limits,
+        recipe.time_map(),
+    )
 }
 
 #[allow(non_snake_case)]
 #[allow(clippy::too_many_lines)]
 #[allow(clippy::similar_names)]
-pub fn glfw_main() -> Result<(), String> {
+pub fn glfw_main(
+    recipe: &Recipe,
+    origins: &Origins,
+    template: &Template,
+    limits: Limits,
+) -> Result<(), String> {
     use glfw::fail_on_errors;
 
     let mut glfw = glfw::init(fail_on_errors!()).map_err(|e| e.to_string())?;
@@ -143,7 +198,7 @@ pub fn glfw_main() -> Result<(), String> {
 
     let (shader_program, vao) = unsafe {
         let vertex_shader = compile_shader(VERTEX_SHADER_SOURCE, gl::VERTEX_SHADER);
-        let fs_source = &get_random_fs()?;
+        let fs_source = &recipe_fs(recipe, origins, template, limits)?;
         let fragment_shader = compile_shader(fs_source, gl::FRAGMENT_SHADER);
         let shader_program = gl::CreateProgram();
         gl::AttachShader(shader_program, vertex_shader);
@@ -238,6 +293,15 @@ pub fn glfw_main() -> Result<(), String> {
         let time = CString::new("time").map_err(|e| e.to_string())?;
         gl::GetUniformLocation(shader_program, time.as_ptr())
     };
+    let param_locations = recipe
+        .params
+        .iter()
+        .map(|(name, value)| {
+            let name = CString::new(name.as_str()).map_err(|e| e.to_string())?;
+            let location = unsafe { gl::GetUniformLocation(shader_program, name.as_ptr()) };
+            Ok((location, *value))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
 
     // Store the initial time
     let initial_time = glfw.get_time();
@@ -258,6 +322,9 @@ pub fn glfw_main() -> Result<(), String> {
             // Update time uniform
             let current_time = glfw.get_time() - initial_time;
             gl::Uniform1f(time_location, current_time as f32);
+            for (location, value) in &param_locations {
+                gl::Uniform1f(*location, *value);
+            }
 
             // Set MVP matrix (identity for now)
             let mvp_location = {
@@ -16,16 +16,11 @@
 //         }
 //     }
 // }
-use std::fmt::{Display, Write};
-
-#[cfg(not(target_arch = "wasm32"))]
-use image::{self as img};
-
-const WIDTH: u32 = 1920;
-const HEIGHT: u32 = 944;
+use std::fmt::Display;
+use std::str::FromStr;
 
 #[allow(dead_code)]
-#[derive(Debug, Copy, Clone, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum CompareOp {
     GreaterThan,
     LessThan,
@@ -36,7 +31,7 @@ pub enum CompareOp {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ArithmeticOp {
     Add,
     Sub,
@@ -46,7 +41,7 @@ pub enum ArithmeticOp {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum UnaryOp {
     Sqrt,
     Abs,
@@ -56,16 +51,24 @@ pub enum UnaryOp {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FnNode {
     // Terminal nodes
     X,
     Y,
     T,
+    R,
+    Theta,
     Random,
     Boolean(bool),
     Number(f32),
     Rule(usize, char),
+    // Grammar `param`, compiled to a uniform; the value is only used for CPU evaluation
+    Param(String, f32),
+    // Temporary `v{idx}` introduced by common-subexpression elimination
+    Var(usize),
+    // Call to the helper `h{idx}` an oversized subtree was hoisted into, with its free variables
+    Call(usize, Vec<FnNode>),
 
     // Non-terminal nodes
     Arithmetic(Box<FnNode>, ArithmeticOp, Box<FnNode>),
@@ -102,107 +105,220 @@ impl FnNode {
     pub fn triple(r: FnNode, g: FnNode, b: FnNode) -> FnNode {
         FnNode::Triple(Box::new(r), Box::new(g), Box::new(b))
     }
+
+    /// Sets the CPU-side value of every `Param` node called `name`, returning whether any was found
+    pub fn bind_param(&mut self, name: &str, value: f32) -> bool {
+        match self {
+            FnNode::Param(param, val) if param == name => {
+                *val = value;
+                true
+            }
+            FnNode::Arithmetic(a, _, b) | FnNode::Compare(a, _, b) => {
+                let a = a.bind_param(name, value);
+                b.bind_param(name, value) || a
+            }
+            FnNode::Unary(_, expr) => expr.bind_param(name, value),
+            FnNode::If(a, b, c) | FnNode::Triple(a, b, c) => {
+                let a = a.bind_param(name, value);
+                let b = b.bind_param(name, value);
+                c.bind_param(name, value) || a || b
+            }
+            _ => false,
+        }
+    }
+
+    /// Direct subexpressions, left to right
+    pub(crate) fn children(&self) -> Vec<&FnNode> {
+        match self {
+            FnNode::Arithmetic(a, _, b) | FnNode::Compare(a, _, b) => vec![a, b],
+            FnNode::Unary(_, a) => vec![a],
+            FnNode::If(a, b, c) | FnNode::Triple(a, b, c) => vec![a, b, c],
+            FnNode::Call(_, args) => args.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Every distinct `Param` in the tree with its CPU-side value, in order of first appearance
+    pub fn bound_params(&self) -> Vec<(&str, f32)> {
+        let mut params: Vec<(&str, f32)> = Vec::new();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            match node {
+                FnNode::Param(name, val) if !params.iter().any(|(param, _)| param == name) => {
+                    params.push((name, *val));
+                }
+                FnNode::Arithmetic(a, _, b) | FnNode::Compare(a, _, b) => {
+                    stack.extend([&**b, &**a]);
+                }
+                FnNode::Unary(_, expr) => stack.push(expr),
+                FnNode::If(a, b, c) | FnNode::Triple(a, b, c) => stack.extend([&**c, &**b, &**a]),
+                _ => {}
+            }
+        }
+        params
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Color {
-    r: f32,
-    g: f32,
-    b: f32,
+    pub(crate) r: f32,
+    pub(crate) g: f32,
+    pub(crate) b: f32,
+}
+
+/// The range of channel values that becomes 0 to 1. What falls outside is up to the `ToneMap`,
+/// unless a float format keeps it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMap {
+    /// -1 to 1
+    #[default]
+    Signed,
+    /// -0.5 to 0.5, as the stock shader templates do it
+    Shader,
+    /// 0 to 1
+    Unit,
+}
+
+impl FromStr for ColorMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "signed" => Ok(ColorMap::Signed),
+            "shader" => Ok(ColorMap::Shader),
+            "unit" => Ok(ColorMap::Unit),
+            _ => Err(format!("Unknown colour map: {s}")),
+        }
+    }
+}
+
+impl Display for ColorMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorMap::Signed => "signed",
+            ColorMap::Shader => "shader",
+            ColorMap::Unit => "unit",
+        })
+    }
+}
+
+#[cfg(test)]
+impl Color {
+    // Finite channels within `tolerance`, relative above 1; inf and NaN channels must match exactly
+    pub(crate) fn approx_eq(&self, other: &Color, tolerance: f32) -> bool {
+        [(self.r, other.r), (self.g, other.g), (self.b, other.b)]
+            .iter()
+            .all(|(a, b)| {
+                if a.is_finite() {
+                    (a - b).abs() <= tolerance * a.abs().max(1.0)
+                } else {
+                    a == b || (a.is_nan() && b.is_nan())
+                }
+            })
+    }
+}
+
+impl TryFrom<FnNode> for Color {
+    type Error = String;
+
+    fn try_from(node: FnNode) -> Result<Self, Self::Error> {
+        match node {
+            FnNode::Triple(r, g, b) => match (*r, *g, *b) {
+                (FnNode::Number(r), FnNode::Number(g), FnNode::Number(b)) => Ok(Color { r, g, b }),
+                _ => Err("Invalid operands for triple operation".to_string()),
+            },
+            _ => Err("Invalid result for function".to_string()),
+        }
+    }
 }
 
 impl FnNode {
-    const OPTIMIZED: bool = false;
+    /// Folds constant subtrees, then rewrites with the algebraic rules in `simplify` until neither
+    /// changes the tree any more
     pub fn optimize(&mut self) -> Result<(), String> {
-        if Self::OPTIMIZED {
-            return Ok(());
-        }
+        self.fold_constants()?;
+        self.simplify()
+    }
+
+    pub(crate) fn fold_constants(&mut self) -> Result<(), String> {
         match self {
             FnNode::Number(val) if val.is_nan() => {
                 eprintln!("NaN encountered during optimization");
                 *self = FnNode::Number(0.0);
                 Ok(())
             }
-            FnNode::X | FnNode::Y | FnNode::T | FnNode::Boolean(_) | FnNode::Number(_) => Ok(()),
+            FnNode::X
+            | FnNode::Y
+            | FnNode::T
+            | FnNode::R
+            | FnNode::Theta
+            | FnNode::Param(_, _)
+            | FnNode::Var(_)
+            | FnNode::Call(_, _)
+            | FnNode::Boolean(_)
+            | FnNode::Number(_) => Ok(()),
 
             FnNode::Random | FnNode::Rule(_, _) => {
                 Err("Rule node encountered during optimization".to_string())
             }
 
             FnNode::Triple(first, second, third) => {
-                first.optimize()?;
-                second.optimize()?;
-                third.optimize()?;
+                first.fold_constants()?;
+                second.fold_constants()?;
+                third.fold_constants()?;
                 Ok(())
             }
 
-            FnNode::Arithmetic(a, _, b) => {
-                a.optimize()?;
-                b.optimize()?;
-                match ((**a).clone(), (**b).clone()) {
-                    (FnNode::Number(a), FnNode::Number(b)) => {
-                        *self = FnNode::Number(match self {
-                            FnNode::Arithmetic(_, ArithmeticOp::Add, _) => a + b,
-                            FnNode::Arithmetic(_, ArithmeticOp::Sub, _) => a - b,
-                            FnNode::Arithmetic(_, ArithmeticOp::Mul, _) => a * b,
-                            FnNode::Arithmetic(_, ArithmeticOp::Div, _) => a / b,
-                            FnNode::Arithmetic(_, ArithmeticOp::Mod, _) => a % b,
-                            _ => {
-                                return Err("Invalid operands for arithmetic operation".to_string());
-                            }
-                        });
-                        Ok(())
-                    }
-                    _ => Ok(()),
+            FnNode::Arithmetic(a, op, b) => {
+                a.fold_constants()?;
+                b.fold_constants()?;
+                if let (FnNode::Number(a), FnNode::Number(b)) = (&**a, &**b) {
+                    *self = FnNode::Number(match op {
+                        ArithmeticOp::Add => a + b,
+                        ArithmeticOp::Sub => a - b,
+                        ArithmeticOp::Mul => a * b,
+                        ArithmeticOp::Div => a / b,
+                        ArithmeticOp::Mod => a % b,
+                    });
                 }
+                Ok(())
             }
 
-            FnNode::Compare(a, _, b) => {
-                a.optimize()?;
-                b.optimize()?;
-                match ((**a).clone(), (**b).clone()) {
-                    (FnNode::Number(a), FnNode::Number(b)) => {
-                        *self = FnNode::Boolean(match self {
-                            FnNode::Compare(_, CompareOp::GreaterThan, _) => a > b,
-                            FnNode::Compare(_, CompareOp::LessThan, _) => a < b,
-                            FnNode::Compare(_, CompareOp::GreaterThanEqual, _) => a >= b,
-                            FnNode::Compare(_, CompareOp::LessThanEqual, _) => a <= b,
-                            FnNode::Compare(_, CompareOp::Equal, _) => (a - b).abs() > f32::EPSILON,
-                            FnNode::Compare(_, CompareOp::NotEqual, _) => {
-                                (a - b).abs() <= f32::EPSILON
-                            }
-                            _ => {
-                                return Err("Invalid operands for comparison operation".to_string());
-                            }
-                        });
-                        Ok(())
-                    }
-                    _ => Ok(()),
+            FnNode::Compare(a, op, b) => {
+                a.fold_constants()?;
+                b.fold_constants()?;
+                if let (FnNode::Number(a), FnNode::Number(b)) = (&**a, &**b) {
+                    *self = FnNode::Boolean(match op {
+                        CompareOp::GreaterThan => a > b,
+                        CompareOp::LessThan => a < b,
+                        CompareOp::GreaterThanEqual => a >= b,
+                        CompareOp::LessThanEqual => a <= b,
+                        // Same tolerance as `eval`
+                        CompareOp::Equal => (a - b).abs() < f32::EPSILON,
+                        CompareOp::NotEqual => (a - b).abs() > f32::EPSILON,
+                    });
                 }
+                Ok(())
             }
 
-            FnNode::Unary(_, expr) => {
-                expr.optimize()?;
-                match **expr {
-                    FnNode::Number(val) => {
-                        *self = FnNode::Number(match self {
-                            FnNode::Unary(UnaryOp::Sqrt, _) => val.sqrt(),
-                            FnNode::Unary(UnaryOp::Abs, _) => val.abs(),
-                            FnNode::Unary(UnaryOp::Sin, _) => val.sin(),
-                            FnNode::Unary(UnaryOp::Cos, _) => val.cos(),
-                            FnNode::Unary(UnaryOp::Tan, _) => val.tan(),
-                            _ => return Err("Invalid operand for unary operation".to_string()),
-                        });
-                        Ok(())
-                    }
-                    _ => Ok(()),
+            FnNode::Unary(op, expr) => {
+                expr.fold_constants()?;
+                if let FnNode::Number(val) = **expr {
+                    *self = FnNode::Number(match op {
+                        UnaryOp::Sqrt => val.sqrt(),
+                        UnaryOp::Abs => val.abs(),
+                        UnaryOp::Sin => val.sin(),
+                        UnaryOp::Cos => val.cos(),
+                        UnaryOp::Tan => val.tan(),
+                    });
                 }
+                Ok(())
             }
 
             FnNode::If(cond, then_branch, else_branch) => {
-                cond.optimize()?;
-                then_branch.optimize()?;
-                else_branch.optimize()?;
+                cond.fold_constants()?;
+                then_branch.fold_constants()?;
+                else_branch.fold_constants()?;
                 match **cond {
                     FnNode::Boolean(true) => {
                         *self = *then_branch.clone();
@@ -218,20 +334,28 @@ impl FnNode {
         }
     }
 
-    fn eval(&self, x: f32, y: f32, t: f32) -> Result<FnNode, String> {
+    pub(crate) fn eval(&self, x: f32, y: f32, t: f32, vars: &[FnNode]) -> Result<FnNode, String> {
         match self {
             FnNode::X => Ok(FnNode::Number(x)),
             FnNode::Y => Ok(FnNode::Number(y)),
             FnNode::T => Ok(FnNode::Number(t)),
+            // Same definitions as the `r` and `theta` locals in the shader templates
+            FnNode::R => Ok(FnNode::Number((x * x + y * y).sqrt())),
+            FnNode::Theta => Ok(FnNode::Number(y.atan2(x))),
             FnNode::Boolean(val) => Ok(FnNode::Boolean(*val)),
-            FnNode::Number(val) => Ok(FnNode::Number(*val)),
+            FnNode::Param(_, val) | FnNode::Number(val) => Ok(FnNode::Number(*val)),
+            FnNode::Var(idx) => vars
+                .get(*idx)
+                .cloned()
+                .ok_or_else(|| format!("Unbound variable v{idx} during evaluation")),
             FnNode::Random | FnNode::Rule(_, _) => {
                 Err("Rule node encountered during evaluation".to_string())
             }
+            FnNode::Call(idx, _) => Err(format!("Call to helper h{idx} during evaluation")),
 
             FnNode::Arithmetic(a, op, b) => {
-                let a = a.eval(x, y, t)?;
-                let b = b.eval(x, y, t)?;
+                let a = a.eval(x, y, t, vars)?;
+                let b = b.eval(x, y, t, vars)?;
 
                 match (a, b) {
                     (FnNode::Number(a), FnNode::Number(b)) => match op {
@@ -246,8 +370,8 @@ impl FnNode {
             }
 
             FnNode::Compare(a, ord, b) => {
-                let a = a.eval(x, y, t)?;
-                let b = b.eval(x, y, t)?;
+                let a = a.eval(x, y, t, vars)?;
+                let b = b.eval(x, y, t, vars)?;
 
                 match (a, b) {
                     (FnNode::Number(a), FnNode::Number(b)) => match ord {
@@ -263,7 +387,7 @@ impl FnNode {
             }
 
             FnNode::Unary(op, expr) => {
-                let expr = expr.eval(x, y, t)?;
+                let expr = expr.eval(x, y, t, vars)?;
                 match op {
                     UnaryOp::Sqrt => match expr {
                         FnNode::Number(val) => Ok(FnNode::Number(val.sqrt())),
@@ -288,16 +412,16 @@ impl FnNode {
                 }
             }
 
-            FnNode::If(cond, then_branch, else_branch) => match cond.eval(x, y, t)? {
-                FnNode::Boolean(true) => then_branch.eval(x, y, t),
-                FnNode::Boolean(false) => else_branch.eval(x, y, t),
+            FnNode::If(cond, then_branch, else_branch) => match cond.eval(x, y, t, vars)? {
+                FnNode::Boolean(true) => then_branch.eval(x, y, t, vars),
+                FnNode::Boolean(false) => else_branch.eval(x, y, t, vars),
                 _ => Err("Invalid condition for if statement".to_string()),
             },
 
             FnNode::Triple(first, second, third) => {
-                let first = first.eval(x, y, t)?;
-                let second = second.eval(x, y, t)?;
-                let third = third.eval(x, y, t)?;
+                let first = first.eval(x, y, t, vars)?;
+                let second = second.eval(x, y, t, vars)?;
+                let third = third.eval(x, y, t, vars)?;
                 match (first, second, third) {
                     (FnNode::Number(r), FnNode::Number(g), FnNode::Number(b)) => {
                         Ok(FnNode::Triple(
@@ -312,136 +436,9 @@ impl FnNode {
         }
     }
 
-    fn eval_fn(&self, x: f32, y: f32, t: f32) -> Result<Color, String> {
-        match self.eval(x, y, t) {
-            Ok(FnNode::Triple(r, g, b)) => match (*r, *g, *b) {
-                (FnNode::Number(r), FnNode::Number(g), FnNode::Number(b)) => Ok(Color { r, g, b }),
-                _ => Err("Invalid operands for triple operation".to_string()),
-            },
-            _ => Err("Invalid result for function".to_string()),
-        }
-    }
-
-    #[cfg(not(target_arch = "wasm32"))]
-    pub fn render(&self) -> Result<(), String> {
-        let mut img = img::ImageBuffer::new(WIDTH, HEIGHT);
-
-        for y in 0..HEIGHT {
-            let ny = (y as f32 / HEIGHT as f32) * 2.0 - 1.0;
-            for x in 0..WIDTH {
-                let nx = (x as f32 / WIDTH as f32) * 2.0 - 1.0;
-
-                let color = self.eval_fn(nx, ny, 0.0)?;
-                let pixel = img::Rgb([
-                    (f32::midpoint(color.r, 1.0) * 255.0) as u8,
-                    (f32::midpoint(color.g, 1.0) * 255.0) as u8,
-                    (f32::midpoint(color.b, 1.0) * 255.0) as u8,
-                ]);
-
-                img.put_pixel(x, y, pixel);
-            }
-        }
-
-        img.save("output.png").map_err(|e| e.to_string())?;
-        Ok(())
-    }
-
-    pub fn compile_to_glsl_fs(&mut self, template_fs: &str) -> Result<String, String> {
-        self.optimize()?;
-        let mut compiled_node = String::new();
-        match self.compile_to_glsl_fs_expr(&mut compiled_node) {
-            Ok(()) => {
-                let formatted_fs = template_fs.replace("%s", compiled_node.as_str());
-                println!("{formatted_fs}");
-                Ok(formatted_fs.to_string())
-            }
-            Err(e) => Err(e),
-        }
-    }
-
-    fn compile_to_glsl_fs_expr(&self, buffer: &mut String) -> Result<(), String> {
-        match self {
-            FnNode::X => buffer.push('x'),
-            FnNode::Y => buffer.push('y'),
-            FnNode::T => buffer.push('t'),
-            FnNode::Number(val) => writeln!(buffer, "({val})").map_err(|e| format!("{e}"))?,
-            FnNode::Boolean(val) => match val {
-                true => buffer.push_str("true"),
-                false => buffer.push_str("false"),
-            },
-
-            FnNode::Random | FnNode::Rule(_, _) => {
-                return Err("Rule node encountered during GLSL compilation".to_string());
-            }
-
-            FnNode::Unary(kind, expr) => {
-                buffer.push_str(match kind {
-                    UnaryOp::Sqrt => "sqrt(",
-                    UnaryOp::Abs => "abs(",
-                    UnaryOp::Sin => "sin(",
-                    UnaryOp::Cos => "cos(",
-                    UnaryOp::Tan => "tan(",
-                });
-                expr.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push(')');
-            }
-
-            FnNode::Arithmetic(a, kind, b) => {
-                buffer.push('(');
-                if let ArithmeticOp::Mod = kind {
-                    buffer.push_str("mod(");
-                }
-                a.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str(match kind {
-                    ArithmeticOp::Add => " + ",
-                    ArithmeticOp::Sub => " - ",
-                    ArithmeticOp::Mul => " * ",
-                    ArithmeticOp::Div => " / ",
-                    ArithmeticOp::Mod => ", ",
-                });
-                b.compile_to_glsl_fs_expr(buffer)?;
-                if let ArithmeticOp::Mod = kind {
-                    buffer.push(')');
-                }
-                buffer.push(')');
-            }
-
-            FnNode::Compare(a, kind, b) => {
-                buffer.push('(');
-                a.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str(match kind {
-                    CompareOp::GreaterThanEqual => " >= ",
-                    CompareOp::GreaterThan => " > ",
-                    CompareOp::LessThanEqual => " <= ",
-                    CompareOp::LessThan => " < ",
-                    CompareOp::Equal => " == ",
-                    CompareOp::NotEqual => " != ",
-                });
-                b.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push(')');
-            }
-
-            FnNode::If(cond, then, elze) => {
-                buffer.push_str("((");
-                cond.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str(") ? (");
-                then.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str(") : (");
-                elze.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str("))");
-            }
-
-            FnNode::Triple(r, g, b) => {
-                buffer.push_str("vec3(");
-                r.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str(", ");
-                g.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push_str(", ");
-                b.compile_to_glsl_fs_expr(buffer)?;
-                buffer.push(')');
-            }
-        }
-        Ok(())
+    #[allow(dead_code)]
+    pub(crate) fn eval_fn(&self, x: f32, y: f32, t: f32) -> Result<Color, String> {
+        Color::try_from(self.eval(x, y, t, &[])?)
     }
 }
 
@@ -455,6 +452,17 @@ impl FnNode {
             FnNode::X => writeln!(f, "{indent_str}X"),
             FnNode::Y => writeln!(f, "{indent_str}Y"),
             FnNode::T => writeln!(f, "{indent_str}T"),
+            FnNode::R => writeln!(f, "{indent_str}R"),
+            FnNode::Theta => writeln!(f, "{indent_str}Theta"),
+            FnNode::Param(name, val) => writeln!(f, "{indent_str}Param({name} = {val})"),
+            FnNode::Var(idx) => writeln!(f, "{indent_str}Var({idx})"),
+            FnNode::Call(idx, args) => {
+                writeln!(f, "{indent_str}Call(h{idx})")?;
+                for arg in args {
+                    arg.fmt_with_indent(f, indent.saturating_add(1))?;
+                }
+                Ok(())
+            }
             FnNode::Random => writeln!(f, "{indent_str}Random"),
             FnNode::Boolean(val) => writeln!(f, "{indent_str}Boolean({val})"),
             FnNode::Number(val) => writeln!(f, "{indent_str}Number({val})"),
@@ -513,6 +521,14 @@ impl Display for FnNode {
             FnNode::X => write!(f, "x"),
             FnNode::Y => write!(f, "y"),
             FnNode::T => write!(f, "t"),
+            FnNode::R => write!(f, "r"),
+            FnNode::Theta => write!(f, "theta"),
+            FnNode::Param(name, _) => write!(f, "{name}"),
+            FnNode::Var(idx) => write!(f, "v{idx}"),
+            FnNode::Call(idx, args) => {
+                let args = args.iter().map(ToString::to_string).collect::<Vec<_>>();
+                write!(f, "h{idx}({})", args.join(", "))
+            }
             FnNode::Random => write!(f, "random"),
             FnNode::Boolean(val) => write!(f, "{val}"),
             FnNode::Number(val) => write!(f, "{val}"),
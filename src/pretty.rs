@@ -0,0 +1,303 @@
+// Human-readable shader output. Expressions that fit the width stay on one line, longer ones are
+// broken between their operands and indented a level per nesting. The splitting points come from
+// the backend's own output for the node, so every shading language is laid out the same way.
+use std::fmt::Write;
+
+use crate::backend::ShaderBackend;
+use crate::cse::Shared;
+use crate::grammar::Origins;
+use crate::hoist::{arguments, Helper};
+use crate::node::FnNode;
+
+/// Line width `Layout::Pretty` wraps at unless told otherwise
+pub const DEFAULT_WIDTH: usize = 100;
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Layout<'a> {
+    /// Each statement on one line, however long
+    Compact,
+    /// Channels in `color_r`, `color_g` and `color_b` helpers, expressions wrapped at `width`, and
+    /// a comment naming the rule behind each subtree in `origins`
+    Pretty { width: usize, origins: &'a Origins },
+}
+
+struct Line {
+    depth: usize,
+    text: String,
+    comment: Option<String>,
+}
+
+fn render(lines: &[Line]) -> Result<String, String> {
+    let mut out = String::new();
+    for line in lines {
+        write!(out, "{}{}", INDENT.repeat(line.depth), line.text).map_err(|e| e.to_string())?;
+        if let Some(comment) = &line.comment {
+            write!(out, " {comment}").map_err(|e| e.to_string())?;
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// A parameter name the backends print verbatim and that can't occur in generated code
+fn hole(idx: usize) -> FnNode {
+    FnNode::Param(format!("\u{1}{idx}\u{1}"), 0.0)
+}
+
+pub(crate) struct Printer<'a> {
+    pub backend: &'a dyn ShaderBackend,
+    pub width: usize,
+    pub origins: &'a Origins,
+}
+
+impl Printer<'_> {
+    // The backend's code for `node` around its children, or `None` for a leaf
+    fn pieces(&self, node: &FnNode) -> Result<Option<Vec<String>>, String> {
+        let shell = match node {
+            FnNode::Arithmetic(_, op, _) => FnNode::arithmetic(hole(0), *op, hole(1)),
+            FnNode::Compare(_, op, _) => FnNode::compare(hole(0), *op, hole(1)),
+            FnNode::Unary(op, _) => FnNode::unary(op.clone(), hole(0)),
+            FnNode::If(_, _, _) => FnNode::if_(hole(0), hole(1), hole(2)),
+            FnNode::Triple(_, _, _) => FnNode::triple(hole(0), hole(1), hole(2)),
+            FnNode::Call(idx, args) => FnNode::Call(*idx, (0..args.len()).map(hole).collect()),
+            _ => return Ok(None),
+        };
+        let mut rest = self.backend.compile_expr(&shell)?;
+        let mut pieces = Vec::new();
+        for idx in 0..node.children().len() {
+            let FnNode::Param(marker, _) = hole(idx) else {
+                return Ok(None);
+            };
+            let Some((before, after)) = rest.split_once(&marker) else {
+                return Ok(None);
+            };
+            pieces.push(before.to_string());
+            rest = after.to_string();
+        }
+        pieces.push(rest);
+        Ok(Some(pieces))
+    }
+
+    // `node` at `depth`, with `reserved` columns of the first line already taken. `inherited` is
+    // the nearest rule commented on above, which isn't repeated.
+    fn lines(
+        &self,
+        node: &FnNode,
+        depth: usize,
+        reserved: usize,
+        inherited: Option<&str>,
+    ) -> Result<Vec<Line>, String> {
+        let flat = self.backend.compile_expr(node)?;
+        let rule = self.origins.rule(node);
+        let comment = rule
+            .filter(|rule| Some(*rule) != inherited)
+            .map(|rule| format!("/* {rule} */"));
+        let column = INDENT
+            .len()
+            .saturating_mul(depth)
+            .saturating_add(reserved)
+            .saturating_add(flat.len());
+        let pieces = if column > self.width {
+            self.pieces(node)?
+        } else {
+            None
+        };
+        let Some(pieces) = pieces else {
+            return Ok(vec![Line {
+                depth,
+                text: flat,
+                comment,
+            }]);
+        };
+
+        let inherited = rule.or(inherited);
+        let mut pieces = pieces.into_iter();
+        let mut lines = vec![Line {
+            depth,
+            text: pieces.next().unwrap_or_default().trim_end().to_string(),
+            comment,
+        }];
+        for (idx, child) in node.children().into_iter().enumerate() {
+            let mut child_lines = self.lines(child, depth.saturating_add(1), 2, inherited)?;
+            let separator = if idx == 0 {
+                String::new()
+            } else {
+                pieces.next().unwrap_or_default().trim().to_string()
+            };
+            if separator == "," {
+                // Trails the previous operand
+                if let Some(last) = lines.last_mut() {
+                    last.text.push(',');
+                }
+            } else if separator.contains(['(', ')']) {
+                // Closes one operand and opens the next, as in `) ? (`
+                lines.push(Line {
+                    depth,
+                    text: separator,
+                    comment: None,
+                });
+            } else if let Some(first) = child_lines.first_mut() {
+                if !separator.is_empty() {
+                    first.text = format!("{separator} {}", first.text);
+                }
+            }
+            lines.append(&mut child_lines);
+        }
+        lines.push(Line {
+            depth,
+            text: pieces.next().unwrap_or_default().trim_start().to_string(),
+            comment: None,
+        });
+        Ok(lines)
+    }
+
+    // A whole statement: `prefix`, the expression, then `suffix`
+    fn statement(&self, prefix: &str, node: &FnNode, suffix: &str) -> Result<Vec<Line>, String> {
+        let reserved = prefix.len().saturating_add(suffix.len());
+        let mut lines = self.lines(node, 1, reserved, None)?;
+        if let Some(first) = lines.first_mut() {
+            first.text.insert_str(0, prefix);
+        }
+        if let Some(last) = lines.last_mut() {
+            last.text.push_str(suffix);
+        }
+        Ok(lines)
+    }
+
+    // A function computing `node` from `args`, returning a bool if `is_bool` is set
+    fn function(
+        &self,
+        name: &str,
+        args: &[(FnNode, bool)],
+        is_bool: bool,
+        node: &FnNode,
+    ) -> Result<String, String> {
+        let mut params = Vec::with_capacity(args.len());
+        for (arg, is_bool) in args {
+            params.push((self.backend.compile_expr(arg)?, *is_bool));
+        }
+        let params = params
+            .iter()
+            .map(|(param, is_bool)| (param.as_str(), *is_bool))
+            .collect::<Vec<_>>();
+        let body = render(&self.statement("return ", node, ";")?)?;
+        Ok(self.backend.function(name, &params, is_bool, &body))
+    }
+
+    /// The `{{helpers}}`, `{{lets}}` and `{{expr}}` of a shader computing `shared`, with the
+    /// subtrees hoisted out of it in `hoisted`. Without `split_channels` the colour stays one
+    /// expression.
+    pub(crate) fn shader(
+        &self,
+        shared: &Shared,
+        hoisted: &[Helper],
+        split_channels: bool,
+    ) -> Result<(String, String, String), String> {
+        let mut helpers = String::new();
+        for (idx, helper) in hoisted.iter().enumerate() {
+            let name = format!("h{idx}");
+            helpers.push_str(&self.function(
+                &name,
+                &helper.params,
+                helper.is_bool,
+                &helper.body,
+            )?);
+        }
+
+        let mut lets = Vec::new();
+        for (idx, binding) in shared.lets.iter().enumerate() {
+            let declaration = self.backend.declare(&format!("v{idx}"), binding.is_bool);
+            lets.extend(self.statement(&format!("{declaration} "), &binding.expr, ";")?);
+        }
+        let lets = render(&lets)?;
+
+        let (r, g, b) = match &shared.body {
+            FnNode::Triple(r, g, b) if split_channels => (r, g, b),
+            body => return Ok((helpers, lets, self.expr(body)?)),
+        };
+        let mut calls = Vec::new();
+        for (name, channel) in [("color_r", r), ("color_g", g), ("color_b", b)] {
+            let args = arguments(channel, shared);
+            helpers.push_str(&self.function(name, &args, false, channel)?);
+            let args = args
+                .iter()
+                .map(|(arg, _)| self.backend.compile_expr(arg))
+                .collect::<Result<Vec<_>, _>>()?;
+            calls.push(format!("{name}({})", args.join(", ")));
+        }
+        let [r, g, b]: [String; 3] = calls
+            .try_into()
+            .map_err(|_| "Expected a call per channel".to_string())?;
+        Ok((helpers, lets, self.backend.vec3(&r, &g, &b)))
+    }
+
+    // An expression spliced into the middle of a template line
+    fn expr(&self, node: &FnNode) -> Result<String, String> {
+        let lines = self.lines(node, 1, INDENT.len(), None)?;
+        Ok(render(&lines)?.trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{Glsl450, TimeMap, Wgsl};
+    use crate::bnf_parser::Parser;
+    use crate::hoist::Limits;
+    use crate::template::Template;
+
+    #[test]
+    fn test_pretty_shader() {
+        let grammar = Parser::new(
+            "param speed = 0.5 in [0, 2];\nE | vec3(C, C, speed) ;\nC | x | y | t | r | sin(C) | mul(C, C) | add(C, C) | sub(C, C) ;",
+        )
+        .parse()
+        .expect("Parse should be successful");
+        let (func, origins) = grammar
+            .gen_traced(0, 8, 1)
+            .expect("Generation should succeed");
+        let layout = Layout::Pretty {
+            width: 60,
+            origins: &origins,
+        };
+
+        let template = Template::parse(crate::export::FRAGMENT_SHADER_TEMPLATE)
+            .expect("Template should parse");
+        let source = func
+            .clone()
+            .compile_fs(
+                &template,
+                "    let speed = 1.0;",
+                &Wgsl,
+                layout,
+                Limits::default(),
+                TimeMap::default(),
+            )
+            .expect("WGSL should compile");
+        assert!(source.contains("fn color_b(speed: f32) -> f32 {\n    return speed;\n}"));
+        if let Err(e) = naga::front::wgsl::parse_str(&source) {
+            panic!("{}", e.emit_to_string(&source));
+        }
+
+        let glsl = Printer {
+            backend: &Glsl450,
+            width: 60,
+            origins: &origins,
+        };
+        let shared = func.share().expect("Sharing should succeed");
+        let (helpers, lets, expr) = glsl.shader(&shared, &[], true).expect("GLSL should print");
+        assert!(helpers.contains("float color_r("));
+        assert!(
+            format!("{lets}{helpers}").contains("/* C */"),
+            "{lets}{helpers}"
+        );
+        assert!(expr.starts_with("vec3(color_r("));
+        // Signatures aren't wrapped, only the expressions
+        for line in helpers.lines().filter(|line| !line.starts_with("float ")) {
+            let code = line.split(" /*").next().unwrap_or_default();
+            assert!(code.len() <= 60, "{line}");
+        }
+    }
+}
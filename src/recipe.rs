@@ -0,0 +1,476 @@
+// Self-contained descriptions of a piece, saved as text that the CLI, the native viewer and the
+// web app all read and write: the grammar, how the function was drawn from it, the function
+// itself, and how to show it. Drawing goes by the stored function rather than generating it
+// again, so changes to generation can't alter a piece. Changes to the recipe fields, the grammar
+// language or the printed form of functions bump `VERSION` and add a step to `MIGRATIONS` that
+// rewrites older recipes, so pieces saved before still draw the same.
+//
+//     shaderand-recipe 1
+//     entry E
+//     seed 42
+//     ...
+//     expression (sin(x), mul(y, speed), t)
+//     grammar <<END
+//     param speed = 0.5 in [0, 2];
+//     E | vec3(C, C, C) ;
+//     ...
+//     END
+//
+// Each line is a field name and its value, except that `<<TAG` makes the value every line up to
+// one of just TAG. Blank lines and lines starting with `#` are skipped.
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::backend::TimeMap;
+use crate::bnf_parser::Parser;
+use crate::expr_parser;
+use crate::grammar::Grammar;
+use crate::interval::{self, Interval};
+use crate::node::{ColorMap, FnNode};
+use crate::template::Template;
+
+/// The first line of a recipe, followed by its version
+const HEADER: &str = "shaderand-recipe";
+
+/// The version recipes are written as
+pub const VERSION: u32 = 1;
+
+// Upgrades recipe fields from version `idx + 1` to the next, version 1 being the first
+type Migration = fn(&mut Fields) -> Result<(), String>;
+
+const MIGRATIONS: [Migration; VERSION as usize - 1] = [];
+
+/// The shader template the viewers show a piece in
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum TemplateChoice {
+    /// Each viewer's own
+    #[default]
+    Stock,
+    /// GLSL 450 source, for the native viewer
+    Glsl(String),
+    /// WGSL source, for the web app
+    Wgsl(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipe {
+    /// Source of the grammar the function was generated from
+    pub grammar: String,
+    /// The rule generation started from
+    pub entry: String,
+    pub seed: u64,
+    /// How deep generation could nest rules
+    pub depth: usize,
+    /// The function as generated, before params are bound or it is normalized
+    pub func: FnNode,
+    /// Every grammar param with its value
+    pub params: Vec<(String, f32)>,
+    /// Whether each colour channel is rescaled to fill the visible range for t in `t_range`
+    pub normalize: bool,
+    pub t_range: Interval,
+    /// Seconds the viewers take to ease t across `t_range` and back, or none to sweep it with
+    /// `tan(time)`
+    pub loop_period: Option<f32>,
+    pub color_map: ColorMap,
+    pub template: TemplateChoice,
+    /// Size of CPU renders
+    pub width: u32,
+    pub height: u32,
+    /// The t CPU renders of a still draw
+    pub time: f32,
+}
+
+impl Recipe {
+    pub fn load_grammar(&self) -> Result<Grammar, String> {
+        Parser::new(&self.grammar)
+            .parse()
+            .map_err(|e| format!("Error parsing BNF: {e:?}"))
+    }
+
+    /// Index of the entry rule in `grammar`
+    pub fn entry_rule(&self, grammar: &Grammar) -> Result<usize, String> {
+        grammar
+            .symbols
+            .iter()
+            .position(|symbol| *symbol == self.entry)
+            .ok_or(format!("No rule {} in the grammar", self.entry))
+    }
+
+    /// The function as drawn, with its params bound and normalized if it is
+    pub fn function(&self) -> Result<FnNode, String> {
+        self.normalized().map(|(func, _)| func)
+    }
+
+    /// `function`, along with the channels normalizing found constant and left flat
+    pub fn normalized(&self) -> Result<(FnNode, Vec<(char, f32)>), String> {
+        let mut func = self.func.clone();
+        for (name, value) in &self.params {
+            func.bind_param(name, *value);
+        }
+        if !self.normalize {
+            return Ok((func, Vec::new()));
+        }
+        func.optimize()?;
+        let ranges = func.normalize(self.t_range, self.color_map)?;
+        Ok((func, interval::constant_channels(&ranges)))
+    }
+
+    /// How the viewers turn their running time into t
+    pub fn time_map(&self) -> TimeMap {
+        match self.loop_period {
+            Some(period) => TimeMap::Loop {
+                period,
+                range: self.t_range,
+            },
+            None => TimeMap::Tan,
+        }
+    }
+
+    /// Checks the recipe describes something that can be drawn
+    pub fn validate(&self) -> Result<(), String> {
+        let grammar = self.load_grammar()?;
+        self.entry_rule(&grammar)?;
+        if let Some((name, _)) = self.params.iter().find(|(n, _)| grammar.param(n).is_none()) {
+            return Err(format!("Unknown param in recipe: {name}"));
+        }
+        if !matches!(self.func, FnNode::Triple(..)) {
+            return Err(format!("Recipe expression is not a colour: {}", self.func));
+        }
+        if self.depth == 0 || self.width == 0 || self.height == 0 {
+            return Err("Recipe depth and size must be at least 1".to_string());
+        }
+        if self.t_range.lo > self.t_range.hi {
+            return Err(format!("Invalid recipe t range {}", self.t_range));
+        }
+        if let Some(period) = self.loop_period.filter(|p| !(p.is_finite() && *p > 0.0)) {
+            return Err(format!("Recipe loop must be above 0, not {period}"));
+        }
+        if let TemplateChoice::Glsl(source) | TemplateChoice::Wgsl(source) = &self.template {
+            Template::parse(source)?;
+        }
+        Ok(())
+    }
+
+    // Builds a recipe from the fields of `version`, first running the `migrations` after it to
+    // bring them up to the version the last one leads to
+    fn from_fields(
+        version: u32,
+        fields: Vec<(String, String)>,
+        migrations: &[Migration],
+    ) -> Result<Self, String> {
+        let latest = migrations.len().saturating_add(1);
+        let from = usize::try_from(version).map_err(|e| e.to_string())?;
+        if !(1..=latest).contains(&from) {
+            return Err(format!(
+                "Recipe version {version} isn't one this reads, which is 1 to {latest}"
+            ));
+        }
+        let mut fields = Fields(fields);
+        for migrate in migrations.iter().skip(from.saturating_sub(1)) {
+            migrate(&mut fields)?;
+        }
+
+        let grammar: String = fields.required("grammar")?;
+        let params = Parser::new(&grammar)
+            .parse()
+            .map_err(|e| format!("Error parsing BNF: {e:?}"))?
+            .params;
+        let func = expr_parser::parse(&fields.required::<String>("expression")?, &params)?;
+        let (lo, hi) = pair(&fields.required::<String>("t-range")?, ',')?;
+        let (width, height) = pair(&fields.required::<String>("size")?, 'x')?;
+        let template = match fields.required::<String>("template")?.as_str() {
+            "stock" => TemplateChoice::Stock,
+            "glsl" => TemplateChoice::Glsl(fields.required("template-source")?),
+            "wgsl" => TemplateChoice::Wgsl(fields.required("template-source")?),
+            other => return Err(format!("Unknown recipe template: {other}")),
+        };
+        let recipe = Recipe {
+            grammar,
+            entry: fields.required("entry")?,
+            seed: fields.required("seed")?,
+            depth: fields.required("depth")?,
+            func,
+            params: parse_params(&fields.required::<String>("params")?)?,
+            normalize: fields.required("normalize")?,
+            t_range: Interval::new(lo, hi),
+            loop_period: fields.field("loop")?,
+            color_map: fields.required("color-map")?,
+            template,
+            width,
+            height,
+            time: fields.required("time")?,
+        };
+        if let Some((name, _)) = fields.0.first() {
+            return Err(format!("Unknown recipe field: {name}"));
+        }
+        recipe.validate()?;
+        Ok(recipe)
+    }
+}
+
+impl FromStr for Recipe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, fields) = split_fields(s)?;
+        Recipe::from_fields(version, fields, &MIGRATIONS)
+    }
+}
+
+impl Display for Recipe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params = self
+            .params
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>();
+        writeln!(f, "{HEADER} {VERSION}")?;
+        writeln!(f, "entry {}", self.entry)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "depth {}", self.depth)?;
+        writeln!(f, "params {}", params.join(", "))?;
+        writeln!(f, "normalize {}", self.normalize)?;
+        writeln!(f, "t-range {},{}", self.t_range.lo, self.t_range.hi)?;
+        if let Some(period) = self.loop_period {
+            writeln!(f, "loop {period}")?;
+        }
+        writeln!(f, "color-map {}", self.color_map)?;
+        writeln!(f, "size {}x{}", self.width, self.height)?;
+        writeln!(f, "time {}", self.time)?;
+        match &self.template {
+            TemplateChoice::Stock => writeln!(f, "template stock")?,
+            TemplateChoice::Glsl(source) | TemplateChoice::Wgsl(source) => {
+                let language = match self.template {
+                    TemplateChoice::Glsl(_) => "glsl",
+                    _ => "wgsl",
+                };
+                writeln!(f, "template {language}")?;
+                write_block(f, "template-source", source)?;
+            }
+        }
+        writeln!(f, "expression {}", self.func)?;
+        write_block(f, "grammar", &self.grammar)
+    }
+}
+
+// `name <<TAG`, the lines of `text` and then TAG, which `text` has no line of
+fn write_block(f: &mut std::fmt::Formatter<'_>, name: &str, text: &str) -> std::fmt::Result {
+    let mut tag = "END".to_string();
+    let mut count = 0u32;
+    while text.lines().any(|line| line == tag) {
+        count = count.saturating_add(1);
+        tag = format!("END{count}");
+    }
+    writeln!(f, "{name} <<{tag}")?;
+    for line in text.lines() {
+        writeln!(f, "{line}")?;
+    }
+    writeln!(f, "{tag}")
+}
+
+// The version and the fields of a recipe's text, block values keeping a newline after each line
+fn split_fields(text: &str) -> Result<(u32, Vec<(String, String)>), String> {
+    let mut lines = text.lines();
+    let version = lines
+        .next()
+        .and_then(|line| line.trim().strip_prefix(HEADER))
+        .ok_or(format!(
+            "Not a recipe, which starts with {HEADER} and its version"
+        ))?
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid recipe version: {e}"))?;
+    let mut fields: Vec<(String, String)> = Vec::new();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        let value = match value.trim().strip_prefix("<<") {
+            Some(tag) => {
+                let mut block = String::new();
+                loop {
+                    let line = lines
+                        .next()
+                        .ok_or(format!("Recipe ends inside {name}, before {tag}"))?;
+                    if line == tag {
+                        break;
+                    }
+                    block.push_str(line);
+                    block.push('\n');
+                }
+                block
+            }
+            None => value.trim().to_string(),
+        };
+        if fields.iter().any(|(field, _)| field == name) {
+            return Err(format!("Recipe has {name} twice"));
+        }
+        fields.push((name.to_string(), value));
+    }
+    Ok((version, fields))
+}
+
+// Fields not yet taken from a recipe
+struct Fields(Vec<(String, String)>);
+
+impl Fields {
+    fn take(&mut self, name: &str) -> Option<String> {
+        let idx = self.0.iter().position(|(field, _)| field == name)?;
+        Some(self.0.remove(idx).1)
+    }
+
+    fn field<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, String>
+    where
+        T::Err: Display,
+    {
+        self.take(name)
+            .map(|val| {
+                val.parse()
+                    .map_err(|e| format!("Invalid recipe {name}: {e}"))
+            })
+            .transpose()
+    }
+
+    fn required<T: FromStr>(&mut self, name: &str) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        self.field(name)?.ok_or(format!("Recipe has no {name}"))
+    }
+}
+
+// Two values separated by `separator`, as in `-1,1` or `1920x944`
+fn pair<T: FromStr>(text: &str, separator: char) -> Result<(T, T), String>
+where
+    T::Err: Display,
+{
+    let (first, second) = text.split_once(separator).ok_or(format!(
+        "Expected two values separated by {separator}: {text}"
+    ))?;
+    let parse = |val: &str| {
+        val.trim()
+            .parse::<T>()
+            .map_err(|e| format!("Invalid value {val}: {e}"))
+    };
+    Ok((parse(first)?, parse(second)?))
+}
+
+/// `name=value` pairs separated by commas
+pub fn parse_params(params: &str) -> Result<Vec<(String, f32)>, String> {
+    params
+        .split(',')
+        .filter(|param| !param.trim().is_empty())
+        .map(|param| {
+            let (name, value) = param
+                .split_once('=')
+                .ok_or(format!("Invalid param: {param}"))?;
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid value for param {name}: {e}"))?;
+            Ok((name.trim().to_string(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recipe() -> Recipe {
+        let grammar = format!(
+            "param speed = 0.5 in [0, 2];\n{}",
+            include_str!("../grammar.bnf").replace("| theta", "| mul(theta, speed)")
+        );
+        let func = Parser::new(&grammar)
+            .parse()
+            .expect("Parse should be successful")
+            .gen_seeded(0, 8, 7)
+            .expect("Generation should succeed");
+        Recipe {
+            grammar,
+            entry: "E".to_string(),
+            seed: 7,
+            depth: 8,
+            func,
+            params: vec![("speed".to_string(), 1.5)],
+            normalize: true,
+            t_range: Interval::new(-2.0, 0.5),
+            loop_period: Some(4.0),
+            color_map: ColorMap::Shader,
+            // With a line that would end the default block
+            template: TemplateChoice::Wgsl("// END\nEND\n{{expr}}\n".to_string()),
+            width: 640,
+            height: 480,
+            time: 0.25,
+        }
+    }
+
+    #[test]
+    fn test_recipe_round_trip() {
+        let recipe = recipe();
+        let text = recipe.to_string();
+        assert!(text.starts_with("shaderand-recipe 1\n"));
+        assert_eq!(text.parse(), Ok(recipe.clone()));
+        let commented = text
+            .replace("seed", "# A comment\n\nseed")
+            .replace('\n', "\r\n");
+        assert_eq!(commented.parse(), Ok(recipe.clone()));
+
+        let future = text.replace("shaderand-recipe 1", "shaderand-recipe 99");
+        assert!(future.parse::<Recipe>().is_err());
+        let unversioned = text.replace("shaderand-recipe 1", "shaderand-recipe 0");
+        assert!(unversioned.parse::<Recipe>().is_err());
+        assert!(format!("{text}extra 1\n").parse::<Recipe>().is_err());
+        assert!(text
+            .replace("entry E", "entry Q")
+            .parse::<Recipe>()
+            .is_err());
+        assert!(text
+            .replace("speed=1.5", "pace=1.5")
+            .parse::<Recipe>()
+            .is_err());
+        assert!(text
+            .replace("size 640x480", "size 0x480")
+            .parse::<Recipe>()
+            .is_err());
+        let unterminated = text
+            .trim_end()
+            .strip_suffix("END")
+            .expect("Text should end a block");
+        assert!(unterminated.parse::<Recipe>().is_err());
+    }
+
+    // A step to a version 2 that renamed `random-seed` to `seed`
+    fn rename_seed(fields: &mut Fields) -> Result<(), String> {
+        let seed = fields
+            .take("random-seed")
+            .ok_or("Recipe has no random-seed")?;
+        fields.0.push(("seed".to_string(), seed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() {
+        let recipe = recipe();
+        let (_, fields) = split_fields(&recipe.to_string()).expect("Recipe should split");
+        let old = fields
+            .iter()
+            .map(|(name, value)| match name.as_str() {
+                "seed" => ("random-seed".to_string(), value.clone()),
+                _ => (name.clone(), value.clone()),
+            })
+            .collect::<Vec<_>>();
+        let migrations: [Migration; 1] = [rename_seed];
+        assert_eq!(Recipe::from_fields(1, old, &migrations), Ok(recipe.clone()));
+        // Fields already at version 2 skip the step, which would fail on them
+        assert_eq!(
+            Recipe::from_fields(2, fields.clone(), &migrations),
+            Ok(recipe)
+        );
+        assert!(Recipe::from_fields(1, fields.clone(), &migrations).is_err());
+        assert!(Recipe::from_fields(3, fields, &migrations).is_err());
+    }
+}
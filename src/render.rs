@@ -0,0 +1,1270 @@
+// CPU rendering. `RenderOptions` says what to draw; the image is cut into square tiles that worker
+// threads take in row-major order, each with its own `Evaluator`, and finished bands of tiles are
+// handed back in order to the calling thread. Tiles carry the colour mapped channels as floats,
+// tone mapped into 8 or 16 bits unless a float format keeps them as they are. Nothing here writes
+// a file unless asked to: images come back as buffers for `save`, and only PNGs stream straight to
+// disk, never holding more than a few bands in memory. As every pixel depends on nothing but its
+// coordinates the output is the same for any number of threads.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+
+use image::{DynamicImage, Rgb32FImage, RgbImage, RgbaImage};
+
+use crate::interval::Interval;
+pub use crate::node::ColorMap;
+use crate::node::{Color, FnNode};
+use crate::vm::{Evaluator, Program};
+
+/// The part of the plane an image shows. The first row of pixels is at `y.lo`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: Interval,
+    pub y: Interval,
+}
+
+impl Default for Viewport {
+    // What the shaders show
+    fn default() -> Self {
+        Viewport {
+            x: Interval::new(-1.0, 1.0),
+            y: Interval::new(-1.0, 1.0),
+        }
+    }
+}
+
+impl ColorMap {
+    // A channel scaled so the range is 0 to 1, NaN going to 0
+    fn linear(self, val: f32) -> f32 {
+        let val = match self {
+            ColorMap::Signed => f32::midpoint(val, 1.0),
+            ColorMap::Shader => val + 0.5,
+            ColorMap::Unit => val,
+        };
+        if val.is_nan() {
+            0.0
+        } else {
+            val
+        }
+    }
+
+    fn rgb(self, color: &Color) -> [f32; 3] {
+        [
+            self.linear(color.r),
+            self.linear(color.g),
+            self.linear(color.b),
+        ]
+    }
+}
+
+/// How colour mapped channels are brought into 0 to 1 for 8 and 16-bit images. Each sample is
+/// mapped before being averaged into its pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneMap {
+    /// Anything outside 0 to 1 is clipped
+    #[default]
+    Clamp,
+    /// `x / (1 + x)`, which rolls highlights off rather than clipping them
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// Each channel stretched so its range over the image fills 0 to 1
+    AutoExposure,
+}
+
+impl ToneMap {
+    pub const ALL: [ToneMap; 4] = [
+        ToneMap::Clamp,
+        ToneMap::Reinhard,
+        ToneMap::Aces,
+        ToneMap::AutoExposure,
+    ];
+
+    // `val` from 0 to 1, `level` being the channel's range over the image
+    fn apply(self, val: f32, level: Interval) -> f32 {
+        // Large enough to saturate either curve without overflowing it
+        let positive = val.clamp(0.0, 1e6);
+        let mapped = match self {
+            ToneMap::Reinhard => positive / (1.0 + positive),
+            ToneMap::Aces => {
+                (positive * (2.51 * positive + 0.03)) / (positive * (2.43 * positive + 0.59) + 0.14)
+            }
+            ToneMap::AutoExposure if level.hi > level.lo => {
+                (val - level.lo) / (level.hi - level.lo)
+            }
+            ToneMap::Clamp | ToneMap::AutoExposure => val,
+        };
+        mapped.clamp(0.0, 1.0)
+    }
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::Aces),
+            "auto" => Ok(ToneMap::AutoExposure),
+            _ => Err(format!("Unknown tone map: {s}")),
+        }
+    }
+}
+
+impl Display for ToneMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ToneMap::Clamp => "clamp",
+            ToneMap::Reinhard => "reinhard",
+            ToneMap::Aces => "aces",
+            ToneMap::AutoExposure => "auto",
+        })
+    }
+}
+
+/// Bits per channel of PNG output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Depth {
+    #[default]
+    Eight,
+    Sixteen,
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn byte(unit: f32) -> u8 {
+    (unit * 255.0) as u8
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn word(unit: f32) -> u16 {
+    (unit * 65535.0) as u16
+}
+
+/// Where in each pixel the function is evaluated, the pixel's colour being the mean
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sampling {
+    /// Once, at the pixel's top left corner
+    #[default]
+    Single,
+    /// At the centres of an `n` by `n` grid
+    Grid { n: u32 },
+    /// Once somewhere in each cell of an `n` by `n` grid, the same for the same `seed`
+    Jittered { n: u32, seed: u64 },
+}
+
+impl Sampling {
+    fn per_side(self) -> u32 {
+        match self {
+            Sampling::Single => 1,
+            Sampling::Grid { n } | Sampling::Jittered { n, .. } => n,
+        }
+    }
+
+    // Offset of sample `idx` in pixel (`x`, `y`), from 0 to 1 either way
+    fn offset(self, x: u32, y: u32, idx: u32) -> (f32, f32) {
+        let n = self.per_side();
+        let (column, row) = (
+            idx.checked_rem(n).unwrap_or(0),
+            idx.checked_div(n).unwrap_or(0),
+        );
+        let (dx, dy) = match self {
+            Sampling::Single => return (0.0, 0.0),
+            Sampling::Grid { .. } => (0.5, 0.5),
+            Sampling::Jittered { seed, .. } => {
+                // Hashed rather than drawn in turn, so tiles and threads can't change it
+                let hash = [x, y, idx]
+                    .into_iter()
+                    .fold(mix(seed), |hash, val| mix(hash ^ u64::from(val)));
+                (unit_float(hash), unit_float(mix(hash)))
+            }
+        };
+        let n = n as f32;
+        ((column as f32 + dx) / n, (row as f32 + dy) / n)
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    // As printed: `single`, `grid N` or `jittered N SEED`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid sampling: {s}");
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let number = |idx: usize| -> Result<u64, String> {
+            words
+                .get(idx)
+                .and_then(|word| word.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let n = || u32::try_from(number(1)?).map_err(|_| invalid());
+        match words.as_slice() {
+            ["single"] => Ok(Sampling::Single),
+            ["grid", _] => Ok(Sampling::Grid { n: n()? }),
+            ["jittered", _, _] => Ok(Sampling::Jittered {
+                n: n()?,
+                seed: number(2)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Sampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sampling::Single => write!(f, "single"),
+            Sampling::Grid { n } => write!(f, "grid {n}"),
+            Sampling::Jittered { n, seed } => write!(f, "jittered {n} {seed}"),
+        }
+    }
+}
+
+// The splitmix64 finaliser
+fn mix(val: u64) -> u64 {
+    let val = val.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let val = (val ^ (val >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    let val = (val ^ (val >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    val ^ (val >> 31)
+}
+
+// The top 24 bits as a float in [0, 1)
+fn unit_float(hash: u64) -> f32 {
+    (hash >> 40) as f32 / (1u32 << 24) as f32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub t: f32,
+    pub viewport: Viewport,
+    pub color_map: ColorMap,
+    pub tone_map: ToneMap,
+    pub depth: Depth,
+    pub sampling: Sampling,
+    /// Only sample pixels more than once where they differ from a neighbour by more than this
+    /// in some channel, from 0 to 1 as the tone map has it
+    pub adaptive: Option<f32>,
+    /// Worker threads, or 0 for one per core
+    pub threads: usize,
+    /// Side of each tile in pixels
+    pub tile_size: u32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: 1920,
+            height: 944,
+            t: 0.0,
+            viewport: Viewport::default(),
+            color_map: ColorMap::default(),
+            tone_map: ToneMap::default(),
+            depth: Depth::default(),
+            sampling: Sampling::default(),
+            adaptive: None,
+            threads: 0,
+            tile_size: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Bmp,
+    Tiff,
+    /// EXR with 32-bit float channels
+    Exr,
+    /// Radiance RGBE
+    Hdr,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 7] = [
+        ImageFormat::Png,
+        ImageFormat::Jpeg,
+        ImageFormat::WebP,
+        ImageFormat::Bmp,
+        ImageFormat::Tiff,
+        ImageFormat::Exr,
+        ImageFormat::Hdr,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::WebP => "webp",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::Tiff => "tiff",
+            ImageFormat::Exr => "exr",
+            ImageFormat::Hdr => "hdr",
+        }
+    }
+
+    /// Whether the format stores floats, which keep channels as the colour map has them
+    pub fn is_float(self) -> bool {
+        matches!(self, ImageFormat::Exr | ImageFormat::Hdr)
+    }
+
+    /// Picks the format from a file name's extension
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or(format!(
+                "No file extension to pick an image format from: {}",
+                path.display()
+            ))?;
+        extension.parse()
+    }
+
+    fn encoding(self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::WebP => image::ImageFormat::WebP,
+            ImageFormat::Bmp => image::ImageFormat::Bmp,
+            ImageFormat::Tiff => image::ImageFormat::Tiff,
+            ImageFormat::Exr => image::ImageFormat::OpenExr,
+            ImageFormat::Hdr => image::ImageFormat::Hdr,
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
+            "webp" => Ok(ImageFormat::WebP),
+            "bmp" => Ok(ImageFormat::Bmp),
+            "tif" | "tiff" => Ok(ImageFormat::Tiff),
+            "exr" => Ok(ImageFormat::Exr),
+            "hdr" => Ok(ImageFormat::Hdr),
+            _ => Err(format!("Unknown image format: {s}")),
+        }
+    }
+}
+
+impl Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ImageFormat::Png => "PNG",
+            ImageFormat::Jpeg => "JPEG",
+            ImageFormat::WebP => "WebP",
+            ImageFormat::Bmp => "BMP",
+            ImageFormat::Tiff => "TIFF",
+            ImageFormat::Exr => "OpenEXR",
+            ImageFormat::Hdr => "Radiance HDR",
+        })
+    }
+}
+
+/// Writes `image` to `path` as `format`, whatever the path's extension
+pub fn save(image: &RgbImage, path: &Path, format: ImageFormat) -> Result<(), String> {
+    if format.is_float() {
+        return save_hdr(
+            &DynamicImage::ImageRgb8(image.clone()).to_rgb32f(),
+            path,
+            format,
+        );
+    }
+    image
+        .save_with_format(path, format.encoding())
+        .map_err(|e| e.to_string())
+}
+
+/// `save` for float images, which are tone mapped by clamping if `format` isn't a float one
+pub fn save_hdr(image: &Rgb32FImage, path: &Path, format: ImageFormat) -> Result<(), String> {
+    if format.is_float() {
+        image.save_with_format(path, format.encoding())
+    } else {
+        DynamicImage::ImageRgb32F(image.clone())
+            .to_rgb8()
+            .save_with_format(path, format.encoding())
+    }
+    .map_err(|e| e.to_string())
+}
+
+// How each sample is finished before it is averaged into its pixel
+#[derive(Debug, Clone, Copy)]
+enum Finish {
+    /// Tone mapped, with the range of each channel over the image for `ToneMap::AutoExposure`
+    Display([Interval; 3]),
+    /// As the colour map has it
+    Linear,
+}
+
+// One image's worth of samples: every t they are averaged over, and how each is finished
+#[derive(Debug, Clone, Copy)]
+struct Exposure<'a> {
+    times: &'a [f32],
+    finish: Finish,
+}
+
+// The tiles of one band of rows, as they arrive
+struct Band {
+    pixels: Vec<f32>,
+    missing: usize,
+}
+
+// What workers share: the next tile to take, how many bands have been written, and whether to stop
+struct Queue {
+    next: AtomicUsize,
+    written: Mutex<usize>,
+    advanced: Condvar,
+    stop: AtomicBool,
+}
+
+impl Queue {
+    fn halt(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.advanced.notify_all();
+    }
+}
+
+impl RenderOptions {
+    fn threads(&self) -> usize {
+        if self.threads == 0 {
+            std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+        } else {
+            self.threads
+        }
+    }
+
+    fn columns(&self) -> usize {
+        self.width.div_ceil(self.tile_size) as usize
+    }
+
+    fn bands(&self) -> usize {
+        self.height.div_ceil(self.tile_size) as usize
+    }
+
+    // Where tile `idx` starts, and its size
+    fn tile(&self, idx: usize) -> Option<(u32, u32, u32, u32)> {
+        let columns = self.columns();
+        let column = u32::try_from(idx.checked_rem(columns)?).ok()?;
+        let band = u32::try_from(idx.checked_div(columns)?).ok()?;
+        let x = column.checked_mul(self.tile_size)?;
+        let y = band.checked_mul(self.tile_size)?;
+        let width = self.tile_size.min(self.width.checked_sub(x)?);
+        let height = self.tile_size.min(self.height.checked_sub(y)?);
+        Some((x, y, width, height))
+    }
+
+    // The point pixel (`x`, `y`) is at, offset by `dx`, `dy` pixels
+    fn point(&self, x: u32, y: u32, dx: f32, dy: f32) -> (f32, f32) {
+        let Viewport { x: across, y: down } = self.viewport;
+        (
+            across.lo + ((x as f32 + dx) / self.width as f32) * (across.hi - across.lo),
+            down.lo + ((y as f32 + dy) / self.height as f32) * (down.hi - down.lo),
+        )
+    }
+
+    // The channels of one sample, finished as `finish` says
+    fn finish(&self, finish: Finish, color: &Color) -> [f32; 3] {
+        let mut rgb = self.color_map.rgb(color);
+        if let Finish::Display(levels) = finish {
+            for (val, level) in rgb.iter_mut().zip(levels) {
+                *val = self.tone_map.apply(*val, level);
+            }
+        }
+        rgb
+    }
+
+    // Evaluates every `(pixel, x, y)` point at each time of the exposure, adding its finished
+    // colours into the pixel's entry in `sums`
+    fn accumulate(
+        &self,
+        evaluator: &mut Evaluator,
+        exposure: Exposure,
+        points: &[(usize, f32, f32)],
+        sums: &mut [[f32; 3]],
+    ) -> Result<(), String> {
+        let mut colors = Vec::new();
+        for chunk in points.chunks(1024) {
+            let xs = chunk.iter().map(|(_, x, _)| *x).collect::<Vec<_>>();
+            let ys = chunk.iter().map(|(_, _, y)| *y).collect::<Vec<_>>();
+            for &t in exposure.times {
+                evaluator.eval_batch(&xs, &ys, t, &mut colors)?;
+                for ((pixel, _, _), color) in chunk.iter().zip(&colors) {
+                    let sum = sums.get_mut(*pixel).ok_or("Sample outside its tile")?;
+                    for (sum, val) in sum.iter_mut().zip(self.finish(exposure.finish, color)) {
+                        *sum += val;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Which pixels of the tile at (`x`, `y`) differ enough from a neighbour to be supersampled,
+    // going by one sample at each centre averaged over the exposure, with the colours of those
+    // that don't
+    fn contrast(
+        &self,
+        evaluator: &mut Evaluator,
+        exposure: Exposure,
+        (x, y, width, height): (u32, u32, u32, u32),
+        threshold: f32,
+    ) -> Result<Vec<Option<[f32; 3]>>, String> {
+        // Neighbours in other tiles count too, so the result doesn't depend on the tiling
+        let (left, top) = (x.saturating_sub(1), y.saturating_sub(1));
+        let right = x.saturating_add(width).saturating_add(1).min(self.width);
+        let bottom = y.saturating_add(height).saturating_add(1).min(self.height);
+        let stride = right.saturating_sub(left) as usize;
+        let mut points = Vec::new();
+        for py in top..bottom {
+            for px in left..right {
+                let (nx, ny) = self.point(px, py, 0.5, 0.5);
+                points.push((points.len(), nx, ny));
+            }
+        }
+        let mut centres = vec![[0.0; 3]; points.len()];
+        self.accumulate(evaluator, exposure, &points, &mut centres)?;
+        let exposures = exposure.times.len() as f32;
+        for centre in &mut centres {
+            *centre = centre.map(|val| val / exposures);
+        }
+
+        let at = |px: u32, py: u32| {
+            let idx = (py.checked_sub(top)? as usize)
+                .checked_mul(stride)?
+                .checked_add(px.checked_sub(left)? as usize)?;
+            centres.get(idx).copied()
+        };
+        let mut pixels = Vec::new();
+        for py in y..y.saturating_add(height) {
+            for px in x..x.saturating_add(width) {
+                let centre = at(px, py).ok_or("Pixel outside its tile")?;
+                let neighbours = [
+                    px.checked_sub(1).and_then(|px| at(px, py)),
+                    at(px.saturating_add(1), py),
+                    py.checked_sub(1).and_then(|py| at(px, py)),
+                    at(px, py.saturating_add(1)),
+                ];
+                let flat = neighbours.into_iter().flatten().all(|neighbour| {
+                    centre
+                        .iter()
+                        .zip(neighbour)
+                        .all(|(a, b)| (a - b).abs() <= threshold)
+                });
+                pixels.push(flat.then_some(centre));
+            }
+        }
+        Ok(pixels)
+    }
+
+    // The RGB channels of tile `idx`, row by row
+    fn render_tile(
+        &self,
+        evaluator: &mut Evaluator,
+        exposure: Exposure,
+        idx: usize,
+    ) -> Result<Vec<f32>, String> {
+        let tile = self.tile(idx).ok_or("Tile out of range")?;
+        let (x, y, width, height) = tile;
+        let samples = self
+            .sampling
+            .per_side()
+            .saturating_mul(self.sampling.per_side());
+        let settled = match self.adaptive {
+            Some(threshold) if samples > 1 => {
+                self.contrast(evaluator, exposure, tile, threshold)?
+            }
+            _ => vec![None; (width as usize).saturating_mul(height as usize)],
+        };
+
+        let mut points = Vec::new();
+        let pixels = (y..y.saturating_add(height))
+            .flat_map(|py| (x..x.saturating_add(width)).map(move |px| (px, py)));
+        for (pixel, ((px, py), settled)) in pixels.zip(&settled).enumerate() {
+            if settled.is_none() {
+                for idx in 0..samples {
+                    let (dx, dy) = self.sampling.offset(px, py, idx);
+                    let (nx, ny) = self.point(px, py, dx, dy);
+                    points.push((pixel, nx, ny));
+                }
+            }
+        }
+        let mut sums = vec![[0.0; 3]; settled.len()];
+        self.accumulate(evaluator, exposure, &points, &mut sums)?;
+
+        let samples = samples as f32 * exposure.times.len() as f32;
+        Ok(sums
+            .into_iter()
+            .zip(settled)
+            .flat_map(|(sum, settled)| settled.unwrap_or(sum.map(|val| val / samples)))
+            .collect())
+    }
+
+    fn work(
+        &self,
+        program: &Program,
+        exposure: Exposure,
+        queue: &Queue,
+        window: usize,
+        sender: &mpsc::Sender<Result<(usize, Vec<f32>), String>>,
+    ) {
+        let mut evaluator = Evaluator::new(program.clone());
+        let total = self.columns().saturating_mul(self.bands());
+        loop {
+            let idx = queue.next.fetch_add(1, Ordering::Relaxed);
+            if idx >= total {
+                return;
+            }
+            // Wait rather than run too far ahead of the writer
+            let band = idx.checked_div(self.columns()).unwrap_or(0);
+            let Ok(mut written) = queue.written.lock() else {
+                return;
+            };
+            while band >= written.saturating_add(window) && !queue.stop.load(Ordering::Relaxed) {
+                let Ok(next) = queue.advanced.wait(written) else {
+                    return;
+                };
+                written = next;
+            }
+            drop(written);
+            if queue.stop.load(Ordering::Relaxed) {
+                return;
+            }
+            let tile = self.render_tile(&mut evaluator, exposure, idx);
+            if sender.send(tile.map(|pixels| (idx, pixels))).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Gathers tiles from the workers into bands and passes each on once it is complete
+    fn write_bands(
+        &self,
+        receiver: &mpsc::Receiver<Result<(usize, Vec<f32>), String>>,
+        queue: &Queue,
+        cancel: &AtomicBool,
+        progress: &mut impl FnMut(u32, u32),
+        rows: &mut impl FnMut(&[f32]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let columns = self.columns();
+        let row_len = (self.width as usize).saturating_mul(3);
+        let mut pending: HashMap<usize, Band> = HashMap::new();
+        let mut written = 0;
+        while written < self.bands() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err("Render cancelled".to_string());
+            }
+            let (idx, pixels) = receiver
+                .recv()
+                .map_err(|_| "Render workers stopped".to_string())??;
+            let (x, _, width, height) = self.tile(idx).ok_or("Tile out of range")?;
+            let band = pending
+                .entry(idx.checked_div(columns).unwrap_or(0))
+                .or_insert_with(|| Band {
+                    pixels: vec![0.0; row_len.saturating_mul(height as usize)],
+                    missing: columns,
+                });
+            let start = (x as usize).saturating_mul(3);
+            let tile_len = (width as usize).saturating_mul(3);
+            for (dst, src) in band.pixels.chunks_mut(row_len).zip(pixels.chunks(tile_len)) {
+                dst.get_mut(start..start.saturating_add(tile_len))
+                    .ok_or("Tile outside its band")?
+                    .copy_from_slice(src);
+            }
+            band.missing = band.missing.saturating_sub(1);
+
+            while pending.get(&written).is_some_and(|band| band.missing == 0) {
+                let band = pending.remove(&written).ok_or("Band went missing")?;
+                rows(&band.pixels)?;
+                written = written.saturating_add(1);
+                *queue.written.lock().map_err(|e| e.to_string())? = written;
+                queue.advanced.notify_all();
+                let done = u32::try_from(written)
+                    .map_or(self.height, |bands| bands.saturating_mul(self.tile_size));
+                progress(done.min(self.height), self.height);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders `func` across all the worker threads
+    pub fn render(&self, func: &FnNode) -> Result<RgbImage, String> {
+        let program = func.compile_program()?;
+        self.render_image(&program, &AtomicBool::new(false), |_, _| {})
+    }
+
+    /// `render` with an opaque alpha channel
+    pub fn render_rgba(&self, func: &FnNode) -> Result<RgbaImage, String> {
+        Ok(DynamicImage::ImageRgb8(self.render(func)?).to_rgba8())
+    }
+
+    /// Renders `program` into an image, reporting progress as `render_rows` does
+    pub fn render_image(
+        &self,
+        program: &Program,
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<RgbImage, String> {
+        self.render_exposure(program, &[self.t], cancel, progress)
+    }
+
+    /// `render_image` with every pixel averaged over each t in `times` rather than drawn at `t`,
+    /// for motion blur
+    pub fn render_exposure(
+        &self,
+        program: &Program,
+        times: &[f32],
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<RgbImage, String> {
+        let finish = self.display(program, times, cancel)?;
+        let mut pixels = Vec::new();
+        self.render_bands(
+            program,
+            Exposure { times, finish },
+            cancel,
+            progress,
+            |rows| {
+                pixels.extend(rows.iter().copied().map(byte));
+                Ok(())
+            },
+        )?;
+        RgbImage::from_raw(self.width, self.height, pixels)
+            .ok_or("Rendered the wrong number of pixels".to_string())
+    }
+
+    /// `render_exposure` with the channels as the colour map has them, neither tone mapped nor
+    /// clamped
+    pub fn render_hdr(
+        &self,
+        program: &Program,
+        times: &[f32],
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<Rgb32FImage, String> {
+        let exposure = Exposure {
+            times,
+            finish: Finish::Linear,
+        };
+        let mut pixels = Vec::new();
+        self.render_bands(program, exposure, cancel, progress, |rows| {
+            pixels.extend_from_slice(rows);
+            Ok(())
+        })?;
+        Rgb32FImage::from_raw(self.width, self.height, pixels)
+            .ok_or("Rendered the wrong number of pixels".to_string())
+    }
+
+    /// Renders `program` across all the worker threads, passing each band of up to `tile_size`
+    /// rows of RGB bytes to `rows` in order and then reporting the rows done so far and in total
+    /// to `progress`. Setting `cancel` stops the render with an error.
+    pub fn render_rows(
+        &self,
+        program: &Program,
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+        mut rows: impl FnMut(&[u8]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let times = [self.t];
+        let finish = self.display(program, &times, cancel)?;
+        let exposure = Exposure {
+            times: &times,
+            finish,
+        };
+        let mut bytes = Vec::new();
+        self.render_bands(program, exposure, cancel, progress, |band| {
+            bytes.clear();
+            bytes.extend(band.iter().copied().map(byte));
+            rows(&bytes)
+        })
+    }
+
+    /// Renders `program` averaged over `times` into a file at `path` as `format`. PNGs stream
+    /// straight to disk at `depth` bits per channel with each keyword and text pair of `text` in
+    /// a text chunk, and float formats keep the channels as `render_hdr` does.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_file(
+        &self,
+        program: &Program,
+        times: &[f32],
+        path: &Path,
+        format: ImageFormat,
+        text: &[(String, String)],
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<(), String> {
+        if format == ImageFormat::Png {
+            self.stream_png(program, times, path, text, cancel, progress)
+        } else if format.is_float() {
+            let image = self.render_hdr(program, times, cancel, progress)?;
+            save_hdr(&image, path, format)
+        } else {
+            let image = self.render_exposure(program, times, cancel, progress)?;
+            save(&image, path, format)
+        }
+    }
+
+    /// Renders `program` straight into a PNG at `path`, never holding more than a few bands of
+    /// the image in memory
+    pub fn render_png(
+        &self,
+        program: &Program,
+        path: &Path,
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<(), String> {
+        self.stream_png(program, &[self.t], path, &[], cancel, progress)
+    }
+
+    fn stream_png(
+        &self,
+        program: &Program,
+        times: &[f32],
+        path: &Path,
+        text: &[(String, String)],
+        cancel: &AtomicBool,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<(), String> {
+        let finish = self.display(program, times, cancel)?;
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(match self.depth {
+            Depth::Eight => png::BitDepth::Eight,
+            Depth::Sixteen => png::BitDepth::Sixteen,
+        });
+        // tEXt only holds Latin-1, so anything else goes in UTF-8 iTXt chunks
+        for (keyword, text) in text {
+            let chunk = if text.is_ascii() {
+                encoder.add_text_chunk(keyword.clone(), text.clone())
+            } else {
+                encoder.add_itxt_chunk(keyword.clone(), text.clone())
+            };
+            chunk.map_err(|e| e.to_string())?;
+        }
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        let mut stream = writer.stream_writer().map_err(|e| e.to_string())?;
+        let mut bytes = Vec::new();
+        self.render_bands(
+            program,
+            Exposure { times, finish },
+            cancel,
+            progress,
+            |band| {
+                bytes.clear();
+                match self.depth {
+                    Depth::Eight => bytes.extend(band.iter().copied().map(byte)),
+                    // PNG samples are big-endian
+                    Depth::Sixteen => {
+                        bytes.extend(band.iter().flat_map(|val| word(*val).to_be_bytes()));
+                    }
+                }
+                stream.write_all(&bytes).map_err(|e| e.to_string())
+            },
+        )?;
+        stream.finish().map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())
+    }
+
+    // How samples are finished for 8 and 16-bit images. `ToneMap::AutoExposure` needs the range
+    // of each channel first, which it takes from a preview no more than 128 pixels across.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn display(
+        &self,
+        program: &Program,
+        times: &[f32],
+        cancel: &AtomicBool,
+    ) -> Result<Finish, String> {
+        if self.tone_map != ToneMap::AutoExposure {
+            return Ok(Finish::Display([Interval::new(0.0, 1.0); 3]));
+        }
+        let scale = (128.0 / self.width.max(self.height) as f32).min(1.0);
+        // Between 1 and the full size, so the casts are exact
+        let preview = RenderOptions {
+            width: (self.width as f32 * scale).ceil().max(1.0) as u32,
+            height: (self.height as f32 * scale).ceil().max(1.0) as u32,
+            sampling: Sampling::Single,
+            adaptive: None,
+            ..*self
+        };
+        let exposure = Exposure {
+            times,
+            finish: Finish::Linear,
+        };
+        let mut levels = [Interval::new(f32::INFINITY, f32::NEG_INFINITY); 3];
+        preview.render_bands(
+            program,
+            exposure,
+            cancel,
+            |_, _| {},
+            |band| {
+                for pixel in band.chunks(3) {
+                    for (level, val) in levels.iter_mut().zip(pixel) {
+                        if val.is_finite() {
+                            *level = Interval::new(level.lo.min(*val), level.hi.max(*val));
+                        }
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        Ok(Finish::Display(levels))
+    }
+
+    // Renders across all the worker threads, passing each band of finished channels to `rows`
+    fn render_bands(
+        &self,
+        program: &Program,
+        exposure: Exposure,
+        cancel: &AtomicBool,
+        mut progress: impl FnMut(u32, u32),
+        mut rows: impl FnMut(&[f32]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 || self.tile_size == 0 {
+            return Err("Image and tile sizes must be at least 1".to_string());
+        }
+        if self.sampling.per_side() == 0 {
+            return Err("Supersampling needs at least 1 sample per side".to_string());
+        }
+        if exposure.times.is_empty() {
+            return Err("An exposure needs at least 1 time".to_string());
+        }
+        let threads = self.threads();
+        // Enough bands in flight to keep every thread busy while one is finished off
+        let window = threads.div_ceil(self.columns()).saturating_add(1);
+        let queue = Queue {
+            next: AtomicUsize::new(0),
+            written: Mutex::new(0),
+            advanced: Condvar::new(),
+            stop: AtomicBool::new(false),
+        };
+
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            for _ in 0..threads {
+                let sender = sender.clone();
+                let queue = &queue;
+                scope.spawn(move || self.work(program, exposure, queue, window, &sender));
+            }
+            drop(sender);
+
+            let result = self.write_bands(&receiver, &queue, cancel, &mut progress, &mut rows);
+            queue.halt();
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::node::{ArithmeticOp, CompareOp, UnaryOp};
+
+    fn node() -> FnNode {
+        FnNode::triple(
+            FnNode::unary(
+                UnaryOp::Sin,
+                FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::Number(9.0)),
+            ),
+            FnNode::if_(
+                FnNode::compare(FnNode::R, CompareOp::LessThan, FnNode::Number(0.5)),
+                FnNode::Y,
+                FnNode::Theta,
+            ),
+            FnNode::T,
+        )
+    }
+
+    #[test]
+    fn test_render_deterministic() {
+        let program = node()
+            .compile_program()
+            .expect("Compilation should succeed");
+        let options = RenderOptions {
+            width: 45,
+            height: 37,
+            t: 0.25,
+            viewport: Viewport {
+                x: Interval::new(-0.5, 2.0),
+                y: Interval::new(1.0, -1.0),
+            },
+            color_map: ColorMap::Shader,
+            threads: 1,
+            tile_size: 16,
+            ..RenderOptions::default()
+        };
+
+        let mut expected = Vec::new();
+        let mut evaluator = Evaluator::interpreted(program.clone());
+        for y in 0..options.height {
+            for x in 0..options.width {
+                let color = evaluator.eval(
+                    -0.5 + (x as f32 / options.width as f32) * 2.5,
+                    1.0 + (y as f32 / options.height as f32) * -2.0,
+                    options.t,
+                );
+                expected.extend(ColorMap::Shader.rgb(&color).map(byte));
+            }
+        }
+        for (threads, tile_size) in [(1, 16), (3, 16), (8, 7), (5, 64)] {
+            let options = RenderOptions {
+                threads,
+                tile_size,
+                ..options
+            };
+            let mut reported = Vec::new();
+            let image = options
+                .render_image(&program, &AtomicBool::new(false), |done, total| {
+                    reported.push((done, total));
+                })
+                .expect("Render should succeed");
+            assert!(
+                image.into_raw() == expected,
+                "{threads} threads, {tile_size}px tiles"
+            );
+            assert_eq!(reported.last(), Some(&(options.height, options.height)));
+        }
+
+        let cancelled = options.render_image(&program, &AtomicBool::new(true), |_, _| {});
+        assert!(cancelled.is_err());
+        let failed = options.render_rows(
+            &program,
+            &AtomicBool::new(false),
+            |_, _| {},
+            |_| Err("Disk full".to_string()),
+        );
+        assert_eq!(failed, Err("Disk full".to_string()));
+    }
+
+    #[test]
+    fn test_supersampling() {
+        // White left of x = 0 and black right of it
+        let step = FnNode::if_(
+            FnNode::compare(FnNode::X, CompareOp::LessThan, FnNode::Number(0.0)),
+            FnNode::Number(1.0),
+            FnNode::Number(-1.0),
+        );
+        let step = FnNode::triple(step.clone(), step.clone(), step);
+        let options = RenderOptions {
+            width: 3,
+            height: 1,
+            ..RenderOptions::default()
+        };
+        let row = |options: RenderOptions| {
+            let image = options.render(&step).expect("Render should succeed");
+            image.pixels().map(|pixel| pixel.0[0]).collect::<Vec<_>>()
+        };
+        assert_eq!(row(options), [255, 255, 0]);
+        let grid = RenderOptions {
+            sampling: Sampling::Grid { n: 4 },
+            ..options
+        };
+        assert_eq!(row(grid), [255, 127, 0]);
+        // Every pixel borders one of another colour
+        let adaptive = RenderOptions {
+            adaptive: Some(0.0),
+            ..grid
+        };
+        assert_eq!(row(adaptive), [255, 127, 0]);
+
+        let options = RenderOptions {
+            width: 20,
+            height: 11,
+            sampling: Sampling::Jittered { n: 3, seed: 7 },
+            adaptive: Some(0.1),
+            ..RenderOptions::default()
+        };
+        let image = options.render(&node()).expect("Render should succeed");
+        for (threads, tile_size) in [(1, 3), (4, 5), (2, 64)] {
+            let tiled = RenderOptions {
+                threads,
+                tile_size,
+                ..options
+            };
+            assert!(tiled.render(&node()).expect("Render should succeed") == image);
+        }
+        let reseeded = RenderOptions {
+            sampling: Sampling::Jittered { n: 3, seed: 8 },
+            ..options
+        };
+        assert!(reseeded.render(&node()).expect("Render should succeed") != image);
+        // Nothing differs by more than everything, leaving one sample at each centre
+        let flat = RenderOptions {
+            adaptive: Some(1.0),
+            ..options
+        };
+        let centres = RenderOptions {
+            sampling: Sampling::Grid { n: 1 },
+            adaptive: None,
+            ..options
+        };
+        assert!(flat.render(&node()).ok() == centres.render(&node()).ok());
+
+        for sampling in [Sampling::Single, grid.sampling, options.sampling] {
+            assert_eq!(sampling.to_string().parse(), Ok(sampling));
+        }
+        assert!("grid".parse::<Sampling>().is_err());
+    }
+
+    #[test]
+    fn test_normalized_render() {
+        let abs = |node| FnNode::unary(UnaryOp::Abs, node);
+        let func = FnNode::triple(
+            FnNode::arithmetic(abs(FnNode::X), ArithmeticOp::Mul, FnNode::Number(0.25)),
+            abs(FnNode::Y),
+            FnNode::arithmetic(abs(FnNode::X), ArithmeticOp::Add, FnNode::Number(3.0)),
+        );
+        for color_map in [ColorMap::Signed, ColorMap::Shader, ColorMap::Unit] {
+            let mut func = func.clone();
+            func.normalize(Interval::new(-1.0, 1.0), color_map)
+                .expect("Normalisation should succeed");
+            let options = RenderOptions {
+                width: 8,
+                height: 6,
+                color_map,
+                ..RenderOptions::default()
+            };
+            let image = options.render(&func).expect("Render should succeed");
+            for channel in 0..3 {
+                let values = image.pixels().filter_map(|pixel| pixel.0.get(channel));
+                assert_eq!(
+                    values.clone().min(),
+                    Some(&0),
+                    "{color_map} channel {channel}"
+                );
+                assert_eq!(values.max(), Some(&255), "{color_map} channel {channel}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_save_formats() {
+        let options = RenderOptions {
+            width: 12,
+            height: 9,
+            ..RenderOptions::default()
+        };
+        let image = options.render(&node()).expect("Render should succeed");
+        assert_eq!(
+            options
+                .render_rgba(&node())
+                .expect("Render should succeed")
+                .len(),
+            12 * 9 * 4
+        );
+        let dir = std::env::temp_dir();
+        for format in ImageFormat::ALL {
+            let path = dir.join(format!("shaderand-save-test.{}", format.extension()));
+            assert_eq!(ImageFormat::from_path(&path), Ok(format));
+            save(&image, &path, format).expect("Saving should succeed");
+            let loaded = image::open(&path).expect("Image should load").to_rgb8();
+            assert_eq!(loaded.dimensions(), image.dimensions(), "{format}");
+            // Radiance shares one exponent between the channels of a pixel
+            if !matches!(format, ImageFormat::Jpeg | ImageFormat::Hdr) {
+                assert!(loaded == image, "{format} should be lossless");
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+        assert!(ImageFormat::from_path(Path::new("image.gif")).is_err());
+    }
+
+    #[test]
+    fn test_high_dynamic_range() {
+        let unit = Interval::new(0.0, 1.0);
+        assert!((ToneMap::Clamp.apply(1.5, unit) - 1.0).abs() < 1e-6);
+        assert!((ToneMap::Reinhard.apply(1.0, unit) - 0.5).abs() < 1e-6);
+        assert!(ToneMap::Aces.apply(0.0, unit).abs() < 0.01);
+        assert!(ToneMap::Aces.apply(f32::INFINITY, unit) > 0.99);
+        assert!((ToneMap::AutoExposure.apply(3.0, Interval::new(2.0, 4.0)) - 0.5).abs() < 1e-6);
+        for map in ToneMap::ALL {
+            assert_eq!(map.to_string().parse(), Ok(map));
+        }
+
+        // From -2 to 2 across the image, well outside the colour map's 0 to 1
+        let wide = FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::Number(2.0));
+        let program = FnNode::triple(wide.clone(), FnNode::Y, wide)
+            .compile_program()
+            .expect("Compilation should succeed");
+        let options = RenderOptions {
+            width: 16,
+            height: 8,
+            color_map: ColorMap::Unit,
+            ..RenderOptions::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let hdr = options
+            .render_hdr(&program, &[0.0], &cancel, |_, _| {})
+            .expect("Render should succeed");
+        let (lo, hi) = hdr
+            .pixels()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), pixel| {
+                (lo.min(pixel.0[0]), hi.max(pixel.0[0]))
+            });
+        assert_eq!((lo, hi), (-2.0, 1.75));
+
+        let auto = RenderOptions {
+            tone_map: ToneMap::AutoExposure,
+            ..options
+        };
+        let image = auto
+            .render_image(&program, &cancel, |_, _| {})
+            .expect("Render should succeed");
+        let reds = image.pixels().map(|pixel| pixel.0[0]).collect::<Vec<_>>();
+        assert_eq!(reds.iter().min(), Some(&0));
+        assert_eq!(reds.iter().max(), Some(&255));
+
+        let dir = std::env::temp_dir();
+        let exr = dir.join("shaderand-hdr-test.exr");
+        options
+            .render_file(
+                &program,
+                &[0.0],
+                &exr,
+                ImageFormat::Exr,
+                &[],
+                &cancel,
+                |_, _| {},
+            )
+            .expect("Rendering should succeed");
+        let loaded = image::open(&exr).expect("EXR should load").to_rgb32f();
+        assert!(loaded == hdr);
+        let _ = std::fs::remove_file(&exr);
+
+        let deep = RenderOptions {
+            depth: Depth::Sixteen,
+            ..options
+        };
+        let png = dir.join("shaderand-hdr-test.png");
+        deep.render_file(
+            &program,
+            &[0.0],
+            &png,
+            ImageFormat::Png,
+            &[],
+            &cancel,
+            |_, _| {},
+        )
+        .expect("Rendering should succeed");
+        let DynamicImage::ImageRgb16(loaded) = image::open(&png).expect("PNG should load") else {
+            panic!("PNG should be 16-bit");
+        };
+        let shallow = options
+            .render_image(&program, &cancel, |_, _| {})
+            .expect("Render should succeed");
+        for (deep, shallow) in loaded.as_raw().iter().zip(shallow.as_raw()) {
+            assert!((deep >> 8).abs_diff(u16::from(*shallow)) <= 1);
+        }
+        let _ = std::fs::remove_file(&png);
+    }
+}
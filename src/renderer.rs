@@ -5,36 +5,22 @@ use wasm_bindgen::prelude::*;
 use web_sys::HtmlCanvasElement;
 use wgpu::util::DeviceExt;
 
+use crate::backend::Wgsl;
 use crate::bnf_parser::Parser;
-use crate::grammar::Grammar;
+use crate::export::{self, ExportFormat};
+use crate::grammar::{Grammar, Origins, Param, MAX_PARAMS};
+use crate::hoist::Limits;
+use crate::interval::Interval;
+use crate::node::{ColorMap, FnNode};
+use crate::pretty::{self, Layout};
+use crate::recipe::{Recipe, TemplateChoice};
+use crate::template::Template;
 
-// WGSL Fragment shader template (converted from GLSL)
-const FRAGMENT_SHADER_TEMPLATE: &str = r"
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-};
-
-struct Uniforms {
-    time: f32,
-    _padding: vec3<f32>, // Ensure 16-byte alignment
-};
-
-@group(0) @binding(0)
-var<uniform> uniforms: Uniforms;
-
-fn map_rgb(rgb: vec3<f32>) -> vec4<f32> {
-    return vec4<f32>(rgb + 0.5, 1.0);
-}
+/// How deep generated functions may nest rules
+const GEN_DEPTH: usize = 10;
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let x = in.tex_coords.x;
-    let y = in.tex_coords.y;
-    let t = tan(uniforms.time);
-    return map_rgb(%s);
-}
-";
+// Params are packed four to a vec4 in the uniform buffer (`array<vec4<f32>, 4>` in WGSL)
+const PARAM_VEC4S: usize = MAX_PARAMS.div_ceil(4);
 
 // WGSL Vertex shader (converted from GLSL)
 const VERTEX_SHADER_SOURCE: &str = r"
@@ -50,7 +36,10 @@ struct VertexOutput {
 
 struct Uniforms {
     time: f32,
-    _padding: vec3<f32>,
+    _padding0: f32,
+    _padding1: f32,
+    _padding2: f32,
+    params: array<vec4<f32>, 4>,
 };
 
 @group(0) @binding(0)
@@ -92,6 +81,23 @@ impl Vertex {
 struct Uniforms {
     time: f32,
     _padding: [f32; 3], // Ensure 16-byte alignment
+    params: [[f32; 4]; PARAM_VEC4S],
+}
+
+impl Uniforms {
+    fn new(time: f32, param_values: &[f32]) -> Self {
+        let mut params = [[0.0; 4]; PARAM_VEC4S];
+        for (slot, values) in params.iter_mut().zip(param_values.chunks(4)) {
+            for (lane, value) in slot.iter_mut().zip(values) {
+                *lane = *value;
+            }
+        }
+        Uniforms {
+            time,
+            _padding: [0.0; 3],
+            params,
+        }
+    }
 }
 
 struct WgpuState<'state> {
@@ -113,7 +119,12 @@ pub struct ShaderRenderer {
     canvas: HtmlCanvasElement,
     animation_frame_id: Rc<RefCell<Option<i32>>>,
     source: String,
-    grammar: String,
+    // What `source` was compiled from, kept for exports and recipe downloads. Its params are
+    // those it was compiled with, see `current_recipe` for the slider values.
+    recipe: Recipe,
+    template: Template,
+    params: Vec<Param>,
+    param_values: Vec<f32>,
     start_time: f64,
 }
 
@@ -131,14 +142,20 @@ impl ShaderRenderer {
         let canvas: HtmlCanvasElement = canvas.dyn_into::<HtmlCanvasElement>()?;
 
         // Generate initial fragment shader
-        let fragment_shader_source = generate_fragment_shader("")?;
+        let template = Template::parse(export::FRAGMENT_SHADER_TEMPLATE)?;
+        let mut recipe = new_recipe("")?;
+        let origins = generate(&mut recipe, &template)?;
+        let fragment_shader_source = compile_shader(&recipe, &[], &template, &origins)?;
 
         Ok(ShaderRenderer {
             state: None,
             canvas,
             animation_frame_id: Rc::new(RefCell::new(None)),
             source: fragment_shader_source,
-            grammar: Grammar::default().to_string(),
+            recipe,
+            template,
+            params: Vec::new(),
+            param_values: Vec::new(),
             start_time: Date::now() / 1000.0,
         })
     }
@@ -232,10 +249,7 @@ impl ShaderRenderer {
         });
 
         // Create uniform buffer
-        let uniforms = Uniforms {
-            time: 0.0,
-            _padding: [0.0; 3],
-        };
+        let uniforms = Uniforms::new(0.0, &self.param_values);
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Uniform Buffer"),
             contents: bytemuck::cast_slice(&[uniforms]),
@@ -367,10 +381,7 @@ impl ShaderRenderer {
 
         // Update uniform buffer
         let current_time = (Date::now() / 1000.0 - self.start_time) as f32;
-        let uniforms = Uniforms {
-            time: current_time,
-            _padding: [0.0; 3],
-        };
+        let uniforms = Uniforms::new(current_time, &self.param_values);
         state
             .queue
             .write_buffer(&state.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
@@ -496,11 +507,18 @@ impl ShaderRenderer {
 
     #[wasm_bindgen]
     pub fn reload_shader(&mut self) -> Result<(), JsValue> {
+        let origins =
+            generate(&mut self.recipe, &self.template).map_err(|e| JsValue::from_str(&e))?;
+        self.compile(&origins)
+    }
+
+    // Swaps in the shader for `self.recipe`, restarting rendering with it
+    fn compile(&mut self, origins: &Origins) -> Result<(), JsValue> {
         self.stop_rendering()?;
 
-        // Generate new fragment shader
         let fragment_shader_source =
-            generate_fragment_shader(&self.grammar).map_err(|e| JsValue::from_str(&e))?;
+            compile_shader(&self.recipe, &self.params, &self.template, origins)
+                .map_err(|e| JsValue::from_str(&e))?;
 
         web_sys::console::log_1(&format!("New shader: {fragment_shader_source}").into());
 
@@ -525,22 +543,95 @@ impl ShaderRenderer {
     #[wasm_bindgen]
     pub fn reload_grammar(&mut self, new_grammar: &str) -> Result<(), JsValue> {
         // Update the grammar and reload the shader
-        let grammar = Parser::new(new_grammar).parse();
-        match grammar {
-            Ok(g) => self.grammar = g.to_string(),
+        let recipe = new_recipe(new_grammar).inspect_err(|e| {
+            web_sys::console::error_1(&format!("{new_grammar} is not a valid grammar: {e}").into());
+        })?;
+        let params = recipe
+            .load_grammar()
+            .map_err(|e| JsValue::from_str(&e))?
+            .params;
+        self.param_values = params.iter().map(|p| p.default).collect();
+        self.params = params;
+        self.recipe = Recipe {
+            template: self.recipe.template.clone(),
+            loop_period: self.recipe.loop_period,
+            ..recipe
+        };
+        web_sys::console::log_1(&format!("New grammar: {}", self.recipe.grammar).into());
+        self.reload_shader()
+    }
+
+    /// Swaps in a user WGSL template, see `export::FRAGMENT_SHADER_TEMPLATE` for the uniforms and
+    /// entry point it has to keep
+    #[wasm_bindgen]
+    pub fn reload_template(&mut self, new_template: &str) -> Result<(), JsValue> {
+        let template = Template::parse(new_template).map_err(|e| JsValue::from_str(&e))?;
+        // Keep the old template if the new one doesn't fit the generated code
+        let previous = std::mem::replace(&mut self.template, template);
+        match self.reload_shader() {
+            Ok(()) => {
+                self.recipe.template = TemplateChoice::Wgsl(new_template.to_string());
+                Ok(())
+            }
             Err(e) => {
-                web_sys::console::error_1(
-                    &format!("{new_grammar} is not a valid grammar: {e:?}").into(),
-                );
-                return Err(JsValue::from_str(&format!(
-                    "Failed to parse grammar: {e:?}\nProvided Grammar: {new_grammar}",
-                )));
+                self.template = previous;
+                Err(e)
             }
         }
-        web_sys::console::log_1(&format!("New grammar: {}", self.grammar).into());
+    }
+
+    /// Loops t across the recipe's t range, [-1, 1] unless one was loaded, every `period`
+    /// seconds, or sweeps it with `tan` as before if `period` isn't a positive number
+    #[wasm_bindgen]
+    pub fn set_loop_period(&mut self, period: f32) -> Result<(), JsValue> {
+        self.recipe.loop_period = (period.is_finite() && period > 0.0).then_some(period);
         self.reload_shader()
     }
 
+    /// Shows what a recipe describes, with its grammar, function, params and template. Recipes
+    /// with a GLSL template, which is for the native viewer, are shown in the current one.
+    #[wasm_bindgen]
+    pub fn load_recipe(&mut self, text: &str) -> Result<(), JsValue> {
+        let recipe: Recipe = text.parse().map_err(|e: String| JsValue::from_str(&e))?;
+        let grammar = recipe.load_grammar().map_err(|e| JsValue::from_str(&e))?;
+        let rule = recipe
+            .entry_rule(&grammar)
+            .map_err(|e| JsValue::from_str(&e))?;
+        // Only for the shader panel's comments, so a function edited in the file just goes without
+        let origins = grammar
+            .gen_traced(rule, recipe.depth, recipe.seed)
+            .map(|(_, origins)| origins)
+            .unwrap_or_default();
+        let template = match &recipe.template {
+            TemplateChoice::Stock => Some(export::FRAGMENT_SHADER_TEMPLATE),
+            TemplateChoice::Wgsl(source) => Some(source.as_str()),
+            TemplateChoice::Glsl(_) => None,
+        };
+        if let Some(template) = template {
+            self.template = Template::parse(template).map_err(|e| JsValue::from_str(&e))?;
+        }
+        self.param_values = grammar
+            .params
+            .iter()
+            .map(|param| {
+                recipe
+                    .params
+                    .iter()
+                    .find(|(name, _)| *name == param.name)
+                    .map_or(param.default, |(_, value)| *value)
+            })
+            .collect();
+        self.params = grammar.params;
+        self.recipe = recipe;
+        self.compile(&origins)
+    }
+
+    /// The current piece as recipe text, with params at their slider values
+    #[wasm_bindgen]
+    pub fn get_recipe(&self) -> String {
+        self.current_recipe().to_string()
+    }
+
     #[wasm_bindgen]
     pub fn get_current_shader(&self) -> String {
         web_sys::console::log_1(
@@ -551,12 +642,60 @@ impl ShaderRenderer {
 
     #[wasm_bindgen]
     pub fn get_current_grammar(&self) -> String {
-        self.grammar.clone()
+        self.recipe.grammar.clone()
+    }
+
+    #[wasm_bindgen]
+    pub fn set_param(&mut self, name: &str, value: f32) -> Result<(), JsValue> {
+        let idx = self
+            .params
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown param: {name}")))?;
+        if let Some(slot) = self.param_values.get_mut(idx) {
+            *slot = value;
+        }
+        // Draw straight away so the change is visible even while paused
+        if self.state.is_some() {
+            self.render()?;
+        }
+        Ok(())
     }
 }
 
-#[wasm_bindgen]
-pub fn generate_fragment_shader(inp: &str) -> Result<String, String> {
+impl ShaderRenderer {
+    pub fn params(&self) -> impl Iterator<Item = (&Param, f32)> {
+        self.params.iter().zip(self.param_values.iter().copied())
+    }
+
+    /// The recipe for the current piece, with params at their slider values and the canvas size
+    pub fn current_recipe(&self) -> Recipe {
+        Recipe {
+            params: self
+                .params()
+                .map(|(param, value)| (param.name.clone(), value))
+                .collect(),
+            width: self.canvas.width().max(1),
+            height: self.canvas.height().max(1),
+            ..self.recipe.clone()
+        }
+    }
+
+    /// The current piece as a standalone shader, with params at their slider values
+    pub fn export(&self, format: ExportFormat) -> Result<Vec<u8>, String> {
+        let recipe = self.current_recipe();
+        export::export(
+            &recipe.function()?,
+            &self.params,
+            format,
+            Limits::default(),
+            recipe.time_map(),
+        )
+    }
+}
+
+// A recipe for `inp`, or the default grammar if it is empty, still to have a function generated
+fn new_recipe(inp: &str) -> Result<Recipe, String> {
     let grammar = if inp.is_empty() {
         // Use a default grammar if no input is provided
         Grammar::default()
@@ -566,12 +705,90 @@ pub fn generate_fragment_shader(inp: &str) -> Result<String, String> {
             .parse()
             .map_err(|e| format!("Failed to parse grammar: {e:?}\nGrammar:\n{inp}"))?
     };
+    Ok(Recipe {
+        entry: grammar
+            .symbols
+            .first()
+            .cloned()
+            .ok_or("The grammar has no rules")?,
+        grammar: grammar.to_string(),
+        seed: 0,
+        depth: GEN_DEPTH,
+        func: FnNode::Number(0.0),
+        params: grammar
+            .params
+            .iter()
+            .map(|param| (param.name.clone(), param.default))
+            .collect(),
+        normalize: false,
+        t_range: Interval::new(-1.0, 1.0),
+        loop_period: None,
+        // As the stock templates map colours
+        color_map: ColorMap::Shader,
+        template: TemplateChoice::Stock,
+        width: 1920,
+        height: 944,
+        time: 0.0,
+    })
+}
+
+// Generates a new function for `recipe` from a random seed, one that makes a valid shader of
+// `template`, returning the rule behind each subtree
+fn generate(recipe: &mut Recipe, template: &Template) -> Result<Origins, String> {
+    let grammar = recipe.load_grammar()?;
+    let rule = recipe.entry_rule(&grammar)?;
+    let time_map = recipe.time_map();
+    let shader = |func: &mut FnNode| {
+        export::wgsl_fragment_shader(
+            func,
+            &grammar.params,
+            template,
+            Layout::Compact,
+            Limits::default(),
+            time_map,
+        )
+    };
+    let validated = grammar.gen_validated(rule, recipe.depth, rand::random(), &Wgsl, &shader)?;
+    for e in &validated.rejected {
+        web_sys::console::warn_1(&format!("Regenerating: {e}").into());
+    }
+    recipe.func = validated.func;
+    recipe.seed = validated.seed;
+    Ok(validated.origins)
+}
 
-    let mut func = grammar
-        .gen_from_rule(0, 10)
-        .ok_or("Failed to generate function".to_string())?;
+// The WGSL `recipe` compiles to, pretty printed for the shader panel
+fn compile_shader(
+    recipe: &Recipe,
+    params: &[Param],
+    template: &Template,
+    origins: &Origins,
+) -> Result<String, String> {
+    let mut func = if recipe.normalize {
+        recipe.function()?
+    } else {
+        recipe.func.clone()
+    };
+    let layout = Layout::Pretty {
+        width: pretty::DEFAULT_WIDTH,
+        origins,
+    };
+    export::wgsl_fragment_shader(
+        &mut func,
+        params,
+        template,
+        layout,
+        Limits::default(),
+        recipe.time_map(),
+    )
+    .map_err(|e| format!("Failed to compile function to WGSL: {e:?}"))
+}
 
-    // Convert to WGSL instead of GLSL
-    func.compile_to_glsl_fs(FRAGMENT_SHADER_TEMPLATE)
-        .map_err(|e| format!("Failed to compile function to WGSL: {e:?}"))
+#[wasm_bindgen]
+pub fn generate_fragment_shader(inp: &str) -> Result<String, String> {
+    let template = Template::parse(export::FRAGMENT_SHADER_TEMPLATE)?;
+    let mut recipe = new_recipe(inp)?;
+    let origins = generate(&mut recipe, &template)?;
+    let params = recipe.load_grammar()?.params;
+    compile_shader(&recipe, &params, &template, &origins)
 }
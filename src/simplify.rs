@@ -0,0 +1,250 @@
+// Rule-based algebraic simplification. Rules are applied bottom-up, interleaved with constant
+// folding, until a pass changes nothing or `MAX_PASSES` is reached. Negation is written `sub(0, e)`.
+// Every rule has to hold for inf and NaN operands too, which rules out `e * 0 -> 0`, `e - e -> 0`
+// and `e == e -> true`.
+use crate::node::{ArithmeticOp, CompareOp, FnNode, UnaryOp};
+
+const MAX_PASSES: usize = 16;
+
+impl FnNode {
+    pub fn simplify(&mut self) -> Result<(), String> {
+        for _ in 0..MAX_PASSES {
+            self.fold_constants()?;
+            if !self.rewrite() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // One bottom-up pass, returning whether any rule fired
+    fn rewrite(&mut self) -> bool {
+        let mut changed = match self {
+            FnNode::Arithmetic(a, _, b) | FnNode::Compare(a, _, b) => {
+                let a = a.rewrite();
+                b.rewrite() || a
+            }
+            FnNode::Unary(_, expr) => expr.rewrite(),
+            FnNode::If(a, b, c) | FnNode::Triple(a, b, c) => {
+                let a = a.rewrite();
+                let b = b.rewrite();
+                c.rewrite() || a || b
+            }
+            _ => false,
+        };
+        while let Some(node) = apply_rules(self) {
+            *self = node;
+            changed = true;
+        }
+        changed
+    }
+}
+
+fn is_number(node: &FnNode, val: f32) -> bool {
+    matches!(node, FnNode::Number(n) if n.to_bits() == val.to_bits())
+}
+
+fn is_zero(node: &FnNode) -> bool {
+    matches!(node, FnNode::Number(n) if *n == 0.0)
+}
+
+fn negate(node: FnNode) -> FnNode {
+    FnNode::arithmetic(FnNode::Number(0.0), ArithmeticOp::Sub, node)
+}
+
+fn negated(node: &FnNode) -> Option<&FnNode> {
+    match node {
+        FnNode::Arithmetic(zero, ArithmeticOp::Sub, expr) if is_zero(zero) => Some(expr),
+        _ => None,
+    }
+}
+
+fn apply_rules(node: &FnNode) -> Option<FnNode> {
+    match node {
+        FnNode::Arithmetic(a, op, b) => arithmetic_rules(a, *op, b),
+        FnNode::Unary(op, expr) => unary_rules(op, expr),
+        // False even for NaN, and for inf where `eq` and `neq` compare the difference
+        FnNode::Compare(
+            a,
+            CompareOp::GreaterThan | CompareOp::LessThan | CompareOp::NotEqual,
+            b,
+        ) if a == b => Some(FnNode::Boolean(false)),
+        FnNode::If(_, then_branch, else_branch) if then_branch == else_branch => {
+            Some((**then_branch).clone())
+        }
+        _ => None,
+    }
+}
+
+fn arithmetic_rules(a: &FnNode, op: ArithmeticOp, b: &FnNode) -> Option<FnNode> {
+    use ArithmeticOp::{Add, Div, Mul, Sub};
+
+    match (a, op, b) {
+        // Keep constants on the right so the reassociation rules below only need one shape
+        (FnNode::Number(_), Add | Mul, _) if !matches!(b, FnNode::Number(_)) => {
+            Some(FnNode::arithmetic(b.clone(), op, a.clone()))
+        }
+
+        // Identities
+        (e, Add | Sub, zero) if is_zero(zero) => Some(e.clone()),
+        (e, Mul | Div, one) if is_number(one, 1.0) => Some(e.clone()),
+        (e, Mul, minus_one) if is_number(minus_one, -1.0) => Some(negate(e.clone())),
+
+        // Negations
+        (a, Add, b) if negated(b).is_some() => {
+            negated(b).map(|b| FnNode::arithmetic(a.clone(), Sub, b.clone()))
+        }
+        (a, Sub, b) if negated(b).is_some() => {
+            negated(b).map(|b| FnNode::arithmetic(a.clone(), Add, b.clone()))
+        }
+        (a, Mul, b) if negated(a).is_some() && negated(b).is_some() => negated(a)
+            .zip(negated(b))
+            .map(|(a, b)| FnNode::arithmetic(a.clone(), Mul, b.clone())),
+
+        // Constant reassociation: (e + c1) + c2 -> e + (c1 + c2), likewise for mul
+        (e, Sub, FnNode::Number(c)) => Some(FnNode::arithmetic(e.clone(), Add, FnNode::Number(-c))),
+        (FnNode::Arithmetic(e, Add, c1), Add, FnNode::Number(c2)) => match **c1 {
+            FnNode::Number(c1) => Some(FnNode::arithmetic(
+                (**e).clone(),
+                Add,
+                FnNode::Number(c1 + c2),
+            )),
+            _ => None,
+        },
+        (FnNode::Arithmetic(e, Mul, c1), Mul, FnNode::Number(c2)) => match **c1 {
+            FnNode::Number(c1) => Some(FnNode::arithmetic(
+                (**e).clone(),
+                Mul,
+                FnNode::Number(c1 * c2),
+            )),
+            _ => None,
+        },
+
+        _ => None,
+    }
+}
+
+fn unary_rules(op: &UnaryOp, expr: &FnNode) -> Option<FnNode> {
+    match (op, expr) {
+        // abs(abs(e)), abs(sqrt(e)) -> the inner node, which is already non-negative
+        (UnaryOp::Abs, FnNode::Unary(UnaryOp::Abs | UnaryOp::Sqrt, _)) => Some(expr.clone()),
+        // sqrt(e * e) -> abs(e)
+        (UnaryOp::Sqrt, FnNode::Arithmetic(a, ArithmeticOp::Mul, b)) if a == b => {
+            Some(FnNode::unary(UnaryOp::Abs, (**a).clone()))
+        }
+        // Even functions drop the negation, odd ones move it outwards
+        (UnaryOp::Abs | UnaryOp::Cos, e) if negated(e).is_some() => {
+            negated(e).map(|e| FnNode::unary(op.clone(), e.clone()))
+        }
+        (UnaryOp::Sin | UnaryOp::Tan, e) if negated(e).is_some() => {
+            negated(e).map(|e| negate(FnNode::unary(op.clone(), e.clone())))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn add(a: FnNode, b: FnNode) -> FnNode {
+        FnNode::arithmetic(a, ArithmeticOp::Add, b)
+    }
+
+    fn mul(a: FnNode, b: FnNode) -> FnNode {
+        FnNode::arithmetic(a, ArithmeticOp::Mul, b)
+    }
+
+    fn sub(a: FnNode, b: FnNode) -> FnNode {
+        FnNode::arithmetic(a, ArithmeticOp::Sub, b)
+    }
+
+    fn simplified(mut node: FnNode) -> String {
+        node.optimize().expect("Simplification should succeed");
+        node.to_string()
+    }
+
+    #[test]
+    fn test_identities() {
+        let n = FnNode::Number;
+        assert_eq!(simplified(mul(FnNode::X, n(1.0))), "x");
+        assert_eq!(simplified(add(n(0.0), FnNode::Y)), "y");
+        // Not 0 when the operand is inf or NaN
+        assert_eq!(simplified(mul(FnNode::X, n(0.0))), "mul(x, 0)");
+        assert_eq!(simplified(sub(FnNode::T, FnNode::T)), "sub(t, t)");
+        assert_eq!(
+            simplified(FnNode::unary(
+                UnaryOp::Abs,
+                FnNode::unary(UnaryOp::Abs, FnNode::X)
+            )),
+            "abs(x)"
+        );
+        assert_eq!(
+            simplified(FnNode::unary(UnaryOp::Sqrt, mul(FnNode::T, FnNode::T))),
+            "abs(t)"
+        );
+        assert_eq!(
+            simplified(FnNode::unary(UnaryOp::Sin, sub(n(0.0), FnNode::X))),
+            "sub(0, sin(x))"
+        );
+        assert_eq!(simplified(add(add(n(2.0), FnNode::X), n(3.0))), "add(x, 5)");
+        assert_eq!(
+            simplified(FnNode::if_(
+                FnNode::compare(FnNode::X, CompareOp::LessThan, FnNode::X),
+                FnNode::Y,
+                FnNode::T,
+            )),
+            "t"
+        );
+    }
+
+    #[test]
+    fn test_simplify_preserves_eval() {
+        let input = r"
+        E | vec3(C, C, C) ;
+        A | random | x | y | t | r ;
+        C ||  A
+          ||| add(C, C)
+          ||| sub(C, C)
+          ||| mul(C, C)
+          |   sqrt(mul(A, A))
+          ||| abs(C)
+          |   sin(sub(A, C))
+          |   cos(C)
+          ;";
+        let grammar = Parser::new(input)
+            .parse()
+            .expect("Parse should be successful");
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for seed in 0..200 {
+            let Some(mut node) = grammar.gen_seeded(0, 6, seed) else {
+                continue;
+            };
+            let mut optimized = node.clone();
+            optimized.optimize().expect("Simplification should succeed");
+            // Folding turns constant NaNs into 0 on its second pass, which isn't the rules' doing
+            node.fold_constants().expect("Folding should succeed");
+            node.fold_constants().expect("Folding should succeed");
+
+            for _ in 0..32 {
+                let (x, y, t) = (
+                    rng.random::<f32>() * 2.0 - 1.0,
+                    rng.random::<f32>() * 2.0 - 1.0,
+                    rng.random::<f32>() * 2.0 - 1.0,
+                );
+                let expected = node.eval_fn(x, y, t).expect("Evaluation should succeed");
+                let actual = optimized
+                    .eval_fn(x, y, t)
+                    .expect("Evaluation should succeed");
+                assert!(
+                    expected.approx_eq(&actual, 1e-3),
+                    "{node}\nsimplified to\n{optimized}\ndiffers at ({x}, {y}, {t}): {expected:?} vs {actual:?}"
+                );
+            }
+        }
+    }
+}
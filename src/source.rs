@@ -0,0 +1,471 @@
+// Source export of a generated function for tools without a GPU. The emitted code follows `eval`
+// operation for operation in single precision: division by zero gives an infinity or NaN, the sqrt
+// of a negative is NaN, `mod` truncates, and `eq`/`ne` compare within `f32::EPSILON`. The tree is
+// not optimized first, since the algebraic rewrites are free to change those edge cases. The one
+// gap is JavaScript's trigonometry, described on `compile_source`.
+use std::fmt::{Display, Write};
+
+use crate::node::{ArithmeticOp, CompareOp, FnNode, UnaryOp};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceLanguage {
+    Rust,
+    C,
+    JavaScript,
+}
+
+impl Display for SourceLanguage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SourceLanguage::Rust => "Rust",
+            SourceLanguage::C => "C",
+            SourceLanguage::JavaScript => "JavaScript",
+        })
+    }
+}
+
+impl SourceLanguage {
+    // Function header through the locals every expression may use
+    fn prelude(self) -> &'static str {
+        match self {
+            SourceLanguage::Rust => {
+                r"/// Colour channels at (x, y) and time t; the viewer shows `rgb + 0.5`
+#[allow(unused_variables, unused_parens, clippy::all)]
+pub fn art(x: f32, y: f32, t: f32) -> [f32; 3] {
+    let r = (x * x + y * y).sqrt();
+    let theta = y.atan2(x);
+"
+            }
+            SourceLanguage::C => {
+                r"#include <float.h>
+#include <math.h>
+
+/* Colour channels at (x, y) and time t, written to rgb; the viewer shows rgb + 0.5.
+ * Build with -ffp-contract=off so no multiply-add is fused and rounded differently. */
+void art(float x, float y, float t, float rgb[3]) {
+    float r = sqrtf(x * x + y * y);
+    float theta = atan2f(y, x);
+"
+            }
+            // Every intermediate is rounded back to single precision with `Math.fround`
+            SourceLanguage::JavaScript => {
+                r"// Colour channels at (x, y) and time t; the viewer shows rgb + 0.5
+function art(x, y, t) {
+    x = Math.fround(x);
+    y = Math.fround(y);
+    t = Math.fround(t);
+    const r = Math.fround(Math.sqrt(Math.fround(Math.fround(x * x) + Math.fround(y * y))));
+    const theta = Math.fround(Math.atan2(y, x));
+"
+            }
+        }
+    }
+
+    fn declare(self, name: &str, is_bool: bool) -> String {
+        match self {
+            SourceLanguage::Rust => {
+                format!("let {name}: {} =", if is_bool { "bool" } else { "f32" })
+            }
+            SourceLanguage::C => format!("{} {name} =", if is_bool { "int" } else { "float" }),
+            SourceLanguage::JavaScript => format!("const {name} ="),
+        }
+    }
+
+    fn ret(self, r: &str, g: &str, b: &str) -> String {
+        match self {
+            SourceLanguage::Rust => format!("    [{r}, {g}, {b}]\n}}\n"),
+            SourceLanguage::C => {
+                format!("    rgb[0] = {r};\n    rgb[1] = {g};\n    rgb[2] = {b};\n}}\n")
+            }
+            SourceLanguage::JavaScript => format!("    return [{r}, {g}, {b}];\n}}\n"),
+        }
+    }
+
+    fn number(self, val: f32) -> String {
+        let literal = match self {
+            SourceLanguage::Rust if val.is_nan() => "f32::NAN".to_string(),
+            SourceLanguage::Rust if val.is_infinite() => "f32::INFINITY".to_string(),
+            SourceLanguage::C if val.is_nan() => "NAN".to_string(),
+            SourceLanguage::C if val.is_infinite() => "INFINITY".to_string(),
+            SourceLanguage::JavaScript if val.is_nan() => "NaN".to_string(),
+            SourceLanguage::JavaScript if val.is_infinite() => "Infinity".to_string(),
+            SourceLanguage::Rust => format!("{:?}", val.abs()),
+            SourceLanguage::C => format!("{:?}f", val.abs()),
+            // The exact double of the single, so no rounding is needed when it is read
+            SourceLanguage::JavaScript => format!("{:?}", f64::from(val.abs())),
+        };
+        if val.is_sign_negative() && !val.is_nan() {
+            format!("(-{literal})")
+        } else {
+            literal
+        }
+    }
+
+    fn unary(self, op: &UnaryOp, a: &str) -> String {
+        let name = match op {
+            UnaryOp::Sqrt => "sqrt",
+            UnaryOp::Abs => "abs",
+            UnaryOp::Sin => "sin",
+            UnaryOp::Cos => "cos",
+            UnaryOp::Tan => "tan",
+        };
+        match self {
+            SourceLanguage::Rust => format!("f32::{name}({a})"),
+            SourceLanguage::C if matches!(op, UnaryOp::Abs) => format!("fabsf({a})"),
+            SourceLanguage::C => format!("{name}f({a})"),
+            SourceLanguage::JavaScript => format!("Math.fround(Math.{name}({a}))"),
+        }
+    }
+
+    fn arithmetic(self, a: &str, op: ArithmeticOp, b: &str) -> String {
+        let op = match op {
+            ArithmeticOp::Add => "+",
+            ArithmeticOp::Sub => "-",
+            ArithmeticOp::Mul => "*",
+            ArithmeticOp::Div => "/",
+            // Rust's `%` on floats and JavaScript's are both C's `fmod`
+            ArithmeticOp::Mod if self == SourceLanguage::C => return format!("fmodf({a}, {b})"),
+            ArithmeticOp::Mod => "%",
+        };
+        match self {
+            SourceLanguage::JavaScript => format!("Math.fround({a} {op} {b})"),
+            _ => format!("({a} {op} {b})"),
+        }
+    }
+
+    fn compare(self, a: &str, op: CompareOp, b: &str) -> String {
+        let within = match op {
+            CompareOp::GreaterThanEqual => return format!("({a} >= {b})"),
+            CompareOp::GreaterThan => return format!("({a} > {b})"),
+            CompareOp::LessThanEqual => return format!("({a} <= {b})"),
+            CompareOp::LessThan => return format!("({a} < {b})"),
+            CompareOp::Equal => "<",
+            CompareOp::NotEqual => ">",
+        };
+        match self {
+            SourceLanguage::Rust => format!("(({a} - {b}).abs() {within} f32::EPSILON)"),
+            SourceLanguage::C => format!("(fabsf({a} - {b}) {within} FLT_EPSILON)"),
+            SourceLanguage::JavaScript => format!(
+                "(Math.abs(Math.fround({a} - {b})) {within} {})",
+                self.number(f32::EPSILON)
+            ),
+        }
+    }
+
+    fn select(self, cond: &str, then: &str, elze: &str) -> String {
+        match self {
+            SourceLanguage::Rust => format!("(if {cond} {{ {then} }} else {{ {elze} }})"),
+            SourceLanguage::C | SourceLanguage::JavaScript => format!("({cond} ? {then} : {elze})"),
+        }
+    }
+
+    fn compile_expr(self, node: &FnNode) -> Result<String, String> {
+        Ok(match node {
+            FnNode::X => "x".to_string(),
+            FnNode::Y => "y".to_string(),
+            FnNode::T => "t".to_string(),
+            FnNode::R => "r".to_string(),
+            FnNode::Theta => "theta".to_string(),
+            FnNode::Param(name, _) => name.clone(),
+            FnNode::Var(idx) => format!("v{idx}"),
+            FnNode::Number(val) => self.number(*val),
+            FnNode::Boolean(val) => match self {
+                SourceLanguage::C => u8::from(*val).to_string(),
+                _ => val.to_string(),
+            },
+            FnNode::Random | FnNode::Rule(_, _) => {
+                return Err("Rule node encountered during source export".to_string());
+            }
+            FnNode::Call(idx, _) => {
+                return Err(format!("Call to helper h{idx} during source export"));
+            }
+            FnNode::Unary(op, a) => self.unary(op, &self.compile_expr(a)?),
+            FnNode::Arithmetic(a, op, b) => {
+                self.arithmetic(&self.compile_expr(a)?, *op, &self.compile_expr(b)?)
+            }
+            FnNode::Compare(a, op, b) => {
+                self.compare(&self.compile_expr(a)?, *op, &self.compile_expr(b)?)
+            }
+            FnNode::If(cond, then, elze) => self.select(
+                &self.compile_expr(cond)?,
+                &self.compile_expr(then)?,
+                &self.compile_expr(elze)?,
+            ),
+            FnNode::Triple(_, _, _) => {
+                return Err("vec3 is only valid as the outermost expression".to_string());
+            }
+        })
+    }
+}
+
+impl FnNode {
+    /// A standalone `art(x, y, t)` function in `language` that computes what `eval_fn` does, with
+    /// every param fixed at its bound value. Rust, and C built with `-ffp-contract=off`, match it
+    /// bit for bit. JavaScript only has double precision `sin`, `cos`, `tan` and `atan2`, which
+    /// can be an ulp off once rounded to single precision, and later operations carry that on.
+    pub fn compile_source(&self, language: SourceLanguage) -> Result<String, String> {
+        let shared = self.share()?;
+        let mut out = language.prelude().to_string();
+        let mut line = |declaration: String, code: String| {
+            writeln!(out, "    {declaration} {code};").map_err(|e| e.to_string())
+        };
+        for (name, value) in self.bound_params() {
+            line(language.declare(name, false), language.number(value))?;
+        }
+        for (idx, binding) in shared.lets.iter().enumerate() {
+            line(
+                language.declare(&format!("v{idx}"), binding.is_bool),
+                language.compile_expr(&binding.expr)?,
+            )?;
+        }
+        let FnNode::Triple(r, g, b) = &shared.body else {
+            return Err("Function does not produce a vec3".to_string());
+        };
+        out.push_str(&language.ret(
+            &language.compile_expr(r)?,
+            &language.compile_expr(g)?,
+            &language.compile_expr(b)?,
+        ));
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use crate::node::Color;
+    use std::process::Command;
+
+    // Points spread over the viewport, each at a few times
+    fn points() -> Vec<(f32, f32, f32)> {
+        let steps = [-1.0, -0.6, -0.25, 0.0, 0.3, 0.75, 1.0];
+        steps
+            .iter()
+            .flat_map(|&x| steps.iter().map(move |&y| (x, y)))
+            .flat_map(|(x, y)| [-2.5, 0.0, 0.4].map(|t| (x, y, t)))
+            .collect()
+    }
+
+    // A program calling each of `sources` as `art<N>` at every point, printing the bits of the
+    // channels, one line per point
+    fn program(language: SourceLanguage, sources: &[String]) -> Result<String, std::fmt::Error> {
+        let points = points();
+        let mut out = String::new();
+        match language {
+            SourceLanguage::Rust => {
+                for (idx, source) in sources.iter().enumerate() {
+                    writeln!(out, "mod f{idx} {{\n{source}}}")?;
+                }
+                out.push_str("fn main() {\n");
+                for (x, y, t) in points {
+                    for idx in 0..sources.len() {
+                        writeln!(
+                            out,
+                            "    let [r, g, b] = f{idx}::art({x:?}, {y:?}, {t:?});\n    \
+                             println!(\"{{}} {{}} {{}}\", r.to_bits(), g.to_bits(), b.to_bits());"
+                        )?;
+                    }
+                }
+                out.push_str("}\n");
+            }
+            SourceLanguage::C => {
+                out.push_str("#include <stdio.h>\n#include <string.h>\n");
+                for (idx, source) in sources.iter().enumerate() {
+                    out.push_str(&source.replace("void art(", &format!("void art{idx}(")));
+                }
+                out.push_str(
+                    r#"static void print(const float rgb[3]) {
+    unsigned int bits[3];
+    memcpy(bits, rgb, sizeof bits);
+    printf("%u %u %u\n", bits[0], bits[1], bits[2]);
+}
+"#,
+                );
+                out.push_str("int main(void) {\n    float rgb[3];\n");
+                for (x, y, t) in points {
+                    for idx in 0..sources.len() {
+                        writeln!(
+                            out,
+                            "    art{idx}({x:?}f, {y:?}f, {t:?}f, rgb);\n    print(rgb);"
+                        )?;
+                    }
+                }
+                out.push_str("    return 0;\n}\n");
+            }
+            SourceLanguage::JavaScript => {
+                for (idx, source) in sources.iter().enumerate() {
+                    writeln!(
+                        out,
+                        "const art{idx} = (() => {{\n{source}return art;\n}})();"
+                    )?;
+                }
+                out.push_str(
+                    r#"const bits = new Uint32Array(1);
+const float = new Float32Array(bits.buffer);
+const print = (rgb) => console.log(rgb.map((v) => { float[0] = v; return bits[0]; }).join(" "));
+"#,
+                );
+                for (x, y, t) in points {
+                    for idx in 0..sources.len() {
+                        writeln!(out, "print(art{idx}({x:?}, {y:?}, {t:?}));")?;
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // Builds and runs `program` for `language`, or None if its toolchain isn't installed
+    fn run(language: SourceLanguage, program: &str) -> Option<Vec<Color>> {
+        let dir = std::env::temp_dir();
+        let binary = dir.join(format!("shaderand-source-test-{language}"));
+        let (file, build) = match language {
+            SourceLanguage::Rust => {
+                let file = dir.join("shaderand-source-test.rs");
+                let mut build = Command::new("rustc");
+                build
+                    .arg("--edition=2021")
+                    .arg("-o")
+                    .arg(&binary)
+                    .arg(&file);
+                (file, Some(build))
+            }
+            SourceLanguage::C => {
+                let file = dir.join("shaderand-source-test.c");
+                let mut build = Command::new("cc");
+                build
+                    .args(["-std=c99", "-ffp-contract=off", "-o"])
+                    .arg(&binary)
+                    .arg(&file)
+                    .arg("-lm");
+                (file, Some(build))
+            }
+            SourceLanguage::JavaScript => (dir.join("shaderand-source-test.js"), None),
+        };
+        std::fs::write(&file, program).expect("Writing the program should succeed");
+        let output = if let Some(mut build) = build {
+            let compiled = build.output().ok()?;
+            assert!(
+                compiled.status.success(),
+                "{language} failed to build:\n{}",
+                String::from_utf8_lossy(&compiled.stderr)
+            );
+            Command::new(&binary).output()
+        } else {
+            Command::new("node").arg(&file).output()
+        }
+        .ok()?;
+        let _ = std::fs::remove_file(&file);
+        let _ = std::fs::remove_file(&binary);
+        assert!(
+            output.status.success(),
+            "{language} failed to run:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let colors = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| {
+                let mut channels = line.split(' ').map(|bits| {
+                    f32::from_bits(bits.parse().expect("Output should be channel bits"))
+                });
+                let mut channel = || channels.next().expect("Output should have three channels");
+                Color {
+                    r: channel(),
+                    g: channel(),
+                    b: channel(),
+                }
+            })
+            .collect();
+        Some(colors)
+    }
+
+    #[test]
+    fn test_source_matches_eval() {
+        let grammar = Parser::new(include_str!("../grammar.bnf"))
+            .parse()
+            .expect("Parse should be successful");
+        let funcs = (0..20)
+            .filter_map(|seed| grammar.gen_seeded(0, 8, seed))
+            .collect::<Vec<_>>();
+        let expected = points()
+            .into_iter()
+            .flat_map(|(x, y, t)| {
+                funcs
+                    .iter()
+                    .map(move |func| func.eval_fn(x, y, t).expect("Evaluation should succeed"))
+            })
+            .collect::<Vec<_>>();
+
+        // JavaScript's trigonometry rounds from double precision, an ulp off at times
+        for (language, tolerance) in [
+            (SourceLanguage::Rust, 0.0),
+            (SourceLanguage::C, 0.0),
+            (SourceLanguage::JavaScript, 1e-5),
+        ] {
+            let sources = funcs
+                .iter()
+                .map(|func| func.compile_source(language))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Source export should succeed");
+            let Some(colors) = run(
+                language,
+                &program(language, &sources).expect("Writing the program should succeed"),
+            ) else {
+                continue;
+            };
+            assert_eq!(colors.len(), expected.len(), "{language}");
+            for (color, expected) in colors.iter().zip(&expected) {
+                assert!(
+                    color.approx_eq(expected, tolerance),
+                    "{language}: {color:?} != {expected:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_source() {
+        let shared = FnNode::arithmetic(FnNode::X, ArithmeticOp::Div, FnNode::Y);
+        let node = FnNode::triple(
+            FnNode::arithmetic(shared.clone(), ArithmeticOp::Mod, FnNode::Number(-0.5)),
+            FnNode::if_(
+                FnNode::compare(FnNode::T, CompareOp::Equal, FnNode::R),
+                FnNode::unary(UnaryOp::Sqrt, shared),
+                FnNode::Number(f32::NEG_INFINITY),
+            ),
+            FnNode::Param("speed".to_string(), 0.1),
+        );
+
+        let rust = node
+            .compile_source(SourceLanguage::Rust)
+            .expect("Rust should compile");
+        assert!(rust.contains("pub fn art(x: f32, y: f32, t: f32) -> [f32; 3] {"));
+        assert!(rust.contains("let speed: f32 = 0.1;"));
+        assert!(rust.contains("let v0: f32 = (x / y);"));
+        assert!(rust.contains("[(v0 % (-0.5)), (if ((t - r).abs() < f32::EPSILON) { f32::sqrt(v0) } else { (-f32::INFINITY) }), speed]"));
+
+        let c = node
+            .compile_source(SourceLanguage::C)
+            .expect("C should compile");
+        assert!(c.contains("float speed = 0.1f;"));
+        assert!(c.contains("rgb[0] = fmodf(v0, (-0.5f));"));
+        assert!(c.contains("((fabsf(t - r) < FLT_EPSILON) ? sqrtf(v0) : (-INFINITY))"));
+
+        let js = node
+            .compile_source(SourceLanguage::JavaScript)
+            .expect("JavaScript should compile");
+        assert!(js.contains("const speed = 0.10000000149011612;"));
+        assert!(js.contains("const v0 = Math.fround(x / y);"));
+        assert!(js.contains("Math.fround(Math.sqrt(v0))"));
+        assert!(js.contains("(-Infinity)"));
+
+        for language in [
+            SourceLanguage::Rust,
+            SourceLanguage::C,
+            SourceLanguage::JavaScript,
+        ] {
+            assert!(FnNode::X.compile_source(language).is_err());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Shader wrappers with named slots. A template is plain shader source with `{{name}}` wherever
+// generated code goes, so it can be loaded from a file and checked before anything is generated.
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// Declarations or bindings of the grammar params
+    Uniforms,
+    /// Functions the expression calls, at the top level of the shader
+    Helpers,
+    /// Shared temporaries, as statements inside the entry point
+    Lets,
+    /// The colour expression
+    Expr,
+    /// The displayed colour as a function of `rgb`, the value of `expr`
+    ColorMap,
+    /// t as a function of `seconds`, the time the shader has been running
+    TimeMap,
+}
+
+impl Slot {
+    pub const ALL: [Slot; 6] = [
+        Slot::Uniforms,
+        Slot::Helpers,
+        Slot::Lets,
+        Slot::Expr,
+        Slot::ColorMap,
+        Slot::TimeMap,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::Uniforms => "uniforms",
+            Slot::Helpers => "helpers",
+            Slot::Lets => "lets",
+            Slot::Expr => "expr",
+            Slot::ColorMap => "color_map",
+            Slot::TimeMap => "time_map",
+        }
+    }
+
+    // Generated code that went nowhere would leave names undefined, so any slot with content has
+    // to be present. A template may map colours or time itself and leave `color_map` or
+    // `time_map` out.
+    fn is_required(self, content: &str) -> bool {
+        match self {
+            Slot::Expr => true,
+            Slot::ColorMap | Slot::TimeMap => false,
+            Slot::Uniforms | Slot::Helpers | Slot::Lets => !content.is_empty(),
+        }
+    }
+}
+
+impl Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{{{}}}}}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Slot(Slot),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    /// Splits `source` at its `{{slot}}`s. Unknown slot names and a missing `{{expr}}` are errors.
+    pub fn parse(source: &str) -> Result<Template, String> {
+        let mut pieces = Vec::new();
+        let mut rest = source;
+        while let Some(open) = rest.find("{{") {
+            let (text, tail) = rest.split_at(open);
+            let tail = tail.get(2..).unwrap_or_default();
+            let close = tail
+                .find("}}")
+                .ok_or_else(|| format!("Unclosed {{{{ in template: {{{{{tail}"))?;
+            let (name, tail) = tail.split_at(close);
+            let name = name.trim();
+            let slot = Slot::ALL
+                .into_iter()
+                .find(|slot| slot.name() == name)
+                .ok_or_else(|| {
+                    let known = Slot::ALL.map(|slot| slot.to_string()).join(", ");
+                    format!("Unknown template slot {{{{{name}}}}}, expected one of {known}")
+                })?;
+            if !text.is_empty() {
+                pieces.push(Piece::Text(text.to_string()));
+            }
+            pieces.push(Piece::Slot(slot));
+            rest = tail.get(2..).unwrap_or_default();
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Text(rest.to_string()));
+        }
+
+        let template = Template { pieces };
+        if !template.has(Slot::Expr) {
+            return Err(format!("Template has no {} slot", Slot::Expr));
+        }
+        Ok(template)
+    }
+
+    pub fn has(&self, slot: Slot) -> bool {
+        self.pieces.contains(&Piece::Slot(slot))
+    }
+
+    /// Fills every slot with its entry in `fills`, or nothing if it has none
+    pub fn render(&self, fills: &[(Slot, &str)]) -> Result<String, String> {
+        let content = |slot: Slot| {
+            fills
+                .iter()
+                .find(|(filled, _)| *filled == slot)
+                .map_or("", |(_, content)| content)
+        };
+        if let Some(slot) = Slot::ALL
+            .into_iter()
+            .find(|slot| slot.is_required(content(*slot)) && !self.has(*slot))
+        {
+            return Err(format!(
+                "Template has no {slot} slot for the generated code"
+            ));
+        }
+
+        Ok(self
+            .pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.as_str(),
+                Piece::Slot(slot) => content(*slot),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let template = Template::parse(
+            "// 100%s\n{{ uniforms }}main {\n{{lets}}  out = {{color_map}}({{expr}}); {{expr}}\n}",
+        )
+        .expect("Template should parse");
+        let rendered = template
+            .render(&[
+                (Slot::Expr, "v0 * x"),
+                (Slot::Lets, "  v0 = y;\n"),
+                (Slot::Helpers, ""),
+            ])
+            .expect("Template should render");
+        assert_eq!(
+            rendered,
+            "// 100%s\nmain {\n  v0 = y;\n  out = (v0 * x); v0 * x\n}"
+        );
+
+        let error = template
+            .render(&[(Slot::Expr, "x"), (Slot::Helpers, "float f() {}")])
+            .expect_err("Helpers have nowhere to go");
+        assert!(error.contains("{{helpers}}"), "{error}");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for (source, expected) in [
+            ("main { }", "no {{expr}}"),
+            ("{{expr}} {{colour}}", "Unknown template slot {{colour}}"),
+            ("{{expr}} {{lets", "Unclosed"),
+        ] {
+            let error = Template::parse(source).expect_err("Template should not parse");
+            assert!(error.contains(expected), "{error}");
+        }
+    }
+}
@@ -0,0 +1,438 @@
+// In-process validation of generated shader code with naga, so broken output is caught before it
+// reaches the GPU driver. Generation checks the shader the caller fills its template with, once per
+// function drawn; the code generators can also be checked on an expression alone, wrapped in a
+// minimal fragment shader. Errors are traced back to the smallest `FnNode` covering the span.
+use std::fmt::{Display, Write};
+use std::ops::Range;
+
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+use crate::backend::ShaderBackend;
+use crate::cse::Shared;
+use crate::grammar::{Grammar, Origins};
+use crate::node::FnNode;
+
+/// Generated expressions rejected in a row before `gen_validated` gives up
+pub const MAX_ATTEMPTS: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Frontend {
+    Glsl,
+    Wgsl,
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationError {
+    pub message: String,
+    /// The validated shader, which `span` indexes into
+    pub source: String,
+    pub span: Option<Range<usize>>,
+    /// The smallest subexpression whose generated code covers `span`
+    pub node: Option<FnNode>,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid shader: {}", self.message)?;
+        if let Some(node) = &self.node {
+            write!(f, "\nIn expression: {node}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A function `gen_validated` drew
+#[derive(Debug, Clone)]
+pub struct Validated {
+    pub func: FnNode,
+    /// The rule behind each subtree
+    pub origins: Origins,
+    /// The seed `func` came from
+    pub seed: u64,
+    /// Why each seed before it was passed over
+    pub rejected: Vec<ValidationError>,
+}
+
+impl Frontend {
+    fn prelude(self) -> &'static str {
+        match self {
+            Frontend::Glsl => {
+                r"#version 450
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 color;
+void main() {
+    float x = uv.x;
+    float y = uv.y;
+    float t = uv.x;
+    float r = length(uv);
+    float theta = atan(y, x);
+"
+            }
+            Frontend::Wgsl => {
+                r"@fragment
+fn main(@location(0) uv: vec2<f32>) -> @location(0) vec4<f32> {
+    let x = uv.x;
+    let y = uv.y;
+    let t = uv.x;
+    let r = length(uv);
+    let theta = atan2(y, x);
+"
+            }
+        }
+    }
+
+    // The colour expression goes between the two halves
+    fn epilogue(self) -> (&'static str, &'static str) {
+        match self {
+            Frontend::Glsl => ("    color = vec4(", ", 1.0);\n}\n"),
+            Frontend::Wgsl => ("    return vec4<f32>(", ", 1.0);\n}\n"),
+        }
+    }
+
+    // naga wants a binding on every uniform, which GL's loose ones don't have
+    fn checkable(self, source: &str) -> String {
+        match self {
+            Frontend::Glsl => {
+                let mut bindings = 0_u32..;
+                source
+                    .lines()
+                    .map(|line| match line.strip_prefix("uniform ") {
+                        Some(rest) => format!(
+                            "layout(binding = {}) uniform {rest}",
+                            bindings.next().unwrap_or_default()
+                        ),
+                        None => line.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            Frontend::Wgsl => source.to_string(),
+        }
+    }
+
+    fn check(self, source: &str) -> Result<(), (String, Option<Range<usize>>)> {
+        let module = match self {
+            Frontend::Glsl => naga::front::glsl::Frontend::default()
+                .parse(
+                    &naga::front::glsl::Options::from(naga::ShaderStage::Fragment),
+                    source,
+                )
+                .map_err(|e| {
+                    let span = e.errors.first().and_then(|error| error.meta.to_range());
+                    let message = e
+                        .errors
+                        .iter()
+                        .map(|error| error.kind.to_string())
+                        .collect::<Vec<_>>()
+                        .join("; ");
+                    (message, span)
+                })?,
+            Frontend::Wgsl => naga::front::wgsl::parse_str(source).map_err(|e| {
+                let span = e.labels().next().and_then(|(span, _)| span.to_range());
+                (e.message().to_string(), span)
+            })?,
+        };
+
+        Validator::new(ValidationFlags::all(), Capabilities::empty())
+            .validate(&module)
+            .map(|_| ())
+            .map_err(|e| {
+                let mut message = vec![e.to_string()];
+                let mut cause = std::error::Error::source(e.as_inner());
+                while let Some(inner) = cause {
+                    message.push(inner.to_string());
+                    cause = inner.source();
+                }
+                // The innermost span is the narrowest one
+                let span = e
+                    .spans()
+                    .filter_map(|(span, _)| span.to_range())
+                    .min_by_key(ExactSizeIterator::len);
+                (message.join(": "), span)
+            })
+    }
+}
+
+// Descends into the child whose code contains `offset`, a position within `code`
+fn locate<'a>(
+    backend: &dyn ShaderBackend,
+    node: &'a FnNode,
+    code: &str,
+    offset: usize,
+) -> &'a FnNode {
+    for child in node.children() {
+        let Ok(child_code) = backend.compile_expr(child) else {
+            continue;
+        };
+        if let Some(start) = code.find(&child_code) {
+            if let Some(inner) = offset.checked_sub(start) {
+                if inner < child_code.len() {
+                    return locate(backend, child, &child_code, inner);
+                }
+            }
+        }
+    }
+    node
+}
+
+// The node whose code in `source` contains `offset`, looking for the code of each root of `shared`
+// in the order shaders declare them
+fn locate_root(
+    backend: &dyn ShaderBackend,
+    shared: &Shared,
+    source: &str,
+    offset: usize,
+) -> Option<FnNode> {
+    let mut searched = 0;
+    let roots = shared.lets.iter().map(|binding| &binding.expr);
+    for root in roots.chain([&shared.body]) {
+        let Ok(code) = backend.compile_expr(root) else {
+            continue;
+        };
+        let Some(start) = source
+            .get(searched..)
+            .and_then(|rest| rest.find(&code))
+            .and_then(|found| found.checked_add(searched))
+        else {
+            continue;
+        };
+        if let Some(inner) = offset.checked_sub(start) {
+            if inner < code.len() {
+                return Some(locate(backend, root, &code, inner).clone());
+            }
+        }
+        searched = start.checked_add(code.len())?;
+    }
+    None
+}
+
+impl FnNode {
+    /// Checks that the code `backend` generates for this expression is valid on its own, in a
+    /// minimal shader
+    pub fn validate(&self, backend: &dyn ShaderBackend) -> Result<(), ValidationError> {
+        let frontend = backend.frontend();
+        let error = |message: String| ValidationError {
+            message,
+            source: String::new(),
+            span: None,
+            node: None,
+        };
+        let shared = self.share().map_err(error)?;
+
+        let mut source = frontend.prelude().to_string();
+        for (name, _) in self.bound_params() {
+            let declaration = backend.declare(name, false);
+            writeln!(source, "    {declaration} {};", backend.number(0.0))
+                .map_err(|e| error(e.to_string()))?;
+        }
+        for (idx, binding) in shared.lets.iter().enumerate() {
+            let code = backend.compile_expr(&binding.expr).map_err(error)?;
+            let declaration = backend.declare(&format!("v{idx}"), binding.is_bool);
+            writeln!(source, "    {declaration} {code};").map_err(|e| error(e.to_string()))?;
+        }
+        let (open, close) = frontend.epilogue();
+        let code = backend.compile_expr(&shared.body).map_err(error)?;
+        source.push_str(open);
+        source.push_str(&code);
+        source.push_str(close);
+        self.validate_shader(backend, &source)
+    }
+
+    /// Checks that `source`, a whole shader `compile_fs` made from this expression, is valid for
+    /// `backend`. Errors are traced to a subexpression where its code is laid out compactly.
+    pub fn validate_shader(
+        &self,
+        backend: &dyn ShaderBackend,
+        source: &str,
+    ) -> Result<(), ValidationError> {
+        let frontend = backend.frontend();
+        let source = frontend.checkable(source);
+        let Err((message, span)) = frontend.check(&source) else {
+            return Ok(());
+        };
+        let node = span.as_ref().and_then(|span| {
+            let shared = self.share().ok()?;
+            locate_root(backend, &shared, &source, span.start)
+        });
+        Err(ValidationError {
+            message,
+            source,
+            span,
+            node,
+        })
+    }
+}
+
+impl Grammar {
+    /// Generates from `rule_idx` until the shader that `shader` makes of the expression validates
+    /// for `backend`, so a bad draw is replaced instead of reaching the GPU. `shader` should lay
+    /// the code out compactly, which lets errors be traced to a subexpression. Draws from `seed`
+    /// and then the seeds after it.
+    pub fn gen_validated(
+        &self,
+        rule_idx: usize,
+        depth: usize,
+        seed: u64,
+        backend: &dyn ShaderBackend,
+        shader: &dyn Fn(&mut FnNode) -> Result<String, String>,
+    ) -> Result<Validated, String> {
+        let mut seed = seed;
+        let mut rejected = Vec::new();
+        for _ in 0..MAX_ATTEMPTS {
+            let (func, origins) = self
+                .gen_traced(rule_idx, depth, seed)
+                .ok_or("Failed to generate function")?;
+            // As compiled, which `compile_fs` optimizes first
+            let mut compiled = func.clone();
+            let source = shader(&mut compiled)?;
+            match compiled.validate_shader(backend, &source) {
+                Ok(()) => {
+                    return Ok(Validated {
+                        func,
+                        origins,
+                        seed,
+                        rejected,
+                    })
+                }
+                Err(e) => rejected.push(e),
+            }
+            seed = seed.wrapping_add(1);
+        }
+        let last = rejected
+            .last()
+            .map(|e| format!("\n{e}"))
+            .unwrap_or_default();
+        Err(format!(
+            "No valid shader after {MAX_ATTEMPTS} attempts, check the grammar{last}"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::{Glsl450, GlslEs300, TimeMap, Wgsl};
+    use crate::bnf_parser::Parser;
+    use crate::export::{self, FRAGMENT_SHADER_TEMPLATE};
+    use crate::hoist::Limits;
+    use crate::node::{ArithmeticOp, CompareOp, UnaryOp};
+    use crate::pretty::Layout;
+    use crate::template::Template;
+
+    fn backends() -> [&'static dyn ShaderBackend; 3] {
+        [&Glsl450, &GlslEs300, &Wgsl]
+    }
+
+    #[test]
+    fn test_generated_shaders_validate() {
+        let grammar = Parser::new(include_str!("../grammar.bnf"))
+            .parse()
+            .expect("Parse should be successful");
+        for backend in backends() {
+            for seed in 0..20 {
+                let Some(mut node) = grammar.gen_seeded(0, 8, seed) else {
+                    continue;
+                };
+                node.optimize().expect("Optimization should succeed");
+                if let Err(e) = node.validate(backend) {
+                    panic!("{e}\n{}", e.source);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_points_at_node() {
+        // A boolean used as a number is valid Rust-side but not in any shading language
+        let bad = FnNode::arithmetic(
+            FnNode::compare(FnNode::X, CompareOp::LessThan, FnNode::Y),
+            ArithmeticOp::Add,
+            FnNode::T,
+        );
+        let node = FnNode::triple(
+            FnNode::unary(UnaryOp::Sin, bad.clone()),
+            FnNode::Y,
+            FnNode::X,
+        );
+        for backend in backends() {
+            let error = node
+                .validate(backend)
+                .expect_err("Adding a boolean should not validate");
+            assert!(error.span.is_some(), "{error}");
+            // Frontends differ in whether they blame the addition itself or the sin consuming it
+            let located = error.node.as_ref().map(ToString::to_string);
+            assert!(
+                located.is_some_and(|located| located.contains(&bad.to_string())
+                    && located.len() < node.to_string().len()),
+                "{error}\n{}",
+                error.source
+            );
+        }
+    }
+
+    #[test]
+    fn test_gen_validated_checks_template() {
+        let grammar = Parser::new(include_str!("../grammar.bnf"))
+            .parse()
+            .expect("Parse should be successful");
+        let wgsl = |template: &str| {
+            let template = Template::parse(template).expect("Template should parse");
+            grammar.gen_validated(0, 8, 3, &Wgsl, &|func| {
+                export::wgsl_fragment_shader(
+                    func,
+                    &grammar.params,
+                    &template,
+                    Layout::Compact,
+                    Limits::default(),
+                    TimeMap::default(),
+                )
+            })
+        };
+        let validated = wgsl(FRAGMENT_SHADER_TEMPLATE).expect("Generation should succeed");
+        assert_eq!(validated.seed, 3);
+        assert!(validated.rejected.is_empty());
+        // Returns the colour without the alpha the template's entry point promises
+        let broken = FRAGMENT_SHADER_TEMPLATE.replace("map_rgb({{expr}})", "{{expr}}");
+        assert!(wgsl(&broken).is_err());
+
+        // Loose GL uniforms, which naga only takes with a binding
+        let template = Template::parse(
+            "#version 450
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 color;
+uniform float time;
+{{uniforms}}
+{{helpers}}
+void main() {
+    float x = uv.x;
+    float y = uv.y;
+    float t = time;
+    float r = length(uv);
+    float theta = atan(y, x);
+{{lets}}
+    color = vec4({{expr}}, 1.0);
+}
+",
+        )
+        .expect("Template should parse");
+        let uniforms = grammar
+            .params
+            .iter()
+            .map(|param| format!("uniform float {};", param.name))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let validated = grammar
+            .gen_validated(0, 8, 3, &Glsl450, &|func| {
+                func.compile_fs(
+                    &template,
+                    &uniforms,
+                    &Glsl450,
+                    Layout::Compact,
+                    Limits::default(),
+                    TimeMap::default(),
+                )
+            })
+            .expect("Generation should succeed");
+        assert!(validated.rejected.is_empty());
+    }
+}
@@ -0,0 +1,770 @@
+#![allow(clippy::many_single_char_names)]
+// Bytecode for evaluating an expression at every pixel on the CPU. `FnNode::eval` walks the tree
+// and allocates a node per operation; here the shared form from CSE is flattened once into
+// register instructions over plain `f32`s. Coordinates and constants are registers the program
+// starts with, so leaves cost nothing, and only the taken branch of an `if` is run.
+use crate::cse::Shared;
+use crate::node::{ArithmeticOp, Color, CompareOp, FnNode, UnaryOp};
+
+pub(crate) const X: usize = 0;
+pub(crate) const Y: usize = 1;
+pub(crate) const T: usize = 2;
+pub(crate) const R: usize = 3;
+pub(crate) const THETA: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Instr {
+    /// The `r` and `theta` of the shader templates, into their registers
+    Polar,
+    Arithmetic {
+        op: ArithmeticOp,
+        dst: usize,
+        a: usize,
+        b: usize,
+    },
+    /// Writes 1 if the comparison holds and 0 otherwise
+    Compare {
+        op: CompareOp,
+        dst: usize,
+        a: usize,
+        b: usize,
+    },
+    Unary {
+        op: UnaryOp,
+        dst: usize,
+        a: usize,
+    },
+    Copy {
+        dst: usize,
+        src: usize,
+    },
+    /// Continues at `target` if `cond` is 0
+    JumpUnless {
+        cond: usize,
+        target: usize,
+    },
+    Jump {
+        target: usize,
+    },
+}
+
+// Where a compiled subtree left its result
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    Scalar(usize),
+    Color(usize, usize, usize),
+}
+
+impl Value {
+    fn registers(self) -> Vec<usize> {
+        match self {
+            Value::Scalar(reg) => vec![reg],
+            Value::Color(r, g, b) => vec![r, g, b],
+        }
+    }
+}
+
+/// An expression compiled for repeated evaluation with `run`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub(crate) instrs: Vec<Instr>,
+    /// Initial register file: coordinates, then constants, then temporaries
+    pub(crate) registers: Vec<f32>,
+    pub(crate) output: [usize; 3],
+}
+
+struct Compiler {
+    instrs: Vec<Instr>,
+    registers: Vec<f32>,
+    // Constants by bit pattern, so NaNs and signed zeros stay distinct
+    constants: Vec<(u32, usize)>,
+    vars: Vec<Value>,
+}
+
+impl Compiler {
+    fn alloc(&mut self) -> usize {
+        self.registers.push(0.0);
+        self.registers.len().saturating_sub(1)
+    }
+
+    fn constant(&mut self, val: f32) -> usize {
+        if let Some((_, reg)) = self
+            .constants
+            .iter()
+            .find(|(bits, _)| *bits == val.to_bits())
+        {
+            return *reg;
+        }
+        let reg = self.alloc();
+        if let Some(slot) = self.registers.get_mut(reg) {
+            *slot = val;
+        }
+        self.constants.push((val.to_bits(), reg));
+        reg
+    }
+
+    fn scalar(&mut self, node: &FnNode) -> Result<usize, String> {
+        match self.compile(node)? {
+            Value::Scalar(reg) => Ok(reg),
+            Value::Color(_, _, _) => Err(format!("Expected a number, found the colour {node}")),
+        }
+    }
+
+    fn compile(&mut self, node: &FnNode) -> Result<Value, String> {
+        let reg = match node {
+            FnNode::X => X,
+            FnNode::Y => Y,
+            FnNode::T => T,
+            FnNode::R => R,
+            FnNode::Theta => THETA,
+            FnNode::Boolean(val) => self.constant(if *val { 1.0 } else { 0.0 }),
+            FnNode::Param(_, val) | FnNode::Number(val) => self.constant(*val),
+            FnNode::Var(idx) => {
+                return self
+                    .vars
+                    .get(*idx)
+                    .copied()
+                    .ok_or_else(|| format!("Unbound variable v{idx} during compilation"))
+            }
+            FnNode::Random | FnNode::Rule(_, _) => {
+                return Err("Rule node encountered during compilation".to_string())
+            }
+            FnNode::Call(idx, _) => return Err(format!("Call to helper h{idx} in bytecode")),
+
+            FnNode::Arithmetic(a, op, b) => {
+                let (a, b) = (self.scalar(a)?, self.scalar(b)?);
+                let dst = self.alloc();
+                self.instrs.push(Instr::Arithmetic { op: *op, dst, a, b });
+                dst
+            }
+            FnNode::Compare(a, op, b) => {
+                let (a, b) = (self.scalar(a)?, self.scalar(b)?);
+                let dst = self.alloc();
+                self.instrs.push(Instr::Compare { op: *op, dst, a, b });
+                dst
+            }
+            FnNode::Unary(op, a) => {
+                let a = self.scalar(a)?;
+                let dst = self.alloc();
+                self.instrs.push(Instr::Unary {
+                    op: op.clone(),
+                    dst,
+                    a,
+                });
+                dst
+            }
+            FnNode::If(cond, then_branch, else_branch) => {
+                return self.branch(cond, then_branch, else_branch)
+            }
+            FnNode::Triple(r, g, b) => {
+                return Ok(Value::Color(
+                    self.scalar(r)?,
+                    self.scalar(g)?,
+                    self.scalar(b)?,
+                ))
+            }
+        };
+        Ok(Value::Scalar(reg))
+    }
+
+    // Both branches copy their result into the same registers
+    fn branch(
+        &mut self,
+        cond: &FnNode,
+        then_branch: &FnNode,
+        else_branch: &FnNode,
+    ) -> Result<Value, String> {
+        let cond = self.scalar(cond)?;
+        let jump_unless = self.instrs.len();
+        self.instrs.push(Instr::JumpUnless { cond, target: 0 });
+
+        let then_value = self.compile(then_branch)?;
+        let dst = match then_value {
+            Value::Scalar(_) => Value::Scalar(self.alloc()),
+            Value::Color(_, _, _) => Value::Color(self.alloc(), self.alloc(), self.alloc()),
+        };
+        for (dst, src) in dst.registers().into_iter().zip(then_value.registers()) {
+            self.instrs.push(Instr::Copy { dst, src });
+        }
+        let jump = self.instrs.len();
+        self.instrs.push(Instr::Jump { target: 0 });
+
+        let else_start = self.instrs.len();
+        let else_value = self.compile(else_branch)?;
+        if dst.registers().len() != else_value.registers().len() {
+            return Err(format!(
+                "Branches of if({then_branch}, {else_branch}) differ in type"
+            ));
+        }
+        for (dst, src) in dst.registers().into_iter().zip(else_value.registers()) {
+            self.instrs.push(Instr::Copy { dst, src });
+        }
+        let end = self.instrs.len();
+
+        if let Some(Instr::JumpUnless { target, .. }) = self.instrs.get_mut(jump_unless) {
+            *target = else_start;
+        }
+        if let Some(Instr::Jump { target }) = self.instrs.get_mut(jump) {
+            *target = end;
+        }
+        Ok(dst)
+    }
+}
+
+fn reads_polar(node: &FnNode) -> bool {
+    matches!(node, FnNode::R | FnNode::Theta) || node.children().into_iter().any(reads_polar)
+}
+
+fn reg(registers: &[f32], idx: usize) -> f32 {
+    registers.get(idx).copied().unwrap_or(f32::NAN)
+}
+
+impl Program {
+    /// Compiles the temporaries of `shared` in order, then its body, which has to be a colour
+    pub fn compile(shared: &Shared) -> Result<Program, String> {
+        let mut compiler = Compiler {
+            instrs: Vec::new(),
+            registers: vec![0.0; THETA.saturating_add(1)],
+            constants: Vec::new(),
+            vars: Vec::with_capacity(shared.lets.len()),
+        };
+        // atan2 is costly enough to skip when nothing reads it
+        if shared.lets.iter().any(|binding| reads_polar(&binding.expr)) || reads_polar(&shared.body)
+        {
+            compiler.instrs.push(Instr::Polar);
+        }
+        for binding in &shared.lets {
+            let value = compiler.compile(&binding.expr)?;
+            compiler.vars.push(value);
+        }
+        let Value::Color(r, g, b) = compiler.compile(&shared.body)? else {
+            return Err("Invalid result for function".to_string());
+        };
+        Ok(Program {
+            instrs: compiler.instrs,
+            registers: compiler.registers,
+            output: [r, g, b],
+        })
+    }
+
+    /// A register file to pass to `run`, reusable across calls
+    pub fn registers(&self) -> Vec<f32> {
+        self.registers.clone()
+    }
+
+    /// The colour at `(x, y, t)`, exactly as `FnNode::eval_fn` computes it
+    pub fn run(&self, registers: &mut [f32], x: f32, y: f32, t: f32) -> Color {
+        let set = |registers: &mut [f32], idx: usize, val: f32| {
+            if let Some(slot) = registers.get_mut(idx) {
+                *slot = val;
+            }
+        };
+        set(registers, X, x);
+        set(registers, Y, y);
+        set(registers, T, t);
+
+        let mut pc = 0;
+        while let Some(instr) = self.instrs.get(pc) {
+            pc = pc.saturating_add(1);
+            match instr {
+                Instr::Polar => {
+                    set(registers, R, (x * x + y * y).sqrt());
+                    set(registers, THETA, y.atan2(x));
+                }
+                Instr::Arithmetic { op, dst, a, b } => {
+                    let (a, b) = (reg(registers, *a), reg(registers, *b));
+                    let val = match op {
+                        ArithmeticOp::Add => a + b,
+                        ArithmeticOp::Sub => a - b,
+                        ArithmeticOp::Mul => a * b,
+                        ArithmeticOp::Div => a / b,
+                        ArithmeticOp::Mod => a % b,
+                    };
+                    set(registers, *dst, val);
+                }
+                Instr::Compare { op, dst, a, b } => {
+                    let (a, b) = (reg(registers, *a), reg(registers, *b));
+                    let holds = match op {
+                        CompareOp::GreaterThan => a > b,
+                        CompareOp::LessThan => a < b,
+                        CompareOp::GreaterThanEqual => a >= b,
+                        CompareOp::LessThanEqual => a <= b,
+                        CompareOp::Equal => (a - b).abs() < f32::EPSILON,
+                        CompareOp::NotEqual => (a - b).abs() > f32::EPSILON,
+                    };
+                    set(registers, *dst, if holds { 1.0 } else { 0.0 });
+                }
+                Instr::Unary { op, dst, a } => {
+                    let a = reg(registers, *a);
+                    let val = match op {
+                        UnaryOp::Sqrt => a.sqrt(),
+                        UnaryOp::Abs => a.abs(),
+                        UnaryOp::Sin => a.sin(),
+                        UnaryOp::Cos => a.cos(),
+                        UnaryOp::Tan => a.tan(),
+                    };
+                    set(registers, *dst, val);
+                }
+                Instr::Copy { dst, src } => {
+                    let val = reg(registers, *src);
+                    set(registers, *dst, val);
+                }
+                Instr::JumpUnless { cond, target } => {
+                    if reg(registers, *cond) == 0.0 {
+                        pc = *target;
+                    }
+                }
+                Instr::Jump { target } => pc = *target,
+            }
+        }
+
+        let [r, g, b] = self.output.map(|idx| reg(registers, idx));
+        Color { r, g, b }
+    }
+}
+
+/// Points `run_batch` evaluates together, one per lane of each register
+pub const LANES: usize = 8;
+
+type Lanes = [f32; LANES];
+
+fn lanes1(a: Lanes, f: impl Fn(f32) -> f32) -> Lanes {
+    a.map(f)
+}
+
+fn lanes2(a: Lanes, b: Lanes, f: impl Fn(f32, f32) -> f32) -> Lanes {
+    let mut out = [0.0; LANES];
+    for ((out, a), b) in out.iter_mut().zip(a).zip(b) {
+        *out = f(a, b);
+    }
+    out
+}
+
+fn truth(a: Lanes, b: Lanes, f: impl Fn(f32, f32) -> bool) -> Lanes {
+    lanes2(a, b, |a, b| if f(a, b) { 1.0 } else { 0.0 })
+}
+
+// Fills a partial chunk out to a whole one; the extra lanes are computed and dropped
+fn chunk(values: &[f32]) -> Lanes {
+    let mut lanes = [values.last().copied().unwrap_or(0.0); LANES];
+    for (lane, value) in lanes.iter_mut().zip(values) {
+        *lane = *value;
+    }
+    lanes
+}
+
+fn load(registers: &[Lanes], idx: usize) -> Lanes {
+    registers.get(idx).copied().unwrap_or([f32::NAN; LANES])
+}
+
+fn store(registers: &mut [Lanes], idx: usize, val: Lanes) {
+    if let Some(slot) = registers.get_mut(idx) {
+        *slot = val;
+    }
+}
+
+// Runs an instruction that isn't a copy or jump on every lane
+fn compute(instr: &Instr, registers: &mut [Lanes], x: Lanes, y: Lanes) {
+    match instr {
+        Instr::Polar => {
+            store(registers, R, lanes2(x, y, |x, y| (x * x + y * y).sqrt()));
+            store(registers, THETA, lanes2(y, x, f32::atan2));
+        }
+        Instr::Arithmetic { op, dst, a, b } => {
+            let (a, b) = (load(registers, *a), load(registers, *b));
+            let val = match op {
+                ArithmeticOp::Add => lanes2(a, b, |a, b| a + b),
+                ArithmeticOp::Sub => lanes2(a, b, |a, b| a - b),
+                ArithmeticOp::Mul => lanes2(a, b, |a, b| a * b),
+                ArithmeticOp::Div => lanes2(a, b, |a, b| a / b),
+                ArithmeticOp::Mod => lanes2(a, b, |a, b| a % b),
+            };
+            store(registers, *dst, val);
+        }
+        Instr::Compare { op, dst, a, b } => {
+            let (a, b) = (load(registers, *a), load(registers, *b));
+            let val = match op {
+                CompareOp::GreaterThan => truth(a, b, |a, b| a > b),
+                CompareOp::LessThan => truth(a, b, |a, b| a < b),
+                CompareOp::GreaterThanEqual => truth(a, b, |a, b| a >= b),
+                CompareOp::LessThanEqual => truth(a, b, |a, b| a <= b),
+                CompareOp::Equal => truth(a, b, |a, b| (a - b).abs() < f32::EPSILON),
+                CompareOp::NotEqual => truth(a, b, |a, b| (a - b).abs() > f32::EPSILON),
+            };
+            store(registers, *dst, val);
+        }
+        Instr::Unary { op, dst, a } => {
+            let a = load(registers, *a);
+            let val = match op {
+                UnaryOp::Sqrt => lanes1(a, f32::sqrt),
+                UnaryOp::Abs => lanes1(a, f32::abs),
+                UnaryOp::Sin => lanes1(a, f32::sin),
+                UnaryOp::Cos => lanes1(a, f32::cos),
+                UnaryOp::Tan => lanes1(a, f32::tan),
+            };
+            store(registers, *dst, val);
+        }
+        Instr::Copy { .. } | Instr::JumpUnless { .. } | Instr::Jump { .. } => {}
+    }
+}
+
+// An `if` being run: the lanes that reached it, the lanes taking its else branch, and where it ends
+struct Frame {
+    outer: [bool; LANES],
+    else_mask: [bool; LANES],
+    end: usize,
+}
+
+impl Program {
+    /// A register file to pass to `run_batch`, reusable across calls
+    pub fn batch_registers(&self) -> Vec<Lanes> {
+        self.registers.iter().map(|val| [*val; LANES]).collect()
+    }
+
+    /// The colours at `(xs[i], ys[i], t)`, replacing the contents of `out`. Every instruction
+    /// runs on `LANES` points at once; a branch no point takes is skipped, and otherwise each
+    /// lane only keeps the result of its own branch, so results match `run`.
+    pub fn run_batch(
+        &self,
+        registers: &mut [Lanes],
+        xs: &[f32],
+        ys: &[f32],
+        t: f32,
+        out: &mut Vec<Color>,
+    ) -> Result<(), String> {
+        if xs.len() != ys.len() {
+            return Err(format!(
+                "{} x coordinates for {} y coordinates",
+                xs.len(),
+                ys.len()
+            ));
+        }
+        out.clear();
+        out.reserve(xs.len());
+        for (xs, ys) in xs.chunks(LANES).zip(ys.chunks(LANES)) {
+            self.run_lanes(registers, chunk(xs), chunk(ys), [t; LANES]);
+            let [r, g, b] = self
+                .output
+                .map(|idx| registers.get(idx).copied().unwrap_or([f32::NAN; LANES]));
+            for ((r, g), b) in r.into_iter().zip(g).zip(b).take(xs.len()) {
+                out.push(Color { r, g, b });
+            }
+        }
+        Ok(())
+    }
+
+    fn run_lanes(&self, registers: &mut [Lanes], x: Lanes, y: Lanes, t: Lanes) {
+        store(registers, X, x);
+        store(registers, Y, y);
+        store(registers, T, t);
+
+        // Lanes whose branch is being run
+        let mut mask = [true; LANES];
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut pc = 0;
+        while let Some(instr) = self.instrs.get(pc) {
+            while let Some(frame) = frames.pop_if(|frame| frame.end == pc) {
+                mask = frame.outer;
+            }
+            pc = pc.saturating_add(1);
+            match instr {
+                // Only lanes in the branch being run take the branch's result
+                Instr::Copy { dst, src } => {
+                    let mut val = load(registers, *dst);
+                    for ((val, src), keep) in val.iter_mut().zip(load(registers, *src)).zip(mask) {
+                        if keep {
+                            *val = src;
+                        }
+                    }
+                    store(registers, *dst, val);
+                }
+                Instr::JumpUnless { cond, target } => {
+                    let cond = load(registers, *cond);
+                    let mut then_mask = mask;
+                    let mut else_mask = mask;
+                    for ((then_lane, else_lane), cond) in
+                        then_mask.iter_mut().zip(&mut else_mask).zip(cond)
+                    {
+                        *then_lane &= cond != 0.0;
+                        *else_lane &= cond == 0.0;
+                    }
+                    // The then branch ends in a jump past the else branch
+                    let end = match self.instrs.get(target.saturating_sub(1)) {
+                        Some(Instr::Jump { target }) => *target,
+                        _ => *target,
+                    };
+                    frames.push(Frame {
+                        outer: mask,
+                        else_mask,
+                        end,
+                    });
+                    if then_mask.contains(&true) {
+                        mask = then_mask;
+                    } else {
+                        mask = else_mask;
+                        pc = *target;
+                    }
+                }
+                // The end of a then branch
+                Instr::Jump { target } => {
+                    if let Some(frame) = frames.last() {
+                        if frame.else_mask.contains(&true) {
+                            mask = frame.else_mask;
+                        } else {
+                            pc = *target;
+                        }
+                    }
+                }
+                _ => compute(instr, registers, x, y),
+            }
+        }
+    }
+}
+
+/// The fastest way this build has to run a program: native code with the `jit` feature, the
+/// interpreter without it or on hosts Cranelift doesn't support
+pub struct Evaluator {
+    program: Program,
+    registers: Vec<f32>,
+    batch_registers: Vec<Lanes>,
+    #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+    native: Option<crate::jit::NativeFunction>,
+}
+
+impl Evaluator {
+    /// Native code, or the interpreter where `native` fails
+    pub fn new(program: Program) -> Evaluator {
+        Self::native(&program).unwrap_or_else(|_| Self::interpreted(program))
+    }
+
+    /// Runs `program` as native code, failing without the `jit` feature or where Cranelift can't
+    /// compile it. `new` falls back on the interpreter quietly, so callers wanting to know why can
+    /// ask this once up front.
+    pub fn native(program: &Program) -> Result<Evaluator, String> {
+        #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+        return Ok(Evaluator {
+            native: Some(crate::jit::NativeFunction::compile(program)?),
+            ..Self::interpreted(program.clone())
+        });
+        #[cfg(not(all(feature = "jit", not(target_arch = "wasm32"))))]
+        {
+            let _ = program;
+            Err("Built without the jit feature".to_string())
+        }
+    }
+
+    /// Always interprets, for comparison with native code
+    pub fn interpreted(program: Program) -> Evaluator {
+        Evaluator {
+            registers: program.registers(),
+            batch_registers: program.batch_registers(),
+            program,
+            #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+            native: None,
+        }
+    }
+
+    pub fn is_native(&self) -> bool {
+        #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+        return self.native.is_some();
+        #[cfg(not(all(feature = "jit", not(target_arch = "wasm32"))))]
+        false
+    }
+
+    pub fn eval(&mut self, x: f32, y: f32, t: f32) -> Color {
+        #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+        if let Some(native) = &self.native {
+            let [r, g, b] = native.call(x, y, t);
+            return Color { r, g, b };
+        }
+        self.program.run(&mut self.registers, x, y, t)
+    }
+
+    /// The colours at `(xs[i], ys[i], t)`, replacing the contents of `out`, as from `eval` for
+    /// each point but faster
+    pub fn eval_batch(
+        &mut self,
+        xs: &[f32],
+        ys: &[f32],
+        t: f32,
+        out: &mut Vec<Color>,
+    ) -> Result<(), String> {
+        #[cfg(all(feature = "jit", not(target_arch = "wasm32")))]
+        if let Some(native) = &self.native {
+            if xs.len() != ys.len() {
+                return Err(format!(
+                    "{} x coordinates for {} y coordinates",
+                    xs.len(),
+                    ys.len()
+                ));
+            }
+            out.clear();
+            out.extend(xs.iter().zip(ys).map(|(x, y)| {
+                let [r, g, b] = native.call(*x, *y, t);
+                Color { r, g, b }
+            }));
+            return Ok(());
+        }
+        self.program
+            .run_batch(&mut self.batch_registers, xs, ys, t, out)
+    }
+}
+
+impl FnNode {
+    /// The expression as bytecode, sharing repeated subtrees
+    pub fn compile_program(&self) -> Result<Program, String> {
+        Program::compile(&self.share()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bnf_parser::Parser;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_matches_eval(node: &FnNode, program: &Program, x: f32, y: f32, t: f32) {
+        let expected = node.eval_fn(x, y, t).expect("Evaluation should succeed");
+        let actual = program.run(&mut program.registers(), x, y, t);
+        let same = |a: f32, b: f32| a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan());
+        assert!(
+            same(expected.r, actual.r) && same(expected.g, actual.g) && same(expected.b, actual.b),
+            "{node}\ndiffers at ({x}, {y}, {t}): {expected:?} vs {actual:?}"
+        );
+    }
+
+    fn assert_batch_matches(node: &FnNode, program: &Program, xs: &[f32], ys: &[f32], t: f32) {
+        let mut out = Vec::new();
+        program
+            .run_batch(&mut program.batch_registers(), xs, ys, t, &mut out)
+            .expect("Lengths match");
+        assert_eq!(out.len(), xs.len());
+        for ((x, y), actual) in xs.iter().zip(ys).zip(out) {
+            let expected = program.run(&mut program.registers(), *x, *y, t);
+            assert_eq!(
+                format!("{expected:?}"),
+                format!("{actual:?}"),
+                "{node}\nbatch differs at ({x}, {y}, {t})"
+            );
+        }
+    }
+
+    #[test]
+    fn test_program_matches_eval() {
+        let input = r"
+        param speed = 0.5 in [0, 2];
+        E | vec3(C, C, C) ;
+        A | x | y | t | r | theta | speed ;
+        C ||  A
+          ||| add(C, C)
+          ||| sub(C, A)
+          ||| mul(C, C)
+          |   div(C, C)
+          |   mod(C, C)
+          |   sqrt(C)
+          |   abs(C)
+          |   sin(C)
+          |   cos(C)
+          |   tan(C)
+          ;";
+        let grammar = Parser::new(input)
+            .parse()
+            .expect("Parse should be successful");
+        let mut rng = StdRng::seed_from_u64(0);
+        for seed in 0..200 {
+            let Some(node) = grammar.gen_seeded(0, 8, seed) else {
+                continue;
+            };
+            let program = node.compile_program().expect("Compilation should succeed");
+            let t = rng.random::<f32>() * 4.0 - 2.0;
+            // Not a whole number of chunks
+            let xs = (0..29)
+                .map(|_| rng.random::<f32>() * 2.0 - 1.0)
+                .collect::<Vec<_>>();
+            let ys = (0..29)
+                .map(|_| rng.random::<f32>() * 2.0 - 1.0)
+                .collect::<Vec<_>>();
+            for (x, y) in xs.iter().zip(&ys) {
+                assert_matches_eval(&node, &program, *x, *y, t);
+            }
+            assert_batch_matches(&node, &program, &xs, &ys, t);
+        }
+    }
+
+    #[test]
+    fn test_program_branches() {
+        let wave = FnNode::unary(
+            UnaryOp::Sin,
+            FnNode::arithmetic(FnNode::X, ArithmeticOp::Mul, FnNode::R),
+        );
+        let ops = [
+            CompareOp::GreaterThan,
+            CompareOp::LessThan,
+            CompareOp::GreaterThanEqual,
+            CompareOp::LessThanEqual,
+            CompareOp::Equal,
+            CompareOp::NotEqual,
+        ];
+        for op in ops {
+            let cond = FnNode::compare(wave.clone(), op, FnNode::Y);
+            // Nested, colour-valued and boolean-valued ifs, with the shared `wave` in a branch
+            let node = FnNode::if_(
+                FnNode::if_(
+                    cond.clone(),
+                    FnNode::Boolean(false),
+                    FnNode::compare(FnNode::T, op, FnNode::Number(0.0)),
+                ),
+                FnNode::triple(wave.clone(), FnNode::Theta, FnNode::Number(1.0)),
+                FnNode::triple(
+                    FnNode::if_(
+                        cond,
+                        FnNode::Y,
+                        FnNode::arithmetic(wave.clone(), ArithmeticOp::Mod, FnNode::T),
+                    ),
+                    FnNode::Number(f32::NAN),
+                    FnNode::T,
+                ),
+            );
+            let program = node.compile_program().expect("Compilation should succeed");
+            for (x, y, t) in [
+                (0.0, 0.0, 0.0),
+                (0.5, -0.25, 1.0),
+                (-1.0, 0.9, -0.5),
+                (0.3, 0.3, 0.0),
+            ] {
+                assert_matches_eval(&node, &program, x, y, t);
+            }
+            // Lanes in the same chunk disagree about every condition
+            let xs = (0..21).map(|i| i as f32 / 10.0 - 1.0).collect::<Vec<_>>();
+            let ys = xs.iter().rev().copied().collect::<Vec<_>>();
+            for t in [-0.5, 0.0, 1.0] {
+                assert_batch_matches(&node, &program, &xs, &ys, t);
+            }
+        }
+
+        let unbound = FnNode::triple(FnNode::Var(0), FnNode::X, FnNode::X);
+        assert!(Program::compile(&Shared {
+            lets: Vec::new(),
+            body: unbound
+        })
+        .is_err());
+        assert!(FnNode::X.compile_program().is_err());
+    }
+
+    #[test]
+    fn test_batch_lengths() {
+        let program = FnNode::triple(FnNode::X, FnNode::Y, FnNode::T)
+            .compile_program()
+            .expect("Compilation should succeed");
+        assert!(program
+            .run_batch(
+                &mut program.batch_registers(),
+                &[0.0; 3],
+                &[0.0; 2],
+                0.0,
+                &mut Vec::new()
+            )
+            .is_err());
+    }
+}
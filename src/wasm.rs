@@ -1,3 +1,4 @@
+use crate::export::ExportFormat;
 use crate::renderer::ShaderRenderer;
 use js_sys::Date;
 use wasm_bindgen::prelude::*;
@@ -234,6 +235,7 @@ impl ShaderApp {
                     self.show_status("✅ Grammar applied successfully!", false)?;
                     self.update_shader_display()?;
                     self.update_grammar_display()?;
+                    self.update_params_display()?;
                     self.hide_grammar_editor()?;
                 }
             }
@@ -243,6 +245,31 @@ impl ShaderApp {
         Ok(())
     }
 
+    /// Redraws with `source`, the contents of a user WGSL template file
+    #[wasm_bindgen]
+    pub fn load_template(&mut self, source: &str) -> Result<(), JsValue> {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.reload_template(source)?;
+            self.show_status("✅ Template loaded successfully!", false)?;
+            self.update_shader_display()?;
+        } else {
+            self.show_status("❌ Renderer not initialized", true)?;
+        }
+        Ok(())
+    }
+
+    /// Redraws looping every `period` seconds, or without looping if it's 0 or empty
+    #[wasm_bindgen]
+    pub fn set_loop_period(&mut self, period: f32) -> Result<(), JsValue> {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_loop_period(period)?;
+            self.update_shader_display()?;
+        } else {
+            self.show_status("❌ Renderer not initialized", true)?;
+        }
+        Ok(())
+    }
+
     #[wasm_bindgen]
     pub fn cancel_grammar_edit(&mut self) -> Result<(), JsValue> {
         self.hide_grammar_editor()?;
@@ -265,41 +292,54 @@ impl ShaderApp {
         Ok(())
     }
 
+    /// Downloads the current shader as `format`: "wgsl", "hlsl", "metal", "spv", "shadertoy", or
+    /// the function as "rs", "c" or "js" source
     #[wasm_bindgen]
-    pub fn download_shader(&self) -> Result<(), JsValue> {
+    pub fn download_shader(&self, format: &str) -> Result<(), JsValue> {
         if let Some(renderer) = &self.renderer {
-            let shader_source = renderer.get_current_shader();
-
-            let blob_props = BlobPropertyBag::new();
-            blob_props.set_type("text/plain");
-            let blob = Blob::new_with_str_sequence_and_options(
-                &js_sys::Array::from_iter([JsValue::from_str(&shader_source)]),
-                &blob_props,
+            let format = format
+                .parse::<ExportFormat>()
+                .map_err(|e| JsValue::from_str(&e))?;
+            let shader = renderer.export(format).map_err(|e| JsValue::from_str(&e))?;
+            self.download(
+                &shader,
+                format.mime_type(),
+                &format!("shader-{}.{}", Date::now() as i64, format.extension()),
             )?;
 
-            let url = Url::create_object_url_with_blob(&blob)?;
-
-            let link = self
-                .document
-                .create_element("a")?
-                .dyn_into::<HtmlElement>()?;
-            link.set_attribute("href", &url)?;
-            link.set_attribute("download", &format!("shader-{}.glsl", Date::now() as i64))?;
-            link.style().set_property("display", "none")?;
-
-            // self.document.body().unwrap().append_child(&link)?;
-            // link.click();
-            if let Some(body) = self.document.body() {
-                body.append_child(&link)?;
-                link.click();
-                body.remove_child(&link)?;
-            } else {
-                return Err(JsValue::from_str("Failed to get document body"));
-            }
-            Url::revoke_object_url(&url)?;
-
             // Note: Can't call show_status here due to &self, would need &mut self
-            web_sys::console::log_1(&"📥 Shader source downloaded!".into());
+            web_sys::console::log_1(&format!("📥 {format} shader downloaded!").into());
+        }
+        Ok(())
+    }
+
+    /// Downloads the current piece as a recipe, which `load_recipe`, `--recipe` and the native
+    /// viewer read back
+    #[wasm_bindgen]
+    pub fn download_recipe(&self) -> Result<(), JsValue> {
+        if let Some(renderer) = &self.renderer {
+            let recipe = renderer.get_recipe();
+            self.download(
+                recipe.as_bytes(),
+                "text/plain",
+                &format!("recipe-{}.recipe", Date::now() as i64),
+            )?;
+            web_sys::console::log_1(&"📥 Recipe downloaded!".into());
+        }
+        Ok(())
+    }
+
+    /// Shows what `source`, the contents of a recipe file, describes
+    #[wasm_bindgen]
+    pub fn load_recipe(&mut self, source: &str) -> Result<(), JsValue> {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.load_recipe(source)?;
+            self.show_status("✅ Recipe loaded successfully!", false)?;
+            self.update_shader_display()?;
+            self.update_grammar_display()?;
+            self.update_params_display()?;
+        } else {
+            self.show_status("❌ Renderer not initialized", true)?;
         }
         Ok(())
     }
@@ -334,6 +374,52 @@ impl ShaderApp {
         Ok(())
     }
 
+    #[wasm_bindgen]
+    pub fn update_params_display(&self) -> Result<(), JsValue> {
+        let (Some(renderer), Some(panel)) = (
+            &self.renderer,
+            self.document.get_element_by_id("params-panel"),
+        ) else {
+            return Ok(());
+        };
+
+        panel.set_inner_html("");
+        let mut has_params = false;
+        for (param, value) in renderer.params() {
+            has_params = true;
+            let label = self.document.create_element("label")?;
+            label.set_text_content(Some(&param.name));
+
+            // The panel forwards `input` events to `set_param` using the slider's name
+            let slider = self.document.create_element("input")?;
+            slider.set_attribute("type", "range")?;
+            slider.set_attribute("name", &param.name)?;
+            slider.set_attribute("min", &param.min.to_string())?;
+            slider.set_attribute("max", &param.max.to_string())?;
+            slider.set_attribute("step", &((param.max - param.min) / 100.0).to_string())?;
+            slider.set_attribute("value", &value.to_string())?;
+
+            label.append_child(&slider)?;
+            panel.append_child(&label)?;
+        }
+
+        let panel = panel.dyn_into::<HtmlElement>()?;
+        panel
+            .style()
+            .set_property("display", if has_params { "block" } else { "none" })?;
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_param(&mut self, name: &str, value: f32) -> Result<(), JsValue> {
+        if let Some(renderer) = &mut self.renderer {
+            renderer.set_param(name, value)?;
+        } else {
+            self.show_status("❌ Renderer not initialized", true)?;
+        }
+        Ok(())
+    }
+
     #[wasm_bindgen]
     pub fn handle_canvas_click(&mut self) -> Result<(), JsValue> {
         self.reload_shader()
@@ -366,6 +452,37 @@ impl ShaderApp {
     }
 }
 
+impl ShaderApp {
+    // Saves `bytes` as a file called `name` through a hidden link
+    fn download(&self, bytes: &[u8], mime_type: &str, name: &str) -> Result<(), JsValue> {
+        let blob_props = BlobPropertyBag::new();
+        blob_props.set_type(mime_type);
+        let blob = Blob::new_with_u8_array_sequence_and_options(
+            &js_sys::Array::from_iter([js_sys::Uint8Array::from(bytes)]),
+            &blob_props,
+        )?;
+
+        let url = Url::create_object_url_with_blob(&blob)?;
+
+        let link = self
+            .document
+            .create_element("a")?
+            .dyn_into::<HtmlElement>()?;
+        link.set_attribute("href", &url)?;
+        link.set_attribute("download", name)?;
+        link.style().set_property("display", "none")?;
+
+        if let Some(body) = self.document.body() {
+            body.append_child(&link)?;
+            link.click();
+            body.remove_child(&link)?;
+        } else {
+            return Err(JsValue::from_str("Failed to get document body"));
+        }
+        Url::revoke_object_url(&url)
+    }
+}
+
 #[wasm_bindgen]
 pub fn create_shader_app() -> Result<ShaderApp, JsValue> {
     ShaderApp::new()
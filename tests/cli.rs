@@ -0,0 +1,26 @@
+// Runs the CLI binary, for what only shows at the process boundary
+use std::process::{Command, Output};
+
+fn shaderand(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_shaderand"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("The CLI should run");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn test_pipe_writes_only_frames() {
+    let args = ["--frames", "2", "--size", "4x4", "--seed", "1", "--pipe"];
+    let y4m = shaderand(&[&args[..], &["y4m"]].concat());
+    assert!(y4m.stdout.starts_with(b"YUV4MPEG2 W4 H4 "));
+
+    let rgb = shaderand(&[&args[..], &["rgb"]].concat());
+    assert_eq!(rgb.stdout.len(), 2 * 4 * 4 * 3);
+}